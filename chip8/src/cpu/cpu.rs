@@ -1,14 +1,20 @@
-use crate::{cpu::sprites::get_sprite, gfx::screen::Screen, sound::message::SoundMessage};
-use std::{fs::OpenOptions, io::Read, path::PathBuf, sync::mpsc::Sender, time::Instant};
+use crate::{
+    cpu::sprites::{get_big_sprite, get_sprite},
+    gfx::screen::{Screen, SpriteEdges},
+    sound::{message::SoundMessage, pattern::AudioPattern},
+};
+use std::{fs::OpenOptions, io::Read, ops::Range, path::PathBuf, sync::mpsc::Sender};
 
 use log::{debug, error, info, warn};
 
-use crate::{
-    cpu::{
-        instruction::{parse_instruction, Instruction},
-        rng::random_byte,
-    },
-    dumper::{dump_cpu, DumpMemory},
+use crate::cpu::{
+    error::CpuError,
+    instruction::{parse_instruction, Instruction},
+    keyboard::Keypad,
+    quirks::Quirks,
+    rewind::RewindBuffer,
+    rng::{random_seed, RandomSource, SeededRng},
+    snapshot::Snapshot,
 };
 
 use super::{
@@ -35,18 +41,30 @@ pub const VD: Register = 0xD;
 pub const VE: Register = 0xE;
 pub const VF: Register = 0xF;
 
+/// Where the SUPER-CHIP 8x10 font starts, right after the standard 8x5 one
+const BIG_SPRITES_ADDRESS: u16 = 0x50;
+
+pub const MEMORY_SIZE: usize = 4096;
+
+/// XO-CHIP can address the full 16 bits
+pub const EXTENDED_MEMORY_SIZE: usize = 65536;
+
+/// F000 nnnn, the only instruction that is four bytes long
+const LONG_INSTRUCTION_OPCODE: u16 = 0xF000;
+
 pub enum CPUIterationDecision {
     Continue,
     Halt,
 }
 
 pub struct CPU {
-    memory: [u8; 4096], // TODO: Maybe move to a different struct and datatype
+    /// 4 KiB, or 64 KiB with the extended memory quirk
+    memory: Vec<u8>,
 
     registers: [u8; 16],
     stack: [u16; 16],
 
-    /// NOTE: Only 12 bits are used for this
+    /// NOTE: Only 12 bits are used for this, unless the extended memory quirk is active
     memory_location: u16,
 
     /// NOTE: Only 12 bits are used for this
@@ -54,36 +72,89 @@ pub struct CPU {
 
     stack_pointer: u8,
 
-    /// Stores the currently pressed key
-    active_key_code: Option<u8>,
+    keypad: Keypad,
 
+    /// Set while Fx0A waits for a key to be pressed and released
     waiting_for_key_press: bool,
 
+    /// The key pressed while Fx0A was waiting, it completes once the key is released
+    awaited_key: Option<u8>,
+
+    /// Set after a DRW when the display wait quirk is active, cleared on the next tick
+    waiting_for_vblank: bool,
+
     screen: Screen,
 
+    /// SUPER-CHIP 128x64 mode
+    high_resolution: bool,
+
+    /// SUPER-CHIP RPL user flags, saved and loaded by Fx75/Fx85
+    ///
+    /// NOTE: SUPER-CHIP only has 8 of them, XO-CHIP extends them to 16
+    rpl_flags: [u8; 16],
+
+    /// XO-CHIP audio pattern, loaded by F002
+    audio_buffer: Option<[u8; 16]>,
+
+    /// XO-CHIP audio pattern playback rate, set by Fx3A
+    pitch: u8,
+
     delay_timer: DelayTimer,
     sound_timer: SoundTimer,
 
-    /// Frequency in Hz
-    frequency: u32,
+    quirks: Quirks,
+
+    /// Source of RND results, seeded so runs can be replayed
+    rng: Box<dyn RandomSource>,
+
+    /// Snapshots captured on every tick, empty unless rewinding is enabled
+    history: RewindBuffer,
 }
 
 impl CPU {
-    pub fn speed_up(&mut self) {
-        let new_frequency = self.frequency + 50;
-        self.frequency = new_frequency.min(2000);
-        println!("New frequency: {}", self.frequency);
+    pub fn tick(&mut self, _: u64) {
+        self.sound_timer.tick();
+        self.delay_timer.tick();
+        self.waiting_for_vblank = false;
+
+        if self.history.capacity() > 0 {
+            self.history.push(self.snapshot());
+        }
     }
 
-    pub fn slow_down(&mut self) {
-        let new_frequency = self.frequency - 50;
-        self.frequency = new_frequency.max(50);
-        println!("New frequency: {}", self.frequency);
+    /// Restarts the RND sequence, the same seed always gives the same results
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng.reseed(seed);
     }
 
-    pub fn tick(&mut self, _: u64) {
-        self.sound_timer.tick();
-        self.delay_timer.tick();
+    /// Replaces where RND results come from, by default a `SeededRng` with a random seed
+    pub fn set_random_source(&mut self, rng: Box<dyn RandomSource>) {
+        self.rng = rng;
+    }
+
+    /// Keeps the last `frames` ticks worth of snapshots to rewind through, 0 disables it
+    pub fn enable_rewind(&mut self, frames: usize) {
+        self.history = RewindBuffer::new(frames);
+    }
+
+    /// Goes back one tick, returns `false` if there is no older snapshot
+    pub fn rewind(&mut self) -> bool {
+        match self.history.pop() {
+            Some(snapshot) => {
+                // Only fails if the random source was replaced since the snapshot was taken
+                if let Err(err) = self.rng.set_state(&snapshot.rng_state) {
+                    warn!("Could not rewind RND: {}", err);
+                }
+                self.apply_snapshot(&snapshot);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns `true` while the sound timer is running
+    pub fn is_beeping(&self) -> bool {
+        self.sound_timer.get_value() > 0
     }
 
     pub fn screen(&self) -> &Screen {
@@ -94,20 +165,40 @@ impl CPU {
         &mut self.screen
     }
 
-    pub fn new(sound_tx: Sender<SoundMessage>, frequency: u32) -> Self {
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    /// Execution speed is up to the caller, see `Scheduler`
+    pub fn new(sound_tx: Sender<SoundMessage>, quirks: Quirks) -> Self {
         let mut cpu = CPU {
             registers: [0x0; 16],
             memory_location: 0x0,
             program_counter: 0x0,
             stack_pointer: 0x0,
-            memory: [0xff; 4096],
+            memory: vec![
+                0xff;
+                if quirks.extended_memory {
+                    EXTENDED_MEMORY_SIZE
+                } else {
+                    MEMORY_SIZE
+                }
+            ],
             screen: Screen::new(),
-            active_key_code: None,
+            high_resolution: false,
+            rpl_flags: [0x0; 16],
+            audio_buffer: None,
+            pitch: AudioPattern::DEFAULT_PITCH,
+            keypad: Keypad::new(),
             waiting_for_key_press: false,
+            awaited_key: None,
+            waiting_for_vblank: false,
             stack: [0x0; 16],
             delay_timer: DelayTimer::new(),
             sound_timer: SoundTimer::new(sound_tx),
-            frequency,
+            quirks,
+            rng: Box::new(SeededRng::new(random_seed())),
+            history: RewindBuffer::new(0),
         };
         cpu.initialize_sprites();
         cpu.clear_screen();
@@ -125,12 +216,32 @@ impl CPU {
             }
         }
         debug!("Initialized sprites from address 0x000 to address 0x04F");
+
+        for i in 0..=15 {
+            let sprite = get_big_sprite(i);
+            let index = (BIG_SPRITES_ADDRESS + self.get_big_sprite_address(i)) as usize;
+            self.memory[index..index + 10].copy_from_slice(&sprite);
+        }
+        debug!("Initialized big sprites from address 0x050 to address 0x0EF");
     }
 
     fn get_sprite_address(&self, sprite: u8) -> u16 {
         sprite as u16 * 5
     }
 
+    fn get_big_sprite_address(&self, sprite: u8) -> u16 {
+        (sprite & 0x0f) as u16 * 10
+    }
+
+    pub fn is_high_resolution(&self) -> bool {
+        self.high_resolution
+    }
+
+    fn set_high_resolution(&mut self, high_resolution: bool) {
+        self.high_resolution = high_resolution;
+        self.screen.set_high_resolution(high_resolution);
+    }
+
     fn clear_screen(&mut self) {
         self.screen.clear();
     }
@@ -138,12 +249,12 @@ impl CPU {
     pub fn load_program_from_file(&mut self, file_path: PathBuf) -> Result<usize, String> {
         info!("Loading file {}", file_path.as_path().to_str().unwrap());
 
-        if !file_path
-            .extension()
-            .unwrap_or_default()
-            .eq_ignore_ascii_case("ch8")
+        let extension = file_path.extension().unwrap_or_default();
+        if !["ch8", "sc8", "xo8"]
+            .iter()
+            .any(|ext| extension.eq_ignore_ascii_case(ext))
         {
-            error!("File extension is not ch8");
+            error!("File extension is not ch8, sc8 or xo8");
             return Err("Wrong file extension".to_string());
         }
         let file = match OpenOptions::new().read(true).open(file_path) {
@@ -204,18 +315,76 @@ impl CPU {
         self.registers[register as usize]
     }
 
-    pub fn memory(&self) -> &[u8; 4096] {
+    pub fn memory(&self) -> &[u8] {
         &self.memory
     }
 
-    fn read_u16_from_memory(&self, addr: usize) -> u16 {
-        ((self.memory[addr] as u16) << 8) | self.memory[addr + 1] as u16
+    /// Overwrites memory starting at `address`, e.g. from a debugger
+    pub fn write_memory(&mut self, address: usize, bytes: &[u8]) -> Result<(), CpuError> {
+        let range = self.memory_range(address, bytes.len())?;
+        self.memory[range].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    pub fn set_program_counter(&mut self, address: u16) {
+        self.program_counter = address;
+    }
+
+    pub fn set_memory_location(&mut self, address: u16) {
+        self.memory_location = address;
+    }
+
+    /// Returns `false` if the stack can't be that deep
+    pub fn set_stack_pointer(&mut self, stack_pointer: u8) -> bool {
+        if stack_pointer as usize > self.stack.len() {
+            return false;
+        }
+        self.stack_pointer = stack_pointer;
+        true
+    }
+
+    /// Return addresses of the subroutines we are in, innermost last
+    pub fn stack(&self) -> &[u16] {
+        &self.stack[..self.stack_pointer as usize]
     }
 
-    fn copy_register_bcd_into_memory(&mut self, register: u8) {
+    /// Returns `true` while the CPU waits for a key press or the next tick and can't make progress
+    pub fn is_waiting(&self) -> bool {
+        self.waiting_for_key() || self.waiting_for_vblank
+    }
+
+    /// Returns `true` until the key pressed during Fx0A has been released
+    fn waiting_for_key(&self) -> bool {
+        self.waiting_for_key_press && self.released_awaited_key().is_none()
+    }
+
+    fn released_awaited_key(&self) -> Option<u8> {
+        self.awaited_key.filter(|key| !self.keypad.is_pressed(*key))
+    }
+
+    /// Returns `None` if either byte is outside of memory
+    fn read_u16_from_memory(&self, addr: usize) -> Option<u16> {
+        let high = *self.memory.get(addr)?;
+        let low = *self.memory.get(addr + 1)?;
+        Some(((high as u16) << 8) | low as u16)
+    }
+
+    /// Returns the range of `length` bytes starting at `start`, if it fits into memory
+    fn memory_range(&self, start: usize, length: usize) -> Result<Range<usize>, CpuError> {
+        if start + length > self.memory.len() {
+            return Err(CpuError::MemoryOutOfRange {
+                address: start,
+                length,
+            });
+        }
+        Ok(start..start + length)
+    }
+
+    fn copy_register_bcd_into_memory(&mut self, register: u8) -> Result<(), CpuError> {
         let mut value = self.registers[register as usize];
 
         let addr = self.memory_location;
+        self.memory_range(addr as usize, 3)?;
 
         let ones = value % 10;
         value /= 10;
@@ -225,51 +394,183 @@ impl CPU {
         self.memory[addr as usize] = hundreds;
         self.memory[(addr + 1) as usize] = tens;
         self.memory[(addr + 2) as usize] = ones;
+        Ok(())
+    }
+
+    /// Skips the next instruction, which might be the four bytes long F000 nnnn
+    fn skip_next_instruction(&mut self) -> Result<(), CpuError> {
+        let next = self.read_u16_from_memory(self.program_counter as usize + 2);
+        self.advance_program_counter(if next == Some(LONG_INSTRUCTION_OPCODE) {
+            4
+        } else {
+            2
+        })
+    }
+
+    /// Moves the program counter `bytes` forward, which can't go past the end of 64 KiB memory
+    fn advance_program_counter(&mut self, bytes: u16) -> Result<(), CpuError> {
+        self.program_counter =
+            self.program_counter
+                .checked_add(bytes)
+                .ok_or(CpuError::ProgramCounterOutOfRange {
+                    address: self.program_counter,
+                })?;
+        Ok(())
+    }
+
+    fn send_audio_pattern(&self) {
+        if let Some(buffer) = self.audio_buffer {
+            self.sound_timer
+                .set_pattern(AudioPattern::new(buffer, self.pitch));
+        }
     }
 
     fn jump(&mut self, addr: u16) {
         self.program_counter = addr;
     }
 
-    fn call(&mut self, addr: u16) {
+    fn call(&mut self, addr: u16) -> Result<(), CpuError> {
+        if self.stack_pointer as usize >= self.stack.len() {
+            return Err(CpuError::StackOverflow {
+                address: self.program_counter,
+            });
+        }
         self.stack[self.stack_pointer as usize] = self.program_counter;
         self.stack_pointer += 1;
 
         self.program_counter = addr;
+        Ok(())
     }
 
-    fn ret(&mut self) {
+    fn ret(&mut self) -> Result<(), CpuError> {
+        if self.stack_pointer == 0 {
+            return Err(CpuError::StackUnderflow {
+                address: self.program_counter,
+            });
+        }
         let index = (self.stack_pointer - 1) as usize;
         self.program_counter = self.stack[index];
         self.stack_pointer -= 1;
+        Ok(())
     }
 
-    pub fn set_key_pressed(&mut self, key: Option<u8>) {
-        self.active_key_code = key;
+    /// Captures the whole machine state
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            memory: self.memory.clone(),
+            registers: self.registers,
+            stack: self.stack,
+            memory_location: self.memory_location,
+            program_counter: self.program_counter,
+            stack_pointer: self.stack_pointer,
+            keypad: self.keypad.bits(),
+            waiting_for_key_press: self.waiting_for_key_press,
+            awaited_key: self.awaited_key,
+            waiting_for_vblank: self.waiting_for_vblank,
+            high_resolution: self.high_resolution,
+            rpl_flags: self.rpl_flags,
+            audio_buffer: self.audio_buffer,
+            pitch: self.pitch,
+            delay_timer: self.delay_timer.get_value(),
+            sound_timer: self.sound_timer.get_value(),
+            screen_width: self.screen.width(),
+            screen_height: self.screen.height(),
+            screen_planes: self.screen.planes(),
+            screen_buffer: self.screen.to_bytes(),
+            rng_state: self.rng.state(),
+        }
     }
 
-    pub fn fetch_decode_execute(&mut self) -> CPUIterationDecision {
-        let now = Instant::now();
-        let milliseconds_to_reach = if self.frequency > 0 {
-            (1000 / self.frequency) as f64
-        } else {
-            0.0
-        };
-        if self.waiting_for_key_press && self.active_key_code == Option::None {
-            return CPUIterationDecision::Continue;
+    /// Puts the machine back in the state captured by `snapshot`
+    ///
+    /// Fails without changing anything if the snapshot is invalid, was taken with a different
+    /// amount of memory than the quirks give, or with another RND algorithm. The rewind history
+    /// starts over from the restored state.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), String> {
+        snapshot.validate()?;
+        if snapshot.memory.len() != self.memory.len() {
+            return Err(format!(
+                "Save state has {} bytes of memory, the quirks give {}",
+                snapshot.memory.len(),
+                self.memory.len()
+            ));
+        }
+        self.rng.set_state(&snapshot.rng_state)?;
+        self.apply_snapshot(snapshot);
+        // Rewinding from here would go back into the timeline we just left
+        self.history.clear();
+        Ok(())
+    }
+
+    /// Restores a snapshot known to be valid, except for the RND state
+    fn apply_snapshot(&mut self, snapshot: &Snapshot) {
+        self.memory = snapshot.memory.clone();
+        self.registers = snapshot.registers;
+        self.stack = snapshot.stack;
+        self.memory_location = snapshot.memory_location;
+        self.program_counter = snapshot.program_counter;
+        self.stack_pointer = snapshot.stack_pointer;
+        self.keypad = Keypad::from_bits(snapshot.keypad);
+        self.waiting_for_key_press = snapshot.waiting_for_key_press;
+        self.awaited_key = snapshot.awaited_key;
+        self.waiting_for_vblank = snapshot.waiting_for_vblank;
+        self.high_resolution = snapshot.high_resolution;
+        self.rpl_flags = snapshot.rpl_flags;
+        self.audio_buffer = snapshot.audio_buffer;
+        self.pitch = snapshot.pitch;
+        self.delay_timer.set_value(snapshot.delay_timer);
+        self.sound_timer.restore(snapshot.sound_timer);
+        self.screen.restore(
+            snapshot.screen_width,
+            snapshot.screen_height,
+            snapshot.screen_planes,
+            &snapshot.screen_buffer,
+        );
+        self.send_audio_pattern();
+    }
+
+    pub fn keypad(&self) -> Keypad {
+        self.keypad
+    }
+
+    /// Keys already held when Fx0A starts waiting don't count until pressed again
+    pub fn press_key(&mut self, key: u8) {
+        let newly_pressed = !self.keypad.is_pressed(key);
+        if newly_pressed && self.waiting_for_key_press && self.awaited_key.is_none() {
+            self.awaited_key = Some(key & 0xF);
+        }
+        self.keypad.press(key);
+    }
+
+    pub fn release_key(&mut self, key: u8) {
+        self.keypad.release(key);
+    }
+
+    pub fn fetch_decode_execute(&mut self) -> Result<CPUIterationDecision, CpuError> {
+        if self.waiting_for_key() {
+            return Ok(CPUIterationDecision::Continue);
+        }
+        if self.waiting_for_vblank {
+            return Ok(CPUIterationDecision::Continue);
         }
 
         // Fetch
-        let instruction_opcode = self.read_u16_from_memory(self.program_counter as usize);
+        let instruction_opcode = self
+            .read_u16_from_memory(self.program_counter as usize)
+            .ok_or(CpuError::ProgramCounterOutOfRange {
+                address: self.program_counter,
+            })?;
 
         // Decode
         let instruction = match parse_instruction(instruction_opcode) {
             InstructionParseResult::Ok(instruction) => instruction,
             InstructionParseResult::Unparsed => {
-                dump_cpu(self, DumpMemory::No);
-                let string = format!("Un-parsed opcode: {:04X}", instruction_opcode);
-                error!("{}", string);
-                todo!("{}", string);
+                let error = CpuError::UnknownOpcode {
+                    opcode: instruction_opcode,
+                    address: self.program_counter,
+                };
+                error!("{}", error);
+                return Err(error);
             }
         };
 
@@ -277,17 +578,41 @@ impl CPU {
         match instruction {
             Instruction::RET => {
                 debug!("RET");
-                self.ret();
+                self.ret()?;
+            }
+            Instruction::SYS(addr) => {
+                // Machine code routines can't be emulated, they are either ignored or an error
+                debug!("SYS {:04X}", addr);
+                if self.quirks.sys_is_error {
+                    let error = CpuError::MachineCodeCall {
+                        routine: addr,
+                        address: self.program_counter,
+                    };
+                    error!("{}", error);
+                    return Err(error);
+                }
             }
             Instruction::JP(addr) => {
                 debug!("JP {:04X}", addr);
                 self.jump(addr);
-                return CPUIterationDecision::Continue;
+                return Ok(CPUIterationDecision::Continue);
+            }
+            Instruction::JPV0(addr) => {
+                // CHIP-48 and SUPER-CHIP read Bxnn as "jump to xnn + Vx"
+                let register = if self.quirks.jump_uses_vx {
+                    ((addr & 0xf00) >> 8) as u8
+                } else {
+                    V0
+                };
+                debug!("JP V{:X}, {:04X}", register, addr);
+                let offset = self.get_register(register) as u16;
+                self.jump(addr + offset);
+                return Ok(CPUIterationDecision::Continue);
             }
             Instruction::CALL(addr) => {
                 debug!("CALL {:04X}", addr);
-                self.call(addr);
-                return CPUIterationDecision::Continue;
+                self.call(addr)?;
+                return Ok(CPUIterationDecision::Continue);
             }
             Instruction::LDI(addr) => {
                 debug!("LD I, {:04X}", addr);
@@ -306,10 +631,10 @@ impl CPU {
             }
             Instruction::HLT => {
                 debug!("Halting");
-                return CPUIterationDecision::Halt;
+                return Ok(CPUIterationDecision::Halt);
             }
             Instruction::RND(register, and_mask) => {
-                let byte = random_byte();
+                let byte = self.rng.next_byte();
                 debug!("RND V{:X}, 0x{:02X} & 0x{:02X}", register, byte, and_mask);
                 self.set_register(register, byte & and_mask);
             }
@@ -319,35 +644,43 @@ impl CPU {
             }
             Instruction::LDB(register) => {
                 debug!("LD B, V{:X}", register);
-                self.copy_register_bcd_into_memory(register);
+                self.copy_register_bcd_into_memory(register)?;
             }
             Instruction::LDIFromVx(register) => {
                 debug!("LD [I], V{:X}", register);
                 let memory = self.memory_location;
+                self.memory_range(memory as usize, register as usize + 1)?;
                 for i in 0..=register {
                     let value = self.get_register(i);
                     let memory_index = (memory + i as u16) as usize;
                     self.memory[memory_index] = value;
                 }
+                let increment = self.quirks.load_store_index.increment(register);
+                self.memory_location = self.memory_location.wrapping_add(increment);
             }
             Instruction::LDVxFromK(register) => {
                 debug!("LD V{:X}, K", register);
-                if let Some(key) = self.active_key_code {
-                    debug!("Key pressed! {:?}", key);
+                // Like the COSMAC VIP, wait for a key to be pressed and then released
+                if let Some(key) = self.released_awaited_key() {
+                    debug!("Key released! {:?}", key);
                     self.set_register(register, key);
                     self.waiting_for_key_press = false;
+                    self.awaited_key = None;
                 } else {
                     self.waiting_for_key_press = true;
-                    return CPUIterationDecision::Continue;
+                    return Ok(CPUIterationDecision::Continue);
                 }
             }
             Instruction::LDVxFromI(register) => {
                 debug!("LD V{:X}, [I]", register);
                 let memory = self.memory_location;
+                self.memory_range(memory as usize, register as usize + 1)?;
                 for i in 0..=register {
                     let memory_index = (memory + i as u16) as usize;
                     self.set_register(i, self.memory[memory_index]);
                 }
+                let increment = self.quirks.load_store_index.increment(register);
+                self.memory_location = self.memory_location.wrapping_add(increment);
             }
             Instruction::LDF(register) => {
                 debug!("LD F, V{:X}", register);
@@ -361,20 +694,37 @@ impl CPU {
                 // usize casting
                 let x = self.get_register(x) as usize;
                 let y = self.get_register(y) as usize;
-                let byte_length = byte_length as usize;
                 let sprite_address = self.memory_location as usize;
-                let range = sprite_address..(sprite_address + byte_length);
+                let edges = if self.quirks.clip_sprites {
+                    SpriteEdges::Clip
+                } else {
+                    SpriteEdges::Wrap
+                };
+                // XO-CHIP sprites hold the data for every selected plane, one after the other
+                let planes = self.screen.planes().count_ones() as usize;
 
                 self.set_register(VF, 0x0);
-                let sprite = &self.memory[range];
-                let did_erase = self.screen.draw_sprite(x, y, &sprite.to_vec());
+                let did_erase = if byte_length == 0 {
+                    // SUPER-CHIP 16x16 sprite, 2 bytes per row
+                    let range = self.memory_range(sprite_address, 32 * planes)?;
+                    let sprite = &self.memory[range];
+                    self.screen.draw_large_sprite(x, y, sprite, edges)
+                } else {
+                    let range = self.memory_range(sprite_address, byte_length as usize * planes)?;
+                    let sprite = &self.memory[range];
+                    self.screen.draw_sprite(x, y, sprite, edges)
+                };
                 self.set_register(VF, if did_erase { 1 } else { 0 });
                 debug!("Drawn sprite to screen at {x}, {y}, {byte_length} bytes");
+
+                if self.quirks.display_wait {
+                    self.waiting_for_vblank = true;
+                }
             }
             Instruction::SE(register, value) => {
                 debug!("SE V{:X}, {:02X}", register, value);
                 if self.get_register(register) == value {
-                    self.program_counter += 2;
+                    self.skip_next_instruction()?;
                 }
             }
             Instruction::SEVxVy(x, y) => {
@@ -382,7 +732,7 @@ impl CPU {
                 let vx = self.get_register(x);
                 let vy = self.get_register(y);
                 if vx == vy {
-                    self.program_counter += 2;
+                    self.skip_next_instruction()?;
                 }
             }
             Instruction::SNEVxVy(x, y) => {
@@ -390,14 +740,14 @@ impl CPU {
                 let vx = self.get_register(x);
                 let vy = self.get_register(y);
                 if vx != vy {
-                    self.program_counter += 2;
+                    self.skip_next_instruction()?;
                 }
             }
 
             Instruction::SNE(register, value) => {
                 debug!("SNE V{:X}, {:02X}", register, value);
                 if self.get_register(register) != value {
-                    self.program_counter += 2;
+                    self.skip_next_instruction()?;
                 }
             }
             Instruction::LDVxFromVy(x, y) => {
@@ -424,42 +774,47 @@ impl CPU {
                 let vx = self.get_register(x);
                 let vy = self.get_register(y);
                 self.set_register(x, vx & vy);
+                if self.quirks.vf_reset {
+                    self.set_register(VF, 0);
+                }
             }
             Instruction::OR(x, y) => {
                 debug!("OR V{:X}, V{:X}", x, y);
                 let vx = self.get_register(x);
                 let vy = self.get_register(y);
                 self.set_register(x, vx | vy);
+                if self.quirks.vf_reset {
+                    self.set_register(VF, 0);
+                }
             }
             Instruction::XOR(x, y) => {
                 debug!("XOR V{:X}, V{:X}", x, y);
                 let vx = self.get_register(x);
                 let vy = self.get_register(y);
                 self.set_register(x, vx ^ vy);
+                if self.quirks.vf_reset {
+                    self.set_register(VF, 0);
+                }
             }
 
             Instruction::ADDIVx(register) => {
                 debug!("ADD I, V{:X}", register);
                 let vx = self.get_register(register);
-                self.memory_location += vx as u16;
+                self.memory_location = self.memory_location.wrapping_add(vx as u16);
             }
 
             Instruction::SKP(register) => {
                 debug!("SKP V{:X}", register);
                 let value = self.get_register(register);
-                let active_key_code = self.active_key_code;
-                if let Some(key) = active_key_code {
-                    if key == value {
-                        self.program_counter += 2;
-                    }
+                if self.keypad.is_pressed(value) {
+                    self.skip_next_instruction()?;
                 }
             }
             Instruction::SKNP(register) => {
                 debug!("SKNP V{:X}", register);
                 let value = self.get_register(register);
-                let active_key_code = self.active_key_code;
-                if active_key_code == None || active_key_code.unwrap() != value {
-                    self.program_counter += 2;
+                if !self.keypad.is_pressed(value) {
+                    self.skip_next_instruction()?;
                 }
             }
             Instruction::SUB(x, y) => {
@@ -496,10 +851,14 @@ impl CPU {
                 self.set_register(x, result);
             }
 
-            Instruction::SHL(x) => {
-                debug!("SHL V{:X}", x);
+            Instruction::SHL(x, y) => {
+                debug!("SHL V{:X}, V{:X}", x, y);
 
-                let vx = self.get_register(x);
+                let vx = if self.quirks.shift_uses_vy {
+                    self.get_register(y)
+                } else {
+                    self.get_register(x)
+                };
                 let msb = (vx & 0x80) >> 7;
 
                 debug!("V{:X} = 0x{:02X}", x, vx);
@@ -508,10 +867,14 @@ impl CPU {
                 self.set_register(x, vx << 1);
             }
 
-            Instruction::SHR(x) => {
-                debug!("SHR V{:X}", x);
+            Instruction::SHR(x, y) => {
+                debug!("SHR V{:X}, V{:X}", x, y);
 
-                let vx = self.get_register(x);
+                let vx = if self.quirks.shift_uses_vy {
+                    self.get_register(y)
+                } else {
+                    self.get_register(x)
+                };
                 let lsb = vx & 0x01;
 
                 debug!("V{:X} = 0x{:02X}", x, vx);
@@ -520,21 +883,630 @@ impl CPU {
                 self.set_register(x, vx >> 1);
             }
 
+            Instruction::SCD(rows) => {
+                debug!("SCD {:X}", rows);
+                self.screen.scroll_down(rows as usize);
+            }
+            Instruction::SCR => {
+                debug!("SCR");
+                self.screen.scroll_right(4);
+            }
+            Instruction::SCL => {
+                debug!("SCL");
+                self.screen.scroll_left(4);
+            }
+            Instruction::EXIT => {
+                debug!("EXIT");
+                return Ok(CPUIterationDecision::Halt);
+            }
+            Instruction::LOW => {
+                debug!("LOW");
+                self.set_high_resolution(false);
+            }
+            Instruction::HIGH => {
+                debug!("HIGH");
+                self.set_high_resolution(true);
+            }
+            Instruction::LDHF(register) => {
+                debug!("LD HF, V{:X}", register);
+                let sprite = self.get_register(register);
+                self.memory_location = BIG_SPRITES_ADDRESS + self.get_big_sprite_address(sprite);
+            }
+            Instruction::LDRFromVx(register) => {
+                debug!("LD R, V{:X}", register);
+                for i in 0..=register {
+                    self.rpl_flags[i as usize] = self.get_register(i);
+                }
+            }
+            Instruction::LDVxFromR(register) => {
+                debug!("LD V{:X}, R", register);
+                for i in 0..=register {
+                    self.set_register(i, self.rpl_flags[i as usize]);
+                }
+            }
+
+            Instruction::SCU(rows) => {
+                debug!("SCU {:X}", rows);
+                self.screen.scroll_up(rows as usize);
+            }
+            Instruction::LDIFromVxVy(x, y) => {
+                debug!("LD [I], V{:X} - V{:X}", x, y);
+                let registers: Vec<u8> = if x <= y {
+                    (x..=y).collect()
+                } else {
+                    (y..=x).rev().collect()
+                };
+                self.memory_range(self.memory_location as usize, registers.len())?;
+                for (offset, register) in registers.into_iter().enumerate() {
+                    let memory_index = self.memory_location as usize + offset;
+                    self.memory[memory_index] = self.get_register(register);
+                }
+            }
+            Instruction::LDVxVyFromI(x, y) => {
+                debug!("LD V{:X} - V{:X}, [I]", x, y);
+                let registers: Vec<u8> = if x <= y {
+                    (x..=y).collect()
+                } else {
+                    (y..=x).rev().collect()
+                };
+                self.memory_range(self.memory_location as usize, registers.len())?;
+                for (offset, register) in registers.into_iter().enumerate() {
+                    let memory_index = self.memory_location as usize + offset;
+                    self.set_register(register, self.memory[memory_index]);
+                }
+            }
+            Instruction::LDILong => {
+                let addr = self
+                    .read_u16_from_memory(self.program_counter as usize + 2)
+                    .ok_or(CpuError::ProgramCounterOutOfRange {
+                        address: self.program_counter.wrapping_add(2),
+                    })?;
+                debug!("LD I, {:04X}", addr);
+                self.memory_location = addr;
+                // Skip the address, the other 2 bytes are skipped below
+                self.advance_program_counter(2)?;
+            }
+            Instruction::PLANE(planes) => {
+                debug!("PLANE {:X}", planes);
+                self.screen.set_planes(planes);
+            }
+            Instruction::AUDIO => {
+                debug!("AUDIO");
+                let range = self.memory_range(self.memory_location as usize, 16)?;
+                let mut buffer = [0x0; 16];
+                buffer.copy_from_slice(&self.memory[range]);
+                self.audio_buffer = Some(buffer);
+                self.send_audio_pattern();
+            }
+            Instruction::PITCH(register) => {
+                debug!("PITCH V{:X}", register);
+                self.pitch = self.get_register(register);
+                self.send_audio_pattern();
+            }
+
             other => {
                 warn!("TODO: Implement {:?}", other);
             }
         }
-        self.program_counter += 2;
-        loop {
-            if now.elapsed().as_nanos() as f64 / 1_000_000.0 >= milliseconds_to_reach {
-                break;
-            }
-        }
+        self.advance_program_counter(2)?;
 
-        CPUIterationDecision::Continue
+        Ok(CPUIterationDecision::Continue)
     }
 
     pub fn force_audio_stop(&self) {
         self.sound_timer.force_audio_stop();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::{CPUIterationDecision, CPU, V0, V1, V2, V3, VF};
+    use crate::{
+        cpu::{
+            error::CpuError,
+            quirks::{LoadStoreIndex, Quirks},
+            sprites::get_big_sprite,
+        },
+        sound::{message::SoundMessage, pattern::AudioPattern},
+    };
+
+    /// A CPU with `program` loaded at 0x200, ready to run it
+    fn load(quirks: Quirks, program: &[u8]) -> CPU {
+        let (sound_tx, _) = mpsc::channel();
+        let mut cpu = CPU::new(sound_tx, quirks);
+        cpu.write_memory(0x200, program).unwrap();
+        cpu.set_program_counter(0x200);
+        cpu
+    }
+
+    /// Executes `count` instructions, none of which may fail or halt
+    fn run(cpu: &mut CPU, count: usize) {
+        for _ in 0..count {
+            let decision = cpu.fetch_decode_execute().unwrap();
+            assert!(matches!(decision, CPUIterationDecision::Continue));
+        }
+    }
+
+    /// Coordinates of every lit pixel, row by row
+    fn lit(cpu: &CPU) -> Vec<(usize, usize)> {
+        let screen = cpu.screen();
+        let mut pixels = vec![];
+        for y in 0..screen.height() {
+            for x in 0..screen.width() {
+                if screen.pixel(x, y) != 0 {
+                    pixels.push((x, y));
+                }
+            }
+        }
+        pixels
+    }
+
+    fn error(cpu: &mut CPU) -> Option<CpuError> {
+        cpu.fetch_decode_execute().err()
+    }
+
+    #[test]
+    fn unknown_opcodes_are_errors() {
+        let mut cpu = load(Quirks::modern(), &[0x80, 0x08]);
+        assert_eq!(
+            error(&mut cpu),
+            Some(CpuError::UnknownOpcode {
+                opcode: 0x8008,
+                address: 0x200
+            })
+        );
+    }
+
+    #[test]
+    fn calls_past_the_sixteenth_overflow_the_stack() {
+        // CALL 200, calling itself forever
+        let mut cpu = load(Quirks::modern(), &[0x22, 0x00]);
+        run(&mut cpu, 16);
+        assert_eq!(cpu.stack_pointer(), 16);
+        assert_eq!(
+            error(&mut cpu),
+            Some(CpuError::StackOverflow { address: 0x200 })
+        );
+    }
+
+    #[test]
+    fn returning_with_an_empty_stack_underflows_it() {
+        let mut cpu = load(Quirks::modern(), &[0x00, 0xEE]);
+        assert_eq!(
+            error(&mut cpu),
+            Some(CpuError::StackUnderflow { address: 0x200 })
+        );
+    }
+
+    #[test]
+    fn program_counter_must_stay_in_memory() {
+        // The second byte of the opcode is past the end of the 4 KiB
+        let mut cpu = load(Quirks::modern(), &[]);
+        cpu.set_program_counter(0xFFF);
+        assert_eq!(
+            error(&mut cpu),
+            Some(CpuError::ProgramCounterOutOfRange { address: 0xFFF })
+        );
+
+        // Moving past the last instruction of the 64 KiB would wrap the program counter around
+        let mut cpu = load(Quirks::xo_chip(), &[]);
+        cpu.write_memory(0xFFFE, &[0x60, 0x01]).unwrap();
+        cpu.set_program_counter(0xFFFE);
+        assert_eq!(
+            error(&mut cpu),
+            Some(CpuError::ProgramCounterOutOfRange { address: 0xFFFE })
+        );
+    }
+
+    #[test]
+    fn memory_accesses_from_i_must_stay_in_memory() {
+        let cases: [(&[u8], usize); 4] = [
+            (&[0xF0, 0x33], 3), // LD B, V0
+            (&[0xF3, 0x55], 4), // LD [I], V3
+            (&[0xF3, 0x65], 4), // LD V3, [I]
+            (&[0xD0, 0x05], 5), // DRW V0, V0, 5
+        ];
+        for (instruction, length) in cases {
+            let mut cpu = load(Quirks::modern(), instruction);
+            cpu.set_memory_location(0xFFE);
+            assert_eq!(
+                error(&mut cpu),
+                Some(CpuError::MemoryOutOfRange {
+                    address: 0xFFE,
+                    length
+                })
+            );
+        }
+
+        let mut cpu = load(Quirks::modern(), &[]);
+        assert_eq!(
+            cpu.write_memory(0xFFF, &[0x00, 0x00]),
+            Err(CpuError::MemoryOutOfRange {
+                address: 0xFFF,
+                length: 2
+            })
+        );
+    }
+
+    #[test]
+    fn shifts_read_vy_with_the_quirk() {
+        // SHR V0, V1 then SHL V0, V1
+        for (opcode, vy_result, vx_result) in [(0x06, 0x40, 0x02), (0x0E, 0x02, 0x08)] {
+            for shift_uses_vy in [false, true] {
+                let quirks = Quirks {
+                    shift_uses_vy,
+                    ..Quirks::modern()
+                };
+                let mut cpu = load(quirks, &[0x80, 0x10 | opcode]);
+                cpu.set_register(V0, 0x04);
+                cpu.set_register(V1, 0x81);
+                run(&mut cpu, 1);
+                let expected = if shift_uses_vy { vy_result } else { vx_result };
+                assert_eq!(cpu.get_register(V0), expected);
+                assert_eq!(cpu.get_register(VF), shift_uses_vy as u8);
+            }
+        }
+    }
+
+    #[test]
+    fn load_and_store_move_i_as_the_quirk_says() {
+        let cases = [
+            (LoadStoreIndex::Unchanged, 0x300),
+            (LoadStoreIndex::IncrementByX, 0x302),
+            (LoadStoreIndex::IncrementByXPlusOne, 0x303),
+        ];
+        for (load_store_index, expected) in cases {
+            let quirks = Quirks {
+                load_store_index,
+                ..Quirks::modern()
+            };
+            // LD [I], V2 then LD V2, [I]
+            for opcode in [0x55, 0x65] {
+                let mut cpu = load(quirks, &[0xF2, opcode]);
+                cpu.set_memory_location(0x300);
+                run(&mut cpu, 1);
+                assert_eq!(cpu.memory_location(), expected);
+            }
+        }
+
+        let mut cpu = load(Quirks::modern(), &[0xF2, 0x55, 0xF2, 0x65]);
+        cpu.set_memory_location(0x300);
+        for (register, value) in [(V0, 0x11), (V1, 0x22), (V2, 0x33)] {
+            cpu.set_register(register, value);
+        }
+        run(&mut cpu, 1);
+        assert_eq!(cpu.memory()[0x300..0x304], [0x11, 0x22, 0x33, 0xFF]);
+        cpu.set_register(V1, 0);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.get_register(V1), 0x22);
+    }
+
+    #[test]
+    fn logic_operations_reset_vf_with_the_quirk() {
+        // OR, AND then XOR V0, V1
+        for opcode in [0x01, 0x02, 0x03] {
+            for vf_reset in [false, true] {
+                let quirks = Quirks {
+                    vf_reset,
+                    ..Quirks::modern()
+                };
+                let mut cpu = load(quirks, &[0x80, 0x10 | opcode]);
+                cpu.set_register(VF, 0x01);
+                run(&mut cpu, 1);
+                assert_eq!(cpu.get_register(VF), if vf_reset { 0 } else { 1 });
+            }
+        }
+    }
+
+    #[test]
+    fn sprites_clip_or_wrap_as_the_quirk_says() {
+        for clip_sprites in [false, true] {
+            let quirks = Quirks {
+                clip_sprites,
+                ..Quirks::modern()
+            };
+            // LD F, V2 points I at the "0" character, whose top row is 4 pixels wide
+            let mut cpu = load(quirks, &[0xF2, 0x29, 0xD0, 0x15]);
+            cpu.set_register(V0, 62);
+            run(&mut cpu, 2);
+            assert_eq!(cpu.screen().pixel(63, 0), 1);
+            assert_eq!(cpu.screen().pixel(0, 0), if clip_sprites { 0 } else { 1 });
+        }
+    }
+
+    #[test]
+    fn drawing_waits_for_the_next_tick_with_the_quirk() {
+        for display_wait in [false, true] {
+            let quirks = Quirks {
+                display_wait,
+                ..Quirks::modern()
+            };
+            // DRW V0, V0, 1 then LD V1, 01
+            let mut cpu = load(quirks, &[0xD0, 0x01, 0x61, 0x01]);
+            run(&mut cpu, 1);
+            assert_eq!(cpu.is_waiting(), display_wait);
+            run(&mut cpu, 1);
+            assert_eq!(cpu.get_register(V1), if display_wait { 0 } else { 1 });
+
+            if display_wait {
+                cpu.tick(0);
+                assert!(!cpu.is_waiting());
+                run(&mut cpu, 1);
+                assert_eq!(cpu.get_register(V1), 1);
+            }
+        }
+    }
+
+    #[test]
+    fn superchip_scrolls_move_the_display() {
+        let program = [
+            0xD0, 0x01, // DRW V0, V0, 1
+            0x00, 0xC2, // SCD 2
+            0x00, 0xFB, // SCR
+            0x00, 0xFC, // SCL
+            0x00, 0xFC, // SCL
+        ];
+        let mut cpu = load(Quirks::superchip(), &program);
+        cpu.write_memory(0x300, &[0x80]).unwrap();
+        cpu.set_memory_location(0x300);
+        cpu.set_register(V0, 4);
+
+        run(&mut cpu, 1);
+        assert_eq!(lit(&cpu), vec![(4, 4)]);
+        run(&mut cpu, 1);
+        assert_eq!(lit(&cpu), vec![(4, 6)]);
+        run(&mut cpu, 1);
+        assert_eq!(lit(&cpu), vec![(8, 6)]);
+        run(&mut cpu, 1);
+        assert_eq!(lit(&cpu), vec![(4, 6)]);
+        run(&mut cpu, 1);
+        assert_eq!(lit(&cpu), vec![(0, 6)]);
+    }
+
+    #[test]
+    fn superchip_resolution_switches_clear_the_display() {
+        let program = [
+            0xD0, 0x01, // DRW V0, V0, 1
+            0x00, 0xFF, // HIGH
+            0xD0, 0x01, // DRW V0, V0, 1
+            0x00, 0xFE, // LOW
+        ];
+        let mut cpu = load(Quirks::superchip(), &program);
+        cpu.write_memory(0x300, &[0x80]).unwrap();
+        cpu.set_memory_location(0x300);
+
+        run(&mut cpu, 2);
+        assert!(cpu.is_high_resolution());
+        assert_eq!((cpu.screen().width(), cpu.screen().height()), (128, 64));
+        assert!(lit(&cpu).is_empty());
+
+        run(&mut cpu, 2);
+        assert!(!cpu.is_high_resolution());
+        assert_eq!((cpu.screen().width(), cpu.screen().height()), (64, 32));
+        assert!(lit(&cpu).is_empty());
+    }
+
+    #[test]
+    fn superchip_draws_16_by_16_sprites_with_n_0() {
+        let mut cpu = load(Quirks::superchip(), &[0x00, 0xFF, 0xD0, 0x00]);
+        cpu.write_memory(0x300, &[0xFF; 32]).unwrap();
+        cpu.set_memory_location(0x300);
+        run(&mut cpu, 2);
+        let expected: Vec<(usize, usize)> =
+            (0..16).flat_map(|y| (0..16).map(move |x| (x, y))).collect();
+        assert_eq!(lit(&cpu), expected);
+    }
+
+    #[test]
+    fn superchip_big_font_points_i_at_the_digit() {
+        let mut cpu = load(Quirks::superchip(), &[0xF0, 0x30]);
+        cpu.set_register(V0, 0x7);
+        run(&mut cpu, 1);
+        let address = cpu.memory_location() as usize;
+        assert_eq!(cpu.memory()[address..address + 10], get_big_sprite(0x7));
+    }
+
+    #[test]
+    fn superchip_rpl_flags_keep_registers() {
+        let program = [
+            0xF2, 0x75, // LD R, V2
+            0xF2, 0x85, // LD V2, R
+        ];
+        let mut cpu = load(Quirks::superchip(), &program);
+        for (register, value) in [(V0, 0x11), (V1, 0x22), (V2, 0x33)] {
+            cpu.set_register(register, value);
+        }
+        run(&mut cpu, 1);
+        for register in [V0, V1, V2] {
+            cpu.set_register(register, 0);
+        }
+        run(&mut cpu, 1);
+        assert_eq!(cpu.registers()[..4], [0x11, 0x22, 0x33, 0x00]);
+    }
+
+    #[test]
+    fn xo_chip_saves_and_loads_register_ranges_in_either_order() {
+        let program = [
+            0x51, 0x32, // LD [I], V1 - V3
+            0x53, 0x12, // LD [I], V3 - V1
+            0x51, 0x33, // LD V1 - V3, [I]
+            0x53, 0x13, // LD V3 - V1, [I]
+        ];
+        let mut cpu = load(Quirks::xo_chip(), &program);
+        for (register, value) in [(V1, 0x11), (V2, 0x22), (V3, 0x33)] {
+            cpu.set_register(register, value);
+        }
+        cpu.set_memory_location(0x300);
+
+        run(&mut cpu, 1);
+        assert_eq!(cpu.memory()[0x300..0x303], [0x11, 0x22, 0x33]);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.memory()[0x300..0x303], [0x33, 0x22, 0x11]);
+        // Neither moves I, whatever the quirks
+        assert_eq!(cpu.memory_location(), 0x300);
+
+        cpu.write_memory(0x300, &[0xAA, 0xBB, 0xCC]).unwrap();
+        run(&mut cpu, 1);
+        assert_eq!(cpu.registers()[1..4], [0xAA, 0xBB, 0xCC]);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.registers()[1..4], [0xCC, 0xBB, 0xAA]);
+        assert_eq!(cpu.memory_location(), 0x300);
+    }
+
+    #[test]
+    fn xo_chip_long_i_load_takes_four_bytes() {
+        let program = [
+            0x30, 0x00, // SE V0, 00
+            0xF0, 0x00, 0x12, 0x34, // LD I, 1234, skipped whole
+            0x61, 0x01, // LD V1, 01
+            0xF0, 0x00, 0xAB, 0xCD, // LD I, ABCD
+            0x62, 0x02, // LD V2, 02
+        ];
+        let mut cpu = load(Quirks::xo_chip(), &program);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.program_counter(), 0x206);
+        run(&mut cpu, 2);
+        assert_eq!(cpu.memory_location(), 0xABCD);
+        assert_eq!(cpu.program_counter(), 0x20C);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.registers()[..3], [0x00, 0x01, 0x02]);
+    }
+
+    #[test]
+    fn xo_chip_draws_on_the_selected_planes() {
+        let program = [
+            0xF2, 0x01, // PLANE 2
+            0xD0, 0x01, // DRW V0, V0, 1
+            0xF3, 0x01, // PLANE 3
+            0xD1, 0x11, // DRW V1, V1, 1
+        ];
+        let mut cpu = load(Quirks::xo_chip(), &program);
+        // One byte per selected plane
+        cpu.write_memory(0x300, &[0x80, 0xC0]).unwrap();
+        cpu.set_memory_location(0x300);
+        cpu.set_register(V1, 8);
+
+        run(&mut cpu, 2);
+        assert_eq!(cpu.screen().planes(), 0b10);
+        assert_eq!(cpu.screen().pixel(0, 0), 0b10);
+
+        run(&mut cpu, 2);
+        assert_eq!(cpu.screen().planes(), 0b11);
+        assert_eq!(cpu.screen().pixel(8, 8), 0b11);
+        assert_eq!(cpu.screen().pixel(9, 8), 0b10);
+    }
+
+    #[test]
+    fn xo_chip_audio_pattern_is_sent_with_its_pitch() {
+        let (sound_tx, sound_rx) = mpsc::channel();
+        let mut cpu = CPU::new(sound_tx, Quirks::xo_chip());
+        let program = [
+            0xF0, 0x02, // AUDIO
+            0xF1, 0x3A, // PITCH V1
+        ];
+        cpu.write_memory(0x200, &program).unwrap();
+        cpu.set_program_counter(0x200);
+        let buffer: [u8; 16] = std::array::from_fn(|index| index as u8);
+        cpu.write_memory(0x300, &buffer).unwrap();
+        cpu.set_memory_location(0x300);
+        cpu.set_register(V1, 80);
+
+        let next_pattern = |cpu: &mut CPU| {
+            run(cpu, 1);
+            sound_rx.try_iter().find_map(|message| match message {
+                SoundMessage::Pattern(pattern) => Some(pattern),
+                _ => None,
+            })
+        };
+        assert_eq!(
+            next_pattern(&mut cpu),
+            Some(AudioPattern::new(buffer, AudioPattern::DEFAULT_PITCH))
+        );
+        assert_eq!(next_pattern(&mut cpu), Some(AudioPattern::new(buffer, 80)));
+    }
+
+    #[test]
+    fn jump_with_offset_adds_v0_or_vx_as_the_quirk_says() {
+        for (jump_uses_vx, expected) in [(false, 0x211), (true, 0x214)] {
+            let quirks = Quirks {
+                jump_uses_vx,
+                ..Quirks::modern()
+            };
+            // JP V0, 210, read as JP V2, 10 with the quirk
+            let mut cpu = load(quirks, &[0xB2, 0x10]);
+            cpu.set_register(V0, 0x01);
+            cpu.set_register(V2, 0x04);
+            run(&mut cpu, 1);
+            assert_eq!(cpu.program_counter(), expected);
+        }
+    }
+
+    #[test]
+    fn machine_code_calls_are_skipped_or_errors_as_the_quirk_says() {
+        // SYS 123
+        let mut cpu = load(Quirks::modern(), &[0x01, 0x23]);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.program_counter(), 0x202);
+
+        let quirks = Quirks {
+            sys_is_error: true,
+            ..Quirks::modern()
+        };
+        let mut cpu = load(quirks, &[0x01, 0x23]);
+        assert_eq!(
+            error(&mut cpu),
+            Some(CpuError::MachineCodeCall {
+                routine: 0x123,
+                address: 0x200
+            })
+        );
+    }
+
+    #[test]
+    fn waiting_for_a_key_finishes_once_it_is_released() {
+        // LD V1, K then LD V2, 02
+        let mut cpu = load(Quirks::modern(), &[0xF1, 0x0A, 0x62, 0x02]);
+        // Held from before, so it doesn't count
+        cpu.press_key(0x3);
+        run(&mut cpu, 1);
+        assert!(cpu.is_waiting());
+        cpu.release_key(0x3);
+        run(&mut cpu, 1);
+        assert!(cpu.is_waiting());
+
+        cpu.press_key(0x5);
+        run(&mut cpu, 1);
+        assert!(cpu.is_waiting());
+        assert_eq!(cpu.program_counter(), 0x200);
+
+        cpu.release_key(0x5);
+        assert!(!cpu.is_waiting());
+        run(&mut cpu, 1);
+        assert_eq!(cpu.get_register(V1), 0x5);
+        assert_eq!(cpu.program_counter(), 0x202);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.get_register(V2), 0x2);
+    }
+
+    #[test]
+    fn skips_follow_every_held_key() {
+        let program = [
+            0xE0, 0x9E, // SKP V0
+            0x00, 0xE0, // CLS
+            0xE1, 0xA1, // SKNP V1
+            0x00, 0xE0, // CLS
+        ];
+        let mut cpu = load(Quirks::modern(), &program);
+        cpu.set_register(V0, 0x4);
+        cpu.set_register(V1, 0x6);
+        cpu.press_key(0x4);
+        cpu.press_key(0x6);
+        cpu.release_key(0x6);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.program_counter(), 0x204);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.program_counter(), 0x208);
+    }
+}
@@ -0,0 +1,558 @@
+use std::{collections::BTreeSet, ops::Range};
+
+use log::debug;
+
+use super::{
+    cpu::{CPUIterationDecision, CPU},
+    error::CpuError,
+    instruction::{parse_instruction, Instruction, InstructionParseResult},
+};
+
+/// How an instruction touches memory
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Which accesses trigger a watchpoint, same as GDB's rwatch, watch and awatch
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl WatchKind {
+    fn matches(&self, access: Access) -> bool {
+        match self {
+            WatchKind::Read => access == Access::Read,
+            WatchKind::Write => access == Access::Write,
+            WatchKind::ReadWrite => true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub addresses: Range<u16>,
+    pub kind: WatchKind,
+}
+
+/// A register whose changes stop execution
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum WatchedRegister {
+    V(u8),
+    I,
+}
+
+/// Why the debugger gave control back
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Stop {
+    /// A single instruction has been executed
+    Step,
+
+    /// The program counter reached a breakpoint, the instruction there hasn't been executed yet
+    Breakpoint(u16),
+
+    /// The last instruction accessed a watched address
+    Watchpoint { address: u16, access: Access },
+
+    /// The last instruction changed a watched register
+    RegisterChanged {
+        register: WatchedRegister,
+        old: u16,
+        new: u16,
+    },
+
+    /// Stepping over or out of a subroutine is done
+    Returned,
+
+    /// The next instruction is the RET of the subroutine we were in
+    AtReturn,
+
+    /// The program exited, with 00FD or a halt
+    Halted,
+}
+
+/// What `run` is trying to reach
+#[derive(Clone, Copy, Debug)]
+enum Target {
+    /// Run until stopped by a breakpoint or watch
+    Continue,
+
+    /// Execute a single instruction
+    Step,
+
+    /// Back at `address` with the same stack depth, after a CALL
+    ReturnTo { address: u16, stack_pointer: u8 },
+
+    /// Out of the subroutine that was running at the given stack depth
+    StackBelow(u8),
+
+    /// About to execute a RET at the given stack depth
+    ReturnAt(u8),
+}
+
+/// Wraps a CPU to run it instruction by instruction, stopping at breakpoints and watches
+///
+/// `step` executes one instruction right away. `resume`, `step_over`, `step_out` and `run_to_return`
+/// only pick where to stop, `run` then executes up to a given number of instructions towards it,
+/// so the caller can keep drawing frames and ticking timers in between.
+pub struct Debugger {
+    cpu: CPU,
+    breakpoints: BTreeSet<u16>,
+    watchpoints: Vec<Watchpoint>,
+    watched_registers: BTreeSet<WatchedRegister>,
+    target: Option<Target>,
+
+    /// Set when a new target is picked, so we don't stop at the breakpoint we are resuming from
+    resuming: bool,
+}
+
+impl Debugger {
+    pub fn new(cpu: CPU) -> Self {
+        Debugger {
+            cpu,
+            breakpoints: BTreeSet::new(),
+            watchpoints: vec![],
+            watched_registers: BTreeSet::new(),
+            target: None,
+            resuming: false,
+        }
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    pub fn into_inner(self) -> CPU {
+        self.cpu
+    }
+
+    /// Returns `false` if there already was a breakpoint at `address`
+    pub fn add_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.insert(address)
+    }
+
+    /// Returns `false` if there was no breakpoint at `address`
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    pub fn add_watchpoint(&mut self, addresses: Range<u16>, kind: WatchKind) {
+        let watchpoint = Watchpoint { addresses, kind };
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    /// Returns `false` if there was no such watchpoint
+    pub fn remove_watchpoint(&mut self, addresses: Range<u16>, kind: WatchKind) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints
+            .retain(|watchpoint| watchpoint.addresses != addresses || watchpoint.kind != kind);
+        self.watchpoints.len() != count
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn watch_register(&mut self, register: WatchedRegister) {
+        self.watched_registers.insert(register);
+    }
+
+    pub fn unwatch_register(&mut self, register: WatchedRegister) {
+        self.watched_registers.remove(&register);
+    }
+
+    /// Returns `true` while `run` still has somewhere to go
+    pub fn is_running(&self) -> bool {
+        self.target.is_some()
+    }
+
+    /// Stops whatever `run` was doing
+    pub fn pause(&mut self) {
+        self.target = None;
+    }
+
+    /// Runs until a breakpoint or watch is hit
+    pub fn resume(&mut self) {
+        self.resuming = true;
+        self.target = Some(Target::Continue);
+    }
+
+    /// Runs a whole subroutine if the next instruction is a CALL, or steps otherwise
+    pub fn step_over(&mut self) {
+        self.resuming = true;
+        let program_counter = self.cpu.program_counter();
+        self.target = Some(match self.next_instruction() {
+            Some(Instruction::CALL(_)) => Target::ReturnTo {
+                address: program_counter.wrapping_add(2),
+                stack_pointer: self.cpu.stack_pointer(),
+            },
+            _ => Target::Step,
+        });
+    }
+
+    /// Runs until the current subroutine has returned
+    ///
+    /// NOTE: Outside of a subroutine, this is the same as `resume`
+    pub fn step_out(&mut self) {
+        self.resuming = true;
+        self.target = Some(match self.cpu.stack_pointer() {
+            0 => Target::Continue,
+            stack_pointer => Target::StackBelow(stack_pointer),
+        });
+    }
+
+    /// Runs until the RET of the current subroutine, without executing it
+    ///
+    /// NOTE: Outside of a subroutine, this is the same as `resume`
+    pub fn run_to_return(&mut self) {
+        self.resuming = true;
+        self.target = Some(match self.cpu.stack_pointer() {
+            0 => Target::Continue,
+            stack_pointer => Target::ReturnAt(stack_pointer),
+        });
+    }
+
+    /// Executes a single instruction, ignoring breakpoints
+    ///
+    /// Returns `Stop::Step` unless the program exited or a watch has been triggered
+    pub fn step(&mut self) -> Result<Stop, CpuError> {
+        self.target = None;
+        Ok(self.execute()?.unwrap_or(Stop::Step))
+    }
+
+    /// Executes up to `max_instructions` towards the target picked by `resume`, `step_over`,
+    /// `step_out` or `run_to_return`
+    ///
+    /// Returns `None` if the target hasn't been reached yet, or if the CPU is waiting for a key or
+    /// the next tick. A breakpoint where execution resumes from is not hit again.
+    pub fn run(&mut self, max_instructions: usize) -> Result<Option<Stop>, CpuError> {
+        for _ in 0..max_instructions {
+            let Some(target) = self.target else {
+                return Ok(None);
+            };
+            if self.cpu.is_waiting() {
+                return Ok(None);
+            }
+
+            let program_counter = self.cpu.program_counter();
+            let stack_pointer = self.cpu.stack_pointer();
+            if !self.resuming && self.breakpoints.contains(&program_counter) {
+                return Ok(Some(self.stop(Stop::Breakpoint(program_counter))));
+            }
+            if let Target::ReturnAt(depth) = target {
+                if stack_pointer == depth
+                    && matches!(self.next_instruction(), Some(Instruction::RET))
+                {
+                    return Ok(Some(self.stop(Stop::AtReturn)));
+                }
+            }
+
+            self.resuming = false;
+            // Running again would only hit the same error
+            let stop = self.execute().inspect_err(|_| self.target = None)?;
+            if let Some(stop) = stop {
+                return Ok(Some(self.stop(stop)));
+            }
+
+            let program_counter = self.cpu.program_counter();
+            let stack_pointer = self.cpu.stack_pointer();
+            let reached = match target {
+                Target::Step => Some(Stop::Step),
+                Target::ReturnTo {
+                    address,
+                    stack_pointer: depth,
+                } if program_counter == address && stack_pointer == depth => Some(Stop::Returned),
+                Target::StackBelow(depth) if stack_pointer < depth => Some(Stop::Returned),
+                _ => None,
+            };
+            if let Some(stop) = reached {
+                return Ok(Some(self.stop(stop)));
+            }
+        }
+        Ok(None)
+    }
+
+    fn stop(&mut self, stop: Stop) -> Stop {
+        debug!("Debugger stopped: {:?}", stop);
+        self.target = None;
+        stop
+    }
+
+    /// Executes one instruction, returning `Stop::Halted` if it exited or the first watch it
+    /// triggered
+    fn execute(&mut self) -> Result<Option<Stop>, CpuError> {
+        let access = self.memory_access();
+        let registers = self.cpu.registers();
+        let memory_location = self.cpu.memory_location();
+
+        if let CPUIterationDecision::Halt = self.cpu.fetch_decode_execute()? {
+            return Ok(Some(Stop::Halted));
+        }
+
+        if let Some((access, addresses)) = access {
+            let watched = self.watchpoints.iter().find_map(|watchpoint| {
+                let start = addresses.start.max(watchpoint.addresses.start as usize);
+                let end = addresses.end.min(watchpoint.addresses.end as usize);
+                (start < end && watchpoint.kind.matches(access)).then_some(start as u16)
+            });
+            if let Some(address) = watched {
+                return Ok(Some(Stop::Watchpoint { address, access }));
+            }
+        }
+
+        for register in &self.watched_registers {
+            let (old, new) = match *register {
+                WatchedRegister::V(index) => (
+                    registers[index as usize] as u16,
+                    self.cpu.get_register(index) as u16,
+                ),
+                WatchedRegister::I => (memory_location, self.cpu.memory_location()),
+            };
+            if old != new {
+                return Ok(Some(Stop::RegisterChanged {
+                    register: *register,
+                    old,
+                    new,
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    fn next_instruction(&self) -> Option<Instruction> {
+        let address = self.cpu.program_counter() as usize;
+        let memory = self.cpu.memory();
+        let opcode = ((*memory.get(address)? as u16) << 8) | *memory.get(address + 1)? as u16;
+        match parse_instruction(opcode) {
+            InstructionParseResult::Ok(instruction) => Some(instruction),
+            InstructionParseResult::Unparsed => None,
+        }
+    }
+
+    /// The memory the next instruction reads or writes, not counting the fetch itself
+    fn memory_access(&self) -> Option<(Access, Range<usize>)> {
+        let start = self.cpu.memory_location() as usize;
+        let (access, length) = match self.next_instruction()? {
+            Instruction::LDB(_) => (Access::Write, 3),
+            Instruction::LDIFromVx(x) => (Access::Write, x as usize + 1),
+            Instruction::LDVxFromI(x) => (Access::Read, x as usize + 1),
+            Instruction::LDIFromVxVy(x, y) => (Access::Write, x.abs_diff(y) as usize + 1),
+            Instruction::LDVxVyFromI(x, y) => (Access::Read, x.abs_diff(y) as usize + 1),
+            Instruction::AUDIO => (Access::Read, 16),
+            Instruction::DRW(_, _, rows) => {
+                let planes = self.cpu.screen().planes().count_ones() as usize;
+                let bytes_per_plane = if rows == 0 { 32 } else { rows as usize };
+                (Access::Read, bytes_per_plane * planes)
+            }
+            _ => return None,
+        };
+        Some((access, start..start + length))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::{Access, Debugger, Stop, WatchKind, WatchedRegister};
+    use crate::cpu::{cpu::CPU, quirks::Quirks};
+
+    /// Calls a subroutine at 0x300 from a loop
+    const PROGRAM: [u8; 8] = [
+        0x60, 0x01, // 200: LD V0, 01
+        0x70, 0x01, // 202: ADD V0, 01
+        0x23, 0x00, // 204: CALL 300
+        0x12, 0x02, // 206: JP 202
+    ];
+    const SUBROUTINE: [u8; 6] = [
+        0x71, 0x01, // 300: ADD V1, 01
+        0x72, 0x01, // 302: ADD V2, 01
+        0x00, 0xEE, // 304: RET
+    ];
+
+    fn debugger(program: &[u8]) -> Debugger {
+        let (sound_tx, _) = mpsc::channel();
+        let mut cpu = CPU::new(sound_tx, Quirks::modern());
+        cpu.write_memory(0x200, program).unwrap();
+        cpu.write_memory(0x300, &SUBROUTINE).unwrap();
+        cpu.set_program_counter(0x200);
+        Debugger::new(cpu)
+    }
+
+    #[test]
+    fn resuming_from_a_breakpoint_stops_there_next_time_round() {
+        let mut debugger = debugger(&PROGRAM);
+        debugger.add_breakpoint(0x202);
+        debugger.resume();
+        assert_eq!(debugger.run(100), Ok(Some(Stop::Breakpoint(0x202))));
+        assert_eq!(debugger.cpu().get_register(0x0), 0x01);
+        assert!(!debugger.is_running());
+
+        debugger.resume();
+        assert_eq!(debugger.run(100), Ok(Some(Stop::Breakpoint(0x202))));
+        assert_eq!(debugger.cpu().get_register(0x0), 0x02);
+        assert_eq!(debugger.cpu().get_register(0x1), 0x01);
+    }
+
+    #[test]
+    fn run_stops_short_of_the_target_after_max_instructions() {
+        let mut debugger = debugger(&PROGRAM);
+        debugger.resume();
+        assert_eq!(debugger.run(3), Ok(None));
+        assert!(debugger.is_running());
+        assert_eq!(debugger.cpu().program_counter(), 0x300);
+    }
+
+    #[test]
+    fn watchpoints_stop_on_overlapping_accesses_of_their_kind() {
+        let program = [
+            0xA3, 0x00, // LD I, 300
+            0xF2, 0x55, // LD [I], V2, writes 300 to 302
+            0xF2, 0x65, // LD V2, [I], reads 300 to 302
+        ];
+        let mut debugger = debugger(&program);
+        debugger.add_watchpoint(0x302..0x304, WatchKind::Read);
+        assert_eq!(debugger.step(), Ok(Stop::Step));
+        assert_eq!(debugger.step(), Ok(Stop::Step));
+        assert_eq!(
+            debugger.step(),
+            Ok(Stop::Watchpoint {
+                address: 0x302,
+                access: Access::Read
+            })
+        );
+
+        let mut debugger = self::debugger(&program);
+        debugger.add_watchpoint(0x2FF..0x301, WatchKind::ReadWrite);
+        debugger.resume();
+        assert_eq!(
+            debugger.run(100),
+            Ok(Some(Stop::Watchpoint {
+                address: 0x300,
+                access: Access::Write
+            }))
+        );
+        assert_eq!(debugger.cpu().program_counter(), 0x204);
+
+        // Right next to the accessed bytes isn't an overlap
+        let mut debugger = self::debugger(&program);
+        debugger.add_watchpoint(0x303..0x310, WatchKind::ReadWrite);
+        debugger.add_watchpoint(0x200..0x300, WatchKind::Write);
+        for _ in 0..3 {
+            assert_eq!(debugger.step(), Ok(Stop::Step));
+        }
+    }
+
+    #[test]
+    fn watched_registers_stop_when_they_change() {
+        let program = [
+            0x63, 0x00, // LD V3, 00
+            0x63, 0x07, // LD V3, 07
+            0xA3, 0x00, // LD I, 300
+        ];
+        let mut debugger = debugger(&program);
+        debugger.watch_register(WatchedRegister::V(3));
+        debugger.watch_register(WatchedRegister::I);
+        debugger.resume();
+        assert_eq!(
+            debugger.run(100),
+            Ok(Some(Stop::RegisterChanged {
+                register: WatchedRegister::V(3),
+                old: 0x00,
+                new: 0x07
+            }))
+        );
+        debugger.resume();
+        assert_eq!(
+            debugger.run(100),
+            Ok(Some(Stop::RegisterChanged {
+                register: WatchedRegister::I,
+                old: 0x000,
+                new: 0x300
+            }))
+        );
+    }
+
+    #[test]
+    fn step_over_runs_a_whole_call() {
+        let mut debugger = debugger(&PROGRAM);
+        debugger.cpu_mut().set_program_counter(0x204);
+        debugger.step_over();
+        assert_eq!(debugger.run(100), Ok(Some(Stop::Returned)));
+        assert_eq!(debugger.cpu().program_counter(), 0x206);
+        assert_eq!(debugger.cpu().stack_pointer(), 0);
+        assert_eq!(debugger.cpu().get_register(0x2), 0x01);
+
+        // Anything else is a single step
+        debugger.step_over();
+        assert_eq!(debugger.run(100), Ok(Some(Stop::Step)));
+        assert_eq!(debugger.cpu().program_counter(), 0x202);
+    }
+
+    #[test]
+    fn step_over_still_stops_at_breakpoints_in_the_call() {
+        let mut debugger = debugger(&PROGRAM);
+        debugger.cpu_mut().set_program_counter(0x204);
+        debugger.add_breakpoint(0x302);
+        debugger.step_over();
+        assert_eq!(debugger.run(100), Ok(Some(Stop::Breakpoint(0x302))));
+    }
+
+    #[test]
+    fn step_out_returns_from_the_current_subroutine() {
+        let mut debugger = debugger(&PROGRAM);
+        debugger.cpu_mut().set_program_counter(0x204);
+        assert_eq!(debugger.step(), Ok(Stop::Step));
+        assert_eq!(debugger.cpu().stack_pointer(), 1);
+
+        debugger.step_out();
+        assert_eq!(debugger.run(100), Ok(Some(Stop::Returned)));
+        assert_eq!(debugger.cpu().program_counter(), 0x206);
+        assert_eq!(debugger.cpu().stack_pointer(), 0);
+        assert_eq!(debugger.cpu().get_register(0x1), 0x01);
+        assert_eq!(debugger.cpu().get_register(0x2), 0x01);
+    }
+
+    #[test]
+    fn run_to_return_stops_before_the_ret() {
+        let mut debugger = debugger(&PROGRAM);
+        debugger.cpu_mut().set_program_counter(0x204);
+        assert_eq!(debugger.step(), Ok(Stop::Step));
+
+        debugger.run_to_return();
+        assert_eq!(debugger.run(100), Ok(Some(Stop::AtReturn)));
+        assert_eq!(debugger.cpu().program_counter(), 0x304);
+        assert_eq!(debugger.cpu().stack_pointer(), 1);
+        assert_eq!(debugger.cpu().get_register(0x2), 0x01);
+    }
+
+    #[test]
+    fn exiting_stops_with_halted() {
+        let program = [
+            0x60, 0x05, // LD V0, 05
+            0x00, 0xFD, // EXIT
+        ];
+        let mut debugger = debugger(&program);
+        debugger.resume();
+        assert_eq!(debugger.run(100), Ok(Some(Stop::Halted)));
+        assert!(!debugger.is_running());
+
+        let mut debugger = self::debugger(&program);
+        assert_eq!(debugger.step(), Ok(Stop::Step));
+        assert_eq!(debugger.step(), Ok(Stop::Halted));
+    }
+}
@@ -0,0 +1,52 @@
+use std::fmt::Display;
+
+/// Reasons why the CPU cannot go on executing the program
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CpuError {
+    /// The opcode at `address` is not a known instruction
+    UnknownOpcode { opcode: u16, address: u16 },
+
+    /// SYS at `address` tried to run the machine code at `routine`, which can't be emulated
+    MachineCodeCall { routine: u16, address: u16 },
+
+    /// CALL with all 16 stack levels in use
+    StackOverflow { address: u16 },
+
+    /// RET with an empty stack
+    StackUnderflow { address: u16 },
+
+    /// The program counter points outside of memory
+    ProgramCounterOutOfRange { address: u16 },
+
+    /// An access to `length` bytes of memory starting at `address` went past the end, by an
+    /// instruction reading or writing from I or by `CPU::write_memory`
+    MemoryOutOfRange { address: usize, length: usize },
+}
+
+impl Display for CpuError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use CpuError::*;
+        match self {
+            UnknownOpcode { opcode, address } => {
+                write!(f, "Unknown opcode {:04X} at {:03X}", opcode, address)
+            }
+            MachineCodeCall { routine, address } => write!(
+                f,
+                "Machine code routine {:03X} called at {:03X}",
+                routine, address
+            ),
+            StackOverflow { address } => write!(f, "Stack overflow at {:03X}", address),
+            StackUnderflow { address } => write!(f, "Stack underflow at {:03X}", address),
+            ProgramCounterOutOfRange { address } => {
+                write!(f, "Program counter out of memory: {:03X}", address)
+            }
+            MemoryOutOfRange { address, length } => write!(
+                f,
+                "Memory access out of range: {} bytes from {:03X}",
+                length, address
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CpuError {}
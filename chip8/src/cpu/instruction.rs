@@ -1,9 +1,15 @@
 use core::fmt::Debug;
 
 pub enum Instruction {
+    /** SYS addr - Call the machine code routine at addr */
+    SYS(u16),
+
     /** JP addr */
     JP(u16),
 
+    /** JP V0, addr - Jump to addr + V0, or to xnn + Vx with the jump quirk */
+    JPV0(u16),
+
     /** CLS */
     CLS,
 
@@ -93,21 +99,71 @@ pub enum Instruction {
     /** ADD Vx, Vy - Set Vx = Vx + Vy, set VF = carry */
     ADDVxVy(u8, u8),
 
-    /** SHL Vx - Set VF = MSb of Vx, then set Vx = Vx << 1 */
-    SHL(u8),
+    /** SHL Vx {, Vy} - Set VF = MSb of Vx, then set Vx = Vx << 1 */
+    SHL(u8, u8),
 
-    /** SHR Vx - Set VF = LSb of Vx, then set Vx = Vx >> 1 */
-    SHR(u8),
+    /** SHR Vx {, Vy} - Set VF = LSb of Vx, then set Vx = Vx >> 1 */
+    SHR(u8, u8),
 
     /** SUBN Vx, Vy - Set Vx = Vy - Vx, set VF = NOT borrow. */
     SUBN(u8, u8),
+
+    /** SCD nibble - Scroll the display down by n pixels (SUPER-CHIP) */
+    SCD(u8),
+
+    /** SCR - Scroll the display right by 4 pixels (SUPER-CHIP) */
+    SCR,
+
+    /** SCL - Scroll the display left by 4 pixels (SUPER-CHIP) */
+    SCL,
+
+    /** EXIT - Exit the interpreter (SUPER-CHIP) */
+    EXIT,
+
+    /** LOW - Disable high resolution mode (SUPER-CHIP) */
+    LOW,
+
+    /** HIGH - Enable 128x64 high resolution mode (SUPER-CHIP) */
+    HIGH,
+
+    /** LD HF, Vx - Set I = location of the 8x10 sprite for digit Vx (SUPER-CHIP) */
+    LDHF(u8),
+
+    /** LD R, Vx - Store V0 through Vx in the RPL user flags (SUPER-CHIP) */
+    LDRFromVx(u8),
+
+    /** LD Vx, R - Read V0 through Vx from the RPL user flags (SUPER-CHIP) */
+    LDVxFromR(u8),
+
+    /** SCU nibble - Scroll the selected planes up by n pixels (XO-CHIP) */
+    SCU(u8),
+
+    /** LD \[I\], Vx - Vy - Copy the values of registers Vx through Vy into memory, starting at I (XO-CHIP) */
+    LDIFromVxVy(u8, u8),
+
+    /** LD Vx - Vy, \[I\] - Read registers Vx through Vy from memory, starting at I (XO-CHIP) */
+    LDVxVyFromI(u8, u8),
+
+    /** LD I, long - Set I to the 16 bit address in the next two bytes (XO-CHIP) */
+    LDILong,
+
+    /** PLANE n - Select the bitplanes used by drawing, clearing and scrolling (XO-CHIP) */
+    PLANE(u8),
+
+    /** AUDIO - Load the 16 byte audio pattern starting at I (XO-CHIP) */
+    AUDIO,
+
+    /** PITCH Vx - Set the audio pattern playback rate to Vx (XO-CHIP) */
+    PITCH(u8),
 }
 
 impl Debug for Instruction {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         use Instruction::*;
         match self {
+            SYS(arg0) => write!(f, "SYS ({:03X})", arg0),
             JP(arg0) => write!(f, "JP ({:03X})", arg0),
+            JPV0(arg0) => write!(f, "JP (V0, {:03X})", arg0),
             CLS => write!(f, "CLS"),
             RET => write!(f, "RET"),
             CALL(arg0) => write!(f, "CALL ({:03X})", arg0),
@@ -136,11 +192,27 @@ impl Debug for Instruction {
             ADDIVx(arg0) => write!(f, "ADD (I, V{:X})", arg0),
             SKP(arg0) => write!(f, "SKP (V{:X})", arg0),
             SKNP(arg0) => write!(f, "SKNP (V{:X})", arg0),
-            SHL(arg0) => write!(f, "SHL (V{:X})", arg0),
-            SHR(arg0) => write!(f, "SHR (V{:X})", arg0),
+            SHL(arg0, arg1) => write!(f, "SHL (V{:X}, V{:X})", arg0, arg1),
+            SHR(arg0, arg1) => write!(f, "SHR (V{:X}, V{:X})", arg0, arg1),
             SUB(arg0, arg1) => write!(f, "SUB (V{:X}, V{:X})", arg0, arg1),
             SUBN(arg0, arg1) => write!(f, "SUBN (V{:X}, V{:X})", arg0, arg1),
             ADDVxVy(arg0, arg1) => write!(f, "ADD (V{:X}, V{:X})", arg0, arg1),
+            SCD(arg0) => write!(f, "SCD ({:X})", arg0),
+            SCR => write!(f, "SCR"),
+            SCL => write!(f, "SCL"),
+            EXIT => write!(f, "EXIT"),
+            LOW => write!(f, "LOW"),
+            HIGH => write!(f, "HIGH"),
+            LDHF(arg0) => write!(f, "LD (HF, V{:X})", arg0),
+            LDRFromVx(arg0) => write!(f, "LD (R, V{:X})", arg0),
+            LDVxFromR(arg0) => write!(f, "LD (V{:X}, R)", arg0),
+            SCU(arg0) => write!(f, "SCU ({:X})", arg0),
+            LDIFromVxVy(arg0, arg1) => write!(f, "LD (I, V{:X} - V{:X})", arg0, arg1),
+            LDVxVyFromI(arg0, arg1) => write!(f, "LD (V{:X} - V{:X}, I)", arg0, arg1),
+            LDILong => write!(f, "LD (I, long)"),
+            PLANE(arg0) => write!(f, "PLANE ({:X})", arg0),
+            AUDIO => write!(f, "AUDIO"),
+            PITCH(arg0) => write!(f, "PITCH (V{:X})", arg0),
         }
     }
 }
@@ -159,11 +231,11 @@ fn parse_8_instruction(instruction: u16) -> InstructionParseResult {
         0x03 => Ok(Instruction::XOR(x, y)),
         0x04 => Ok(Instruction::ADDVxVy(x, y)),
         0x05 => Ok(Instruction::SUB(x, y)),
-        // NOTE: We ignore the Vy as most emulators do nowadays
-        0x06 => Ok(Instruction::SHR(x)),
+        // NOTE: Whether Vy is used depends on the CPU quirks
+        0x06 => Ok(Instruction::SHR(x, y)),
         0x07 => Ok(Instruction::SUBN(x, y)),
-        // NOTE: We ignore the Vy as most emulators do nowadays
-        0x0E => Ok(Instruction::SHL(x)),
+        // NOTE: Whether Vy is used depends on the CPU quirks
+        0x0E => Ok(Instruction::SHL(x, y)),
         _ => Unparsed,
     }
 }
@@ -175,6 +247,21 @@ fn parse_f_instruction(instruction: u16) -> InstructionParseResult {
     let register = ((instruction & 0x0f00) >> 8) as u8;
     let least_significant_byte = (instruction & 0x00ff) as u8;
 
+    if instruction == 0xF000 {
+        // 0xF000 nnnn: LD I, long
+        return Ok(Instruction::LDILong);
+    }
+
+    if instruction == 0xF002 {
+        // 0xF002: AUDIO
+        return Ok(Instruction::AUDIO);
+    }
+
+    if least_significant_byte == 0x01 {
+        // 0xFn01: PLANE n
+        return Ok(Instruction::PLANE(register));
+    }
+
     if least_significant_byte == 0x07 {
         // 0xFx07, LD Vx, DT
         return Ok(Instruction::LDVxFromDT(register));
@@ -205,6 +292,16 @@ fn parse_f_instruction(instruction: u16) -> InstructionParseResult {
         return Ok(Instruction::LDF(register));
     }
 
+    if least_significant_byte == 0x30 {
+        // 0xFx30: LD HF, Vx
+        return Ok(Instruction::LDHF(register));
+    }
+
+    if least_significant_byte == 0x3A {
+        // 0xFx3A: PITCH Vx
+        return Ok(Instruction::PITCH(register));
+    }
+
     if least_significant_byte == 0x33 {
         // 0xFx33: LD B, Vx
         return Ok(Instruction::LDB(register));
@@ -219,6 +316,15 @@ fn parse_f_instruction(instruction: u16) -> InstructionParseResult {
         return Ok(Instruction::LDIFromVx(register));
     }
 
+    if least_significant_byte == 0x75 {
+        // 0xFx75 - LD R, Vx
+        return Ok(Instruction::LDRFromVx(register));
+    }
+    if least_significant_byte == 0x85 {
+        // 0xFx85 - LD Vx, R
+        return Ok(Instruction::LDVxFromR(register));
+    }
+
     Unparsed
 }
 
@@ -237,6 +343,27 @@ pub fn parse_instruction(instruction: u16) -> InstructionParseResult {
     if instruction == 0x00EE {
         return Ok(Instruction::RET);
     }
+    if instruction & 0xFFF0 == 0x00C0 {
+        return Ok(Instruction::SCD((instruction & 0x000F) as u8));
+    }
+    if instruction & 0xFFF0 == 0x00D0 {
+        return Ok(Instruction::SCU((instruction & 0x000F) as u8));
+    }
+    if instruction == 0x00FB {
+        return Ok(Instruction::SCR);
+    }
+    if instruction == 0x00FC {
+        return Ok(Instruction::SCL);
+    }
+    if instruction == 0x00FD {
+        return Ok(Instruction::EXIT);
+    }
+    if instruction == 0x00FE {
+        return Ok(Instruction::LOW);
+    }
+    if instruction == 0x00FF {
+        return Ok(Instruction::HIGH);
+    }
 
     // Parse other instructions
 
@@ -247,6 +374,10 @@ pub fn parse_instruction(instruction: u16) -> InstructionParseResult {
     let least_significant_byte: u8 = least_significant_byte.try_into().unwrap();
 
     return match most_significant_byte & 0xf0 {
+        0x00 => {
+            // 0x0nnn = SYS nnn
+            Ok(Instruction::SYS(instruction & 0xfff))
+        }
         0x10 => {
             // 0x1nnn = JP nnn
             Ok(Instruction::JP(instruction & 0xfff))
@@ -268,10 +399,17 @@ pub fn parse_instruction(instruction: u16) -> InstructionParseResult {
             Ok(Instruction::SNE(register_index, value))
         }
         0x50 => {
-            //0x5xy0 = SE Vx, Vy
             let x = ((instruction & 0x0f00) >> 8) as u8;
             let y = ((instruction & 0x00f0) >> 4) as u8;
-            Ok(Instruction::SEVxVy(x, y))
+            match least_significant_byte & 0x0f {
+                //0x5xy0 = SE Vx, Vy
+                0x0 => Ok(Instruction::SEVxVy(x, y)),
+                //0x5xy2 = LD [I], Vx - Vy
+                0x2 => Ok(Instruction::LDIFromVxVy(x, y)),
+                //0x5xy3 = LD Vx - Vy, [I]
+                0x3 => Ok(Instruction::LDVxVyFromI(x, y)),
+                _ => Unparsed,
+            }
         }
         0x60 => {
             // 0x6xkk = LD Vx, kk
@@ -300,6 +438,10 @@ pub fn parse_instruction(instruction: u16) -> InstructionParseResult {
             let address = instruction & 0xfff;
             Ok(Instruction::LDI(address))
         }
+        0xB0 => {
+            // 0xBnnn = JP V0, nnn
+            Ok(Instruction::JPV0(instruction & 0xfff))
+        }
         0xC0 => {
             // 0xCxkk = RND Vx, byte & kk
             let register_index = most_significant_byte & 0x0f;
@@ -308,6 +450,7 @@ pub fn parse_instruction(instruction: u16) -> InstructionParseResult {
         }
         0xD0 => {
             // 0xDxyn = DRW Vx, Vy, sprite length
+            // 0xDxy0 draws a 16x16 sprite on SUPER-CHIP
             let sprite_length = least_significant_byte & 0x0f;
             let x = most_significant_byte & 0x0f;
             let y = (least_significant_byte & 0xf0) >> 4;
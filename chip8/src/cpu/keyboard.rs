@@ -1,7 +1,33 @@
-use std::collections::HashMap;
+/// The 16 keys of the hex keypad, one bit per key
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Keypad {
+    pressed: u16,
+}
+
+impl Keypad {
+    pub fn new() -> Self {
+        Keypad { pressed: 0 }
+    }
+
+    pub fn from_bits(bits: u16) -> Self {
+        Keypad { pressed: bits }
+    }
+
+    /// Bit n is set while key n is held
+    pub fn bits(&self) -> u16 {
+        self.pressed
+    }
+
+    /// NOTE: Only the low nibble of `key` is used, like on the COSMAC VIP
+    pub fn press(&mut self, key: u8) {
+        self.pressed |= 1 << (key & 0xF);
+    }
 
-use tao::keyboard::KeyCode;
+    pub fn release(&mut self, key: u8) {
+        self.pressed &= !(1 << (key & 0xF));
+    }
 
-pub fn parse_key_code(key_code: KeyCode, key_map: &HashMap<KeyCode, u8>) -> Option<u8> {
-    key_map.get(&key_code).copied()
+    pub fn is_pressed(&self, key: u8) -> bool {
+        self.pressed & (1 << (key & 0xF)) != 0
+    }
 }
@@ -1,6 +1,12 @@
 pub mod cpu;
+pub mod debugger;
+pub mod error;
 pub mod instruction;
 pub mod keyboard;
+pub mod quirks;
+pub mod rewind;
 pub mod rng;
+pub mod scheduler;
+pub mod snapshot;
 pub mod sprites;
 pub mod timer;
@@ -0,0 +1,149 @@
+/// Where Fx55/Fx65 leave I after loading or storing V0 to Vx
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadStoreIndex {
+    /// I is left untouched
+    Unchanged,
+
+    /// I points to the last register loaded or stored, as on CHIP-48
+    IncrementByX,
+
+    /// I points after the last register loaded or stored, as on the COSMAC VIP
+    IncrementByXPlusOne,
+}
+
+impl LoadStoreIndex {
+    /// How much I moves after loading or storing V0 to V`register`
+    pub fn increment(self, register: u8) -> u16 {
+        match self {
+            LoadStoreIndex::Unchanged => 0,
+            LoadStoreIndex::IncrementByX => register as u16,
+            LoadStoreIndex::IncrementByXPlusOne => register as u16 + 1,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LoadStoreIndex::Unchanged => "unchanged",
+            LoadStoreIndex::IncrementByX => "x",
+            LoadStoreIndex::IncrementByXPlusOne => "x-plus-one",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "unchanged" => Some(LoadStoreIndex::Unchanged),
+            "x" => Some(LoadStoreIndex::IncrementByX),
+            "x-plus-one" => Some(LoadStoreIndex::IncrementByXPlusOne),
+            _ => None,
+        }
+    }
+}
+
+/// Behaviours that differ between CHIP-8 interpreters.
+///
+/// Every flag describes what the CPU does when it is set, so `Quirks::modern()`
+/// (all flags off except sprite wrapping) matches what most emulators do nowadays.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks {
+    /// 8xy6/8xyE copy Vy into Vx before shifting
+    pub shift_uses_vy: bool,
+
+    /// Where Fx55/Fx65 leave I
+    pub load_store_index: LoadStoreIndex,
+
+    /// 8xy1/8xy2/8xy3 reset VF to 0
+    pub vf_reset: bool,
+
+    /// Bnnn jumps to xnn + Vx instead of nnn + V0
+    pub jump_uses_vx: bool,
+
+    /// Sprites are cut at the screen edges instead of wrapping around
+    pub clip_sprites: bool,
+
+    /// Dxyn waits for the next 60Hz tick before the CPU continues
+    pub display_wait: bool,
+
+    /// 0nnn stops execution with an error instead of being ignored
+    pub sys_is_error: bool,
+
+    /// 64 KiB of memory instead of 4 KiB, as on XO-CHIP
+    pub extended_memory: bool,
+}
+
+impl Quirks {
+    /// The original COSMAC VIP interpreter
+    pub const fn cosmac_vip() -> Self {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_index: LoadStoreIndex::IncrementByXPlusOne,
+            vf_reset: true,
+            jump_uses_vx: false,
+            clip_sprites: true,
+            display_wait: true,
+            sys_is_error: true,
+            extended_memory: false,
+        }
+    }
+
+    /// CHIP-48 on the HP48 calculator
+    pub const fn chip48() -> Self {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_index: LoadStoreIndex::IncrementByX,
+            vf_reset: false,
+            jump_uses_vx: true,
+            clip_sprites: true,
+            display_wait: false,
+            sys_is_error: false,
+            extended_memory: false,
+        }
+    }
+
+    /// SUPER-CHIP 1.1
+    pub const fn superchip() -> Self {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_index: LoadStoreIndex::Unchanged,
+            vf_reset: false,
+            jump_uses_vx: true,
+            clip_sprites: true,
+            display_wait: false,
+            sys_is_error: false,
+            extended_memory: false,
+        }
+    }
+
+    /// XO-CHIP, as implemented by Octo
+    pub const fn xo_chip() -> Self {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_index: LoadStoreIndex::IncrementByXPlusOne,
+            vf_reset: false,
+            jump_uses_vx: false,
+            clip_sprites: false,
+            display_wait: false,
+            sys_is_error: false,
+            extended_memory: true,
+        }
+    }
+
+    /// What most modern interpreters do, and what this emulator has always done
+    pub const fn modern() -> Self {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_index: LoadStoreIndex::Unchanged,
+            vf_reset: false,
+            jump_uses_vx: false,
+            clip_sprites: false,
+            display_wait: false,
+            sys_is_error: false,
+            extended_memory: false,
+        }
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Self::modern()
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::VecDeque;
+
+use super::snapshot::Snapshot;
+
+/// Bytes that differ between two buffers, or the whole buffer if their sizes differ
+#[derive(Clone, Debug)]
+enum BufferDelta {
+    Sparse(Vec<(usize, u8)>),
+    Full(Vec<u8>),
+}
+
+impl BufferDelta {
+    /// Records what needs to change in `newer` to get back `older`
+    fn new(newer: &[u8], older: &[u8]) -> Self {
+        if newer.len() != older.len() {
+            return BufferDelta::Full(older.to_vec());
+        }
+        let changes = newer
+            .iter()
+            .zip(older)
+            .enumerate()
+            .filter(|(_, (new, old))| new != old)
+            .map(|(index, (_, old))| (index, *old))
+            .collect();
+        BufferDelta::Sparse(changes)
+    }
+
+    fn apply(&self, buffer: &mut Vec<u8>) {
+        match self {
+            BufferDelta::Sparse(changes) => {
+                for (index, value) in changes {
+                    buffer[*index] = *value;
+                }
+            }
+            BufferDelta::Full(older) => *buffer = older.clone(),
+        }
+    }
+}
+
+/// Turns a snapshot into the one captured the frame before
+#[derive(Clone, Debug)]
+struct SnapshotDelta {
+    /// Everything else from the older snapshot, with empty memory and screen buffer
+    rest: Snapshot,
+    memory: BufferDelta,
+    screen_buffer: BufferDelta,
+}
+
+/// A bounded history of per-frame snapshots
+///
+/// Only the latest snapshot is stored in full, older ones are kept as deltas
+/// against the frame after them since most of the memory doesn't change.
+pub struct RewindBuffer {
+    capacity: usize,
+    latest: Option<Snapshot>,
+    deltas: VecDeque<SnapshotDelta>,
+}
+
+impl RewindBuffer {
+    /// Keeps up to `capacity` frames before the latest one
+    pub fn new(capacity: usize) -> Self {
+        RewindBuffer {
+            capacity,
+            latest: None,
+            deltas: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Forgets every frame, e.g. when the machine jumps to an unrelated state
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+    }
+
+    pub fn push(&mut self, snapshot: Snapshot) {
+        if let Some(older) = self.latest.take() {
+            if self.capacity == 0 {
+                self.latest = Some(snapshot);
+                return;
+            }
+            if self.deltas.len() == self.capacity {
+                self.deltas.pop_front();
+            }
+            let delta = SnapshotDelta {
+                memory: BufferDelta::new(&snapshot.memory, &older.memory),
+                screen_buffer: BufferDelta::new(&snapshot.screen_buffer, &older.screen_buffer),
+                rest: Snapshot {
+                    memory: vec![],
+                    screen_buffer: vec![],
+                    ..older
+                },
+            };
+            self.deltas.push_back(delta);
+        }
+        self.latest = Some(snapshot);
+    }
+
+    /// Drops the latest snapshot and returns the one before it, if any
+    pub fn pop(&mut self) -> Option<Snapshot> {
+        let delta = self.deltas.pop_back()?;
+        let latest = self.latest.take()?;
+
+        let mut memory = latest.memory;
+        delta.memory.apply(&mut memory);
+        let mut screen_buffer = latest.screen_buffer;
+        delta.screen_buffer.apply(&mut screen_buffer);
+
+        let previous = Snapshot {
+            memory,
+            screen_buffer,
+            ..delta.rest
+        };
+        self.latest = Some(previous.clone());
+        Some(previous)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::{BufferDelta, RewindBuffer};
+    use crate::cpu::{cpu::CPU, quirks::Quirks, snapshot::Snapshot};
+
+    fn cpu() -> CPU {
+        let (sound_tx, _) = mpsc::channel();
+        CPU::new(sound_tx, Quirks::modern())
+    }
+
+    /// A snapshot telling `frame` apart, in its program counter, memory and screen
+    fn snapshot(frame: u16) -> Snapshot {
+        let mut snapshot = cpu().snapshot();
+        snapshot.program_counter = 0x200 + frame * 2;
+        snapshot.memory[0x300] = frame as u8;
+        snapshot.screen_buffer[frame as usize] = 1;
+        // Every CPU starts from a random seed
+        snapshot.rng_state = vec![0; 8];
+        snapshot
+    }
+
+    #[test]
+    fn deltas_hold_the_changed_bytes_or_the_whole_buffer() {
+        let older = [1, 2, 3, 4];
+        let newer = [1, 9, 3, 8];
+        let delta = BufferDelta::new(&newer, &older);
+        assert!(matches!(&delta, BufferDelta::Sparse(changes) if *changes == vec![(1, 2), (3, 4)]));
+        let mut buffer = newer.to_vec();
+        delta.apply(&mut buffer);
+        assert_eq!(buffer, older);
+
+        // The screen buffer changes size with the resolution
+        let delta = BufferDelta::new(&[0; 8], &older);
+        assert!(matches!(delta, BufferDelta::Full(_)));
+        let mut buffer = vec![0; 8];
+        delta.apply(&mut buffer);
+        assert_eq!(buffer, older);
+    }
+
+    #[test]
+    fn pop_walks_back_one_frame_at_a_time() {
+        let mut history = RewindBuffer::new(10);
+        for frame in 0..4 {
+            history.push(snapshot(frame));
+        }
+        for frame in (0..3).rev() {
+            assert_eq!(history.pop(), Some(snapshot(frame)));
+        }
+        assert_eq!(history.pop(), None);
+    }
+
+    #[test]
+    fn frames_past_the_capacity_are_forgotten_oldest_first() {
+        let mut history = RewindBuffer::new(2);
+        for frame in 0..5 {
+            history.push(snapshot(frame));
+        }
+        assert_eq!(history.pop(), Some(snapshot(3)));
+        assert_eq!(history.pop(), Some(snapshot(2)));
+        assert_eq!(history.pop(), None);
+    }
+
+    #[test]
+    fn resolution_changes_are_rewound() {
+        let mut history = RewindBuffer::new(2);
+        let low = snapshot(0);
+        let mut high = snapshot(1);
+        high.high_resolution = true;
+        high.screen_width = 128;
+        high.screen_height = 64;
+        high.screen_buffer = vec![0; 128 * 64];
+        history.push(low.clone());
+        history.push(high);
+        assert_eq!(history.pop(), Some(low));
+    }
+
+    #[test]
+    fn restoring_a_state_forgets_the_history() {
+        let mut cpu = cpu();
+        cpu.enable_rewind(10);
+        let saved = cpu.snapshot();
+        for _ in 0..3 {
+            cpu.tick(0);
+        }
+        cpu.restore(&saved).unwrap();
+        assert!(!cpu.rewind());
+
+        cpu.tick(0);
+        cpu.tick(0);
+        assert!(cpu.rewind());
+    }
+}
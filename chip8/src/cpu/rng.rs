@@ -1,6 +1,219 @@
 use rand::Rng;
 
-pub fn random_byte() -> u8 {
-    let mut rng = rand::thread_rng();
-    rng.gen_range(0..=255).try_into().unwrap()
+/// Where RND gets its bytes from
+pub trait RandomSource: Send {
+    fn next_byte(&mut self) -> u8;
+
+    /// Restarts the sequence, the same seed always gives the same bytes
+    fn reseed(&mut self, seed: u64);
+
+    /// Everything needed to carry on the sequence from where it is, for save states
+    fn state(&self) -> Vec<u8>;
+
+    /// Carries on the sequence from a `state`, which must come from the same kind of source
+    fn set_state(&mut self, state: &[u8]) -> Result<(), String>;
+}
+
+fn state_size_error(expected: usize, found: usize) -> String {
+    format!(
+        "RND state is {} bytes long, expected {} for this algorithm",
+        found, expected
+    )
+}
+
+/// SplitMix64, small and fast enough for RND, and easy to replay from a seed on any platform
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SeededRng {
+    state: u64,
+}
+
+impl SeededRng {
+    pub fn new(seed: u64) -> Self {
+        SeededRng { state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+impl RandomSource for SeededRng {
+    fn next_byte(&mut self) -> u8 {
+        (self.next_u64() >> 56) as u8
+    }
+
+    fn reseed(&mut self, seed: u64) {
+        self.state = seed;
+    }
+
+    fn state(&self) -> Vec<u8> {
+        self.state.to_be_bytes().to_vec()
+    }
+
+    fn set_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let state: [u8; 8] = state
+            .try_into()
+            .map_err(|_| state_size_error(8, state.len()))?;
+        self.state = u64::from_be_bytes(state);
+        Ok(())
+    }
+}
+
+/// The RND routine of the COSMAC VIP interpreter
+///
+/// The VIP kept a 16 bit counter in R9, and on every RND it incremented it, added the byte at
+/// `0x0100 + R9.0` to R9.1 and returned that sum. Page 0x01 held the interpreter's own code, which
+/// acted as a fixed lookup table, so results are strongly correlated with how many RNDs ran before.
+///
+/// NOTE: We don't ship the interpreter, the table is filled from the seed instead, so sequences
+/// have the same shape as on the VIP but not the same values
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CosmacVipRng {
+    counter: u16,
+    table: [u8; 256],
+}
+
+impl CosmacVipRng {
+    pub fn new(seed: u64) -> Self {
+        let mut rng = CosmacVipRng {
+            counter: 0,
+            table: [0; 256],
+        };
+        rng.reseed(seed);
+        rng
+    }
+}
+
+impl RandomSource for CosmacVipRng {
+    fn next_byte(&mut self) -> u8 {
+        self.counter = self.counter.wrapping_add(1);
+        let [high, low] = self.counter.to_be_bytes();
+        let byte = high.wrapping_add(self.table[low as usize]);
+        self.counter = u16::from_be_bytes([byte, low]);
+        byte
+    }
+
+    fn reseed(&mut self, seed: u64) {
+        let mut filler = SeededRng::new(seed);
+        for chunk in self.table.chunks_mut(8) {
+            chunk.copy_from_slice(&filler.next_u64().to_be_bytes());
+        }
+        self.counter = (seed >> 48) as u16;
+    }
+
+    /// The counter, then the table
+    fn state(&self) -> Vec<u8> {
+        let mut state = self.counter.to_be_bytes().to_vec();
+        state.extend_from_slice(&self.table);
+        state
+    }
+
+    fn set_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let expected = 2 + self.table.len();
+        if state.len() != expected {
+            return Err(state_size_error(expected, state.len()));
+        }
+        self.counter = u16::from_be_bytes([state[0], state[1]]);
+        self.table.copy_from_slice(&state[2..]);
+        Ok(())
+    }
+}
+
+/// The RND algorithms to pick from, stored in movies so they replay with the same one
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RndAlgorithm {
+    #[default]
+    SplitMix,
+    CosmacVip,
+}
+
+impl RndAlgorithm {
+    pub fn source(&self, seed: u64) -> Box<dyn RandomSource> {
+        match self {
+            RndAlgorithm::SplitMix => Box::new(SeededRng::new(seed)),
+            RndAlgorithm::CosmacVip => Box::new(CosmacVipRng::new(seed)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RndAlgorithm::SplitMix => "splitmix",
+            RndAlgorithm::CosmacVip => "cosmac-vip",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "splitmix" => Some(RndAlgorithm::SplitMix),
+            "cosmac-vip" => Some(RndAlgorithm::CosmacVip),
+            _ => None,
+        }
+    }
+}
+
+/// A seed for runs that don't need to be reproduced
+pub fn random_seed() -> u64 {
+    rand::thread_rng().gen()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CosmacVipRng, RandomSource, SeededRng};
+
+    fn bytes(rng: &mut dyn RandomSource, count: usize) -> Vec<u8> {
+        (0..count).map(|_| rng.next_byte()).collect()
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_bytes() {
+        let first = bytes(&mut SeededRng::new(1234), 64);
+        assert_eq!(bytes(&mut SeededRng::new(1234), 64), first);
+        assert_ne!(bytes(&mut SeededRng::new(1235), 64), first);
+
+        let mut rng = SeededRng::new(99);
+        bytes(&mut rng, 10);
+        rng.reseed(1234);
+        assert_eq!(bytes(&mut rng, 64), first);
+
+        let first = bytes(&mut CosmacVipRng::new(1234), 64);
+        assert_eq!(bytes(&mut CosmacVipRng::new(1234), 64), first);
+    }
+
+    #[test]
+    fn state_carries_on_the_sequence() {
+        let sources: [Box<dyn RandomSource>; 2] = [
+            Box::new(SeededRng::new(42)),
+            Box::new(CosmacVipRng::new(42)),
+        ];
+        for mut rng in sources {
+            bytes(rng.as_mut(), 10);
+            let state = rng.state();
+            let expected = bytes(rng.as_mut(), 32);
+
+            rng.reseed(7);
+            rng.set_state(&state).unwrap();
+            assert_eq!(bytes(rng.as_mut(), 32), expected);
+        }
+    }
+
+    #[test]
+    fn state_of_the_wrong_size_is_rejected() {
+        let mut rng = SeededRng::new(42);
+        let state = rng.state();
+        assert_eq!(state.len(), 8);
+        assert!(rng.set_state(&state[..7]).is_err());
+        assert!(rng.set_state(&CosmacVipRng::new(42).state()).is_err());
+        assert_eq!(rng.state(), state);
+
+        let mut rng = CosmacVipRng::new(42);
+        let state = rng.state();
+        assert_eq!(state.len(), 258);
+        assert!(rng.set_state(&state[..257]).is_err());
+        assert!(rng.set_state(&SeededRng::new(42).state()).is_err());
+        assert_eq!(rng.state(), state);
+    }
 }
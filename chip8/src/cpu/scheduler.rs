@@ -0,0 +1,156 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, info};
+
+use super::{
+    cpu::{CPUIterationDecision, CPU},
+    error::CpuError,
+};
+
+/// Timers count down at 60Hz, so that's what a frame lasts
+pub const FRAMES_PER_SECOND: u32 = 60;
+
+/// One instruction per frame at the slowest, a thousand at the fastest
+pub const MIN_FREQUENCY: u32 = FRAMES_PER_SECOND;
+pub const MAX_FREQUENCY: u32 = 1000 * FRAMES_PER_SECOND;
+
+/// Paces a CPU in 60Hz frames: a number of instructions, then exactly one timer tick
+///
+/// Frames are due at fixed points in time rather than a fixed delay after the previous one, so
+/// the clock doesn't drift when a frame takes longer than usual to run or draw.
+#[derive(Debug)]
+pub struct Scheduler {
+    /// Instructions per second
+    frequency: u32,
+
+    /// Sixtieths of an instruction left over by the frames so far, so frequencies that aren't a
+    /// multiple of 60Hz are kept exactly: at 500Hz, frames run 8 or 9 instructions
+    left_over: u32,
+
+    /// Frames run so far, also the number of the next one
+    frame: u64,
+
+    next_frame: Instant,
+}
+
+impl Scheduler {
+    /// Spreads `frequency` instructions per second across frames
+    pub fn from_frequency(frequency: u32) -> Self {
+        Scheduler {
+            frequency: frequency.clamp(MIN_FREQUENCY, MAX_FREQUENCY),
+            left_over: 0,
+            frame: 0,
+            next_frame: Instant::now(),
+        }
+    }
+
+    pub fn frame_duration() -> Duration {
+        Duration::from_secs(1) / FRAMES_PER_SECOND
+    }
+
+    /// Returns how many instructions the frame about to run gets, keeping what's left over for
+    /// the next ones
+    pub fn take_frame_instructions(&mut self) -> u32 {
+        let sixtieths = self.left_over + self.frequency;
+        self.left_over = sixtieths % FRAMES_PER_SECOND;
+        sixtieths / FRAMES_PER_SECOND
+    }
+
+    /// Instructions per second
+    pub fn frequency(&self) -> u32 {
+        self.frequency
+    }
+
+    pub fn set_frequency(&mut self, frequency: u32) {
+        self.frequency = frequency.clamp(MIN_FREQUENCY, MAX_FREQUENCY);
+        info!("Running at {}Hz", self.frequency);
+    }
+
+    /// One more instruction per frame
+    pub fn speed_up(&mut self) {
+        self.set_frequency(self.frequency + FRAMES_PER_SECOND);
+    }
+
+    /// One less instruction per frame
+    pub fn slow_down(&mut self) {
+        self.set_frequency(self.frequency.saturating_sub(FRAMES_PER_SECOND));
+    }
+
+    /// Number of the frame about to run
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// When the next frame should start
+    pub fn next_frame(&self) -> Instant {
+        self.next_frame
+    }
+
+    /// Returns `true` once it's time to run the next frame
+    pub fn is_frame_due(&self) -> bool {
+        Instant::now() >= self.next_frame
+    }
+
+    /// Runs a whole frame, stopping early if the program halts or fails
+    ///
+    /// NOTE: The timers are only ticked if all the instructions of the frame ran
+    pub fn run_frame(&mut self, cpu: &mut CPU) -> Result<CPUIterationDecision, CpuError> {
+        for _ in 0..self.take_frame_instructions() {
+            if let CPUIterationDecision::Halt = cpu.fetch_decode_execute()? {
+                return Ok(CPUIterationDecision::Halt);
+            }
+        }
+        self.end_frame(cpu);
+        Ok(CPUIterationDecision::Continue)
+    }
+
+    /// Ticks the timers once and moves on to the next frame, for callers that run the
+    /// instructions themselves
+    pub fn end_frame(&mut self, cpu: &mut CPU) {
+        cpu.tick(self.frame);
+        self.skip_frame();
+    }
+
+    /// Moves on to the next frame without touching the CPU
+    pub fn skip_frame(&mut self) {
+        self.frame += 1;
+        self.next_frame += Self::frame_duration();
+
+        // After a long stall, e.g. the window being dragged, start over instead of running every
+        // missed frame at once
+        let now = Instant::now();
+        if now > self.next_frame + Self::frame_duration() {
+            debug!("Frame {} is late, skipping ahead", self.frame);
+            self.next_frame = now;
+        }
+    }
+
+    /// Sleeps until the next frame is due
+    pub fn wait_for_next_frame(&self) {
+        thread::sleep(self.next_frame.saturating_duration_since(Instant::now()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Scheduler, FRAMES_PER_SECOND};
+
+    #[test]
+    fn every_second_runs_exactly_the_frequency() {
+        for frequency in [60, 500, 540, 1001] {
+            let mut scheduler = Scheduler::from_frequency(frequency);
+            let per_frame: Vec<u32> = (0..FRAMES_PER_SECOND)
+                .map(|_| scheduler.take_frame_instructions())
+                .collect();
+            assert_eq!(per_frame.iter().sum::<u32>(), frequency);
+
+            let fewest = frequency / FRAMES_PER_SECOND;
+            assert!(per_frame
+                .iter()
+                .all(|instructions| (fewest..=fewest + 1).contains(instructions)));
+        }
+    }
+}
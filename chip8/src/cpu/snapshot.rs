@@ -0,0 +1,400 @@
+use std::{
+    fs::OpenOptions,
+    io::{Read, Write},
+    path::Path,
+};
+
+use log::{error, info};
+
+use super::cpu::{EXTENDED_MEMORY_SIZE, MEMORY_SIZE};
+use crate::gfx::screen::Screen;
+
+/// Identifies chippy save state files
+const MAGIC: &[u8; 6] = b"CHIPPY";
+
+/// Bumped every time the file layout changes, older files are rejected
+pub const SNAPSHOT_VERSION: u16 = 3;
+
+/// The full machine state, as captured by `CPU::snapshot` and applied by `CPU::restore`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    pub memory: Vec<u8>,
+    pub registers: [u8; 16],
+    pub stack: [u16; 16],
+    pub memory_location: u16,
+    pub program_counter: u16,
+    pub stack_pointer: u8,
+    /// One bit per pressed key
+    pub keypad: u16,
+    pub waiting_for_key_press: bool,
+    pub awaited_key: Option<u8>,
+    pub waiting_for_vblank: bool,
+    pub high_resolution: bool,
+    pub rpl_flags: [u8; 16],
+    pub audio_buffer: Option<[u8; 16]>,
+    pub pitch: u8,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    pub screen_width: usize,
+    pub screen_height: usize,
+    pub screen_planes: u8,
+    pub screen_buffer: Vec<u8>,
+    /// As given by `RandomSource::state`
+    pub rng_state: Vec<u8>,
+}
+
+/// Reads the fields back in the order they were written, failing on truncated data
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8], String> {
+        if self.data.len() < length {
+            return Err("Save state is truncated".to_string());
+        }
+        let (bytes, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, String> {
+        Ok(self.u8()? != 0)
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        let mut array = [0x0; N];
+        array.copy_from_slice(self.bytes(N)?);
+        Ok(array)
+    }
+}
+
+impl Snapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.memory.len() + self.screen_buffer.len() + 128);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&SNAPSHOT_VERSION.to_be_bytes());
+
+        bytes.extend_from_slice(&(self.memory.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.memory);
+        bytes.extend_from_slice(&self.registers);
+        for addr in self.stack {
+            bytes.extend_from_slice(&addr.to_be_bytes());
+        }
+        bytes.extend_from_slice(&self.memory_location.to_be_bytes());
+        bytes.extend_from_slice(&self.program_counter.to_be_bytes());
+        bytes.push(self.stack_pointer);
+
+        bytes.extend_from_slice(&self.keypad.to_be_bytes());
+        bytes.push(self.waiting_for_key_press as u8);
+        // Keys only go up to 0xF, so 0xFF can stand for "no key"
+        bytes.push(self.awaited_key.unwrap_or(0xFF));
+        bytes.push(self.waiting_for_vblank as u8);
+
+        bytes.push(self.high_resolution as u8);
+        bytes.extend_from_slice(&self.rpl_flags);
+        bytes.push(self.audio_buffer.is_some() as u8);
+        bytes.extend_from_slice(&self.audio_buffer.unwrap_or_default());
+        bytes.push(self.pitch);
+
+        bytes.push(self.delay_timer);
+        bytes.push(self.sound_timer);
+
+        bytes.extend_from_slice(&(self.screen_width as u16).to_be_bytes());
+        bytes.extend_from_slice(&(self.screen_height as u16).to_be_bytes());
+        bytes.push(self.screen_planes);
+        bytes.extend_from_slice(&self.screen_buffer);
+
+        bytes.extend_from_slice(&(self.rng_state.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&self.rng_state);
+
+        bytes
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, String> {
+        let mut reader = Reader { data };
+
+        if reader.bytes(MAGIC.len())? != MAGIC {
+            return Err("Not a save state file".to_string());
+        }
+        let version = reader.u16()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(format!(
+                "Unsupported save state version {}, expected {}",
+                version, SNAPSHOT_VERSION
+            ));
+        }
+
+        let memory_length = reader.u32()? as usize;
+        let memory = reader.bytes(memory_length)?.to_vec();
+        let registers = reader.array()?;
+        let mut stack = [0x0; 16];
+        for addr in stack.iter_mut() {
+            *addr = reader.u16()?;
+        }
+        let memory_location = reader.u16()?;
+        let program_counter = reader.u16()?;
+        let stack_pointer = reader.u8()?;
+
+        let keypad = reader.u16()?;
+        let waiting_for_key_press = reader.bool()?;
+        let awaited_key = Some(reader.u8()?).filter(|key| *key <= 0xF);
+        let waiting_for_vblank = reader.bool()?;
+
+        let high_resolution = reader.bool()?;
+        let rpl_flags = reader.array()?;
+        let has_audio_buffer = reader.bool()?;
+        let audio_buffer = Some(reader.array()?).filter(|_| has_audio_buffer);
+        let pitch = reader.u8()?;
+
+        let delay_timer = reader.u8()?;
+        let sound_timer = reader.u8()?;
+
+        let screen_width = reader.u16()? as usize;
+        let screen_height = reader.u16()? as usize;
+        let screen_planes = reader.u8()?;
+        let screen_buffer = reader.bytes(screen_width * screen_height)?.to_vec();
+
+        let rng_state_length = reader.u16()? as usize;
+        let rng_state = reader.bytes(rng_state_length)?.to_vec();
+
+        let snapshot = Snapshot {
+            memory,
+            registers,
+            stack,
+            memory_location,
+            program_counter,
+            stack_pointer,
+            keypad,
+            waiting_for_key_press,
+            awaited_key,
+            waiting_for_vblank,
+            high_resolution,
+            rpl_flags,
+            audio_buffer,
+            pitch,
+            delay_timer,
+            sound_timer,
+            screen_width,
+            screen_height,
+            screen_planes,
+            screen_buffer,
+            rng_state,
+        };
+        snapshot.validate()?;
+        Ok(snapshot)
+    }
+
+    /// Rejects states the CPU can't be put in, e.g. read from a corrupt or hand-edited file
+    pub fn validate(&self) -> Result<(), String> {
+        if self.memory.len() != MEMORY_SIZE && self.memory.len() != EXTENDED_MEMORY_SIZE {
+            return Err(format!(
+                "Save state has {} bytes of memory, expected {} or {}",
+                self.memory.len(),
+                MEMORY_SIZE,
+                EXTENDED_MEMORY_SIZE
+            ));
+        }
+        if self.stack_pointer as usize > self.stack.len() {
+            return Err(format!(
+                "Save state stack pointer {} is past the end of the stack",
+                self.stack_pointer
+            ));
+        }
+
+        let high_resolution = match (self.screen_width, self.screen_height) {
+            (Screen::WIDTH, Screen::HEIGHT) => false,
+            (Screen::HIRES_WIDTH, Screen::HIRES_HEIGHT) => true,
+            (width, height) => {
+                return Err(format!(
+                    "Save state screen is {}x{}, expected {}x{} or {}x{}",
+                    width,
+                    height,
+                    Screen::WIDTH,
+                    Screen::HEIGHT,
+                    Screen::HIRES_WIDTH,
+                    Screen::HIRES_HEIGHT
+                ))
+            }
+        };
+        if high_resolution != self.high_resolution {
+            return Err("Save state resolution does not match its screen size".to_string());
+        }
+        if self.screen_buffer.len() != self.screen_width * self.screen_height {
+            return Err("Save state screen buffer does not match its size".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn save_to_file(&self, path: &Path) -> Result<(), String> {
+        let mut file = match OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)
+        {
+            Ok(file) => file,
+            Err(err) => {
+                error!("Could not open file: {}", err.kind().to_string());
+                return Err("Could not open file".to_string());
+            }
+        };
+
+        if let Err(err) = file.write_all(&self.to_bytes()) {
+            error!("Could not write save state: {}", err.kind().to_string());
+            return Err("Could not write save state".to_string());
+        }
+        info!("Saved state to {}", path.display());
+        Ok(())
+    }
+
+    pub fn load_from_file(path: &Path) -> Result<Self, String> {
+        let mut file = match OpenOptions::new().read(true).open(path) {
+            Ok(file) => file,
+            Err(err) => {
+                error!("Could not open file: {}", err.kind().to_string());
+                return Err("Could not open file".to_string());
+            }
+        };
+
+        let mut data = vec![];
+        if let Err(err) = file.read_to_end(&mut data) {
+            error!("Could not read save state: {}", err.kind().to_string());
+            return Err("Could not read save state".to_string());
+        }
+        info!("Loaded state from {}", path.display());
+        Self::from_bytes(&data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::Snapshot;
+    use crate::cpu::{cpu::CPU, quirks::Quirks, rng::RndAlgorithm};
+
+    /// Sets VA, points I at the font, then calls a subroutine drawing a digit
+    const PROGRAM: [u8; 12] = [
+        0x6A, 0x05, // LD VA, 05
+        0xA0, 0x00, // LD I, 000
+        0x22, 0x08, // CALL 208
+        0x12, 0x06, // JP 206
+        0xD0, 0x15, // DRW V0, V1, 5
+        0x00, 0xEE, // RET
+    ];
+
+    fn cpu(quirks: Quirks) -> CPU {
+        let (sound_tx, _) = mpsc::channel();
+        let mut cpu = CPU::new(sound_tx, quirks);
+        cpu.write_memory(0x200, &PROGRAM).unwrap();
+        cpu.set_program_counter(0x200);
+        cpu
+    }
+
+    /// A CPU halfway through the program, inside the subroutine with a digit on screen
+    fn running_cpu() -> CPU {
+        let mut cpu = cpu(Quirks::modern());
+        cpu.press_key(0x3);
+        for _ in 0..4 {
+            cpu.fetch_decode_execute().unwrap();
+        }
+        cpu
+    }
+
+    #[test]
+    fn round_trip_through_bytes_restores_the_same_state() {
+        let mut original = running_cpu();
+        let snapshot = original.snapshot();
+        let loaded = Snapshot::from_bytes(&snapshot.to_bytes()).unwrap();
+        assert_eq!(loaded, snapshot);
+
+        let mut restored = cpu(Quirks::modern());
+        restored.restore(&loaded).unwrap();
+        assert_eq!(restored.snapshot(), snapshot);
+
+        // Both go on the same way
+        original.fetch_decode_execute().unwrap();
+        restored.fetch_decode_execute().unwrap();
+        assert_eq!(restored.snapshot(), original.snapshot());
+        assert_eq!(restored.program_counter(), 0x206);
+    }
+
+    #[test]
+    fn invalid_states_are_rejected() {
+        let valid = running_cpu().snapshot();
+        let corruptions: [fn(&mut Snapshot); 6] = [
+            |snapshot| snapshot.stack_pointer = 17,
+            |snapshot| snapshot.screen_width = 0,
+            |snapshot| snapshot.screen_height = 0,
+            |snapshot| snapshot.screen_height = 64,
+            |snapshot| snapshot.high_resolution = true,
+            |snapshot| snapshot.memory.truncate(1000),
+        ];
+        for corrupt in corruptions {
+            let mut snapshot = valid.clone();
+            corrupt(&mut snapshot);
+            assert!(snapshot.validate().is_err(), "{:?}", snapshot);
+            assert!(Snapshot::from_bytes(&snapshot.to_bytes()).is_err());
+
+            let mut cpu = cpu(Quirks::modern());
+            let before = cpu.snapshot();
+            assert!(cpu.restore(&snapshot).is_err());
+            assert_eq!(cpu.snapshot(), before);
+        }
+    }
+
+    #[test]
+    fn rnd_carries_on_from_the_saved_state() {
+        let rnd = [0xC0, 0xFF, 0xC1, 0xFF, 0xC2, 0xFF]; // RND V0 to V2, FF
+        for algorithm in [RndAlgorithm::SplitMix, RndAlgorithm::CosmacVip] {
+            let (sound_tx, _) = mpsc::channel();
+            let mut original = CPU::new(sound_tx.clone(), Quirks::modern());
+            original.set_random_source(algorithm.source(1));
+            original.write_memory(0x200, &rnd).unwrap();
+            original.set_program_counter(0x200);
+            original.fetch_decode_execute().unwrap();
+            let snapshot = Snapshot::from_bytes(&original.snapshot().to_bytes()).unwrap();
+
+            let mut restored = CPU::new(sound_tx, Quirks::modern());
+            restored.set_random_source(algorithm.source(2));
+            restored.restore(&snapshot).unwrap();
+            for _ in 0..2 {
+                original.fetch_decode_execute().unwrap();
+                restored.fetch_decode_execute().unwrap();
+            }
+            assert_eq!(restored.registers(), original.registers());
+        }
+    }
+
+    #[test]
+    fn rnd_state_must_match_the_algorithm() {
+        let mut cpu = cpu(Quirks::modern());
+        cpu.set_random_source(RndAlgorithm::SplitMix.source(0));
+        let snapshot = cpu.snapshot();
+        cpu.set_random_source(RndAlgorithm::CosmacVip.source(0));
+        assert!(cpu.restore(&snapshot).is_err());
+    }
+
+    #[test]
+    fn memory_must_match_the_quirks() {
+        let snapshot = cpu(Quirks::xo_chip()).snapshot();
+        assert!(snapshot.validate().is_ok());
+        assert!(cpu(Quirks::modern()).restore(&snapshot).is_err());
+        assert!(cpu(Quirks::xo_chip()).restore(&snapshot).is_ok());
+    }
+}
@@ -2,7 +2,7 @@ use std::sync::mpsc::Sender;
 
 use log::error;
 
-use crate::sound::message::SoundMessage;
+use crate::sound::{message::SoundMessage, pattern::AudioPattern};
 
 use super::timer::Timer;
 
@@ -28,6 +28,30 @@ impl SoundTimer {
                 error!("Error while stopping sound! {:?}", err.to_string());
             });
     }
+
+    /// Sets the timer to `value` without assuming it was zero before, starting or stopping the sound
+    pub fn restore(&mut self, value: u8) {
+        if value > 0 {
+            self.set_value(value);
+            return;
+        }
+
+        self.value = 0;
+        self.active = false;
+        self.sound_tx
+            .send(SoundMessage::Pause)
+            .unwrap_or_else(|err| {
+                error!("Error pausing sound: {:?}", err);
+            });
+    }
+
+    pub fn set_pattern(&self, pattern: AudioPattern) {
+        self.sound_tx
+            .send(SoundMessage::Pattern(pattern))
+            .unwrap_or_else(|err| {
+                error!("Error setting audio pattern: {:?}", err);
+            });
+    }
 }
 
 impl Timer for SoundTimer {
@@ -0,0 +1,313 @@
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::TcpStream,
+};
+
+use log::{debug, error};
+
+/// Sent by GDB outside of any packet to pause the target
+const INTERRUPT: u8 = 0x03;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Incoming {
+    Packet(String),
+    Interrupt,
+}
+
+/// Reads and writes `$data#checksum` packets over a non-blocking TCP stream
+pub struct Connection {
+    stream: TcpStream,
+    incoming: Vec<u8>,
+
+    /// Set once GDB asks for QStartNoAckMode
+    no_ack: bool,
+    closed: bool,
+}
+
+impl Connection {
+    pub fn new(stream: TcpStream) -> Result<Self, String> {
+        stream
+            .set_nonblocking(true)
+            .and_then(|_| stream.set_nodelay(true))
+            .map_err(|err| {
+                error!("Could not configure the GDB connection: {}", err);
+                "Could not configure the GDB connection".to_string()
+            })?;
+        Ok(Connection {
+            stream,
+            incoming: vec![],
+            no_ack: false,
+            closed: false,
+        })
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    pub fn close(&mut self) {
+        self.closed = true;
+    }
+
+    pub fn disable_acks(&mut self) {
+        self.no_ack = true;
+    }
+
+    /// Returns everything GDB sent since the last call, without blocking
+    pub fn receive(&mut self) -> Vec<Incoming> {
+        let mut buffer = [0x0; 1024];
+        while !self.closed {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => {
+                    debug!("GDB closed the connection");
+                    self.closed = true;
+                }
+                Ok(length) => self.incoming.extend_from_slice(&buffer[..length]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => {
+                    error!("Could not read from GDB: {}", err);
+                    self.closed = true;
+                }
+            }
+        }
+
+        let mut received = vec![];
+        while let Some(incoming) = self.parse_next() {
+            received.push(incoming);
+        }
+        received
+    }
+
+    /// Takes the first complete packet out of the incoming bytes
+    fn parse_next(&mut self) -> Option<Incoming> {
+        loop {
+            match *self.incoming.first()? {
+                INTERRUPT => {
+                    self.incoming.remove(0);
+                    return Some(Incoming::Interrupt);
+                }
+                b'$' => break,
+                // Acks, or garbage between packets
+                _ => {
+                    self.incoming.remove(0);
+                }
+            }
+        }
+
+        let end = self.incoming.iter().position(|byte| *byte == b'#')?;
+        if self.incoming.len() < end + 3 {
+            return None;
+        }
+        let packet: Vec<u8> = self.incoming.drain(..end + 3).collect();
+        let data = &packet[1..end];
+        let checksum = std::str::from_utf8(&packet[end + 1..])
+            .ok()
+            .and_then(|checksum| u8::from_str_radix(checksum, 16).ok());
+
+        if !self.no_ack {
+            let valid = checksum == Some(checksum_of(data));
+            self.write(if valid { b"+" } else { b"-" });
+            if !valid {
+                debug!("Invalid checksum, asking GDB to resend");
+                return self.parse_next();
+            }
+        }
+        let data = String::from_utf8_lossy(&unescape(data)).into_owned();
+        debug!("GDB <- {}", data);
+        Some(Incoming::Packet(data))
+    }
+
+    pub fn send(&mut self, data: &str) {
+        debug!("GDB -> {}", data);
+        let escaped = escape(data.as_bytes());
+        let mut packet = Vec::with_capacity(escaped.len() + 4);
+        packet.push(b'$');
+        packet.extend_from_slice(&escaped);
+        packet.extend_from_slice(format!("#{:02x}", checksum_of(&escaped)).as_bytes());
+        self.write(&packet);
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        let mut written = 0;
+        while written < bytes.len() && !self.closed {
+            match self.stream.write(&bytes[written..]) {
+                Ok(length) => written += length,
+                Err(err)
+                    if err.kind() == ErrorKind::WouldBlock
+                        || err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => {
+                    error!("Could not write to GDB: {}", err);
+                    self.closed = true;
+                }
+            }
+        }
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+/// `#`, `$`, `}` and `*` are sent as `}` followed by the byte XOR 0x20
+fn escape(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for byte in data {
+        if matches!(byte, b'#' | b'$' | b'}' | b'*') {
+            escaped.push(b'}');
+            escaped.push(byte ^ 0x20);
+        } else {
+            escaped.push(*byte);
+        }
+    }
+    escaped
+}
+
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(data.len());
+    let mut bytes = data.iter();
+    while let Some(byte) = bytes.next() {
+        match (byte, bytes.clone().next()) {
+            (b'}', Some(next)) => {
+                unescaped.push(next ^ 0x20);
+                bytes.next();
+            }
+            _ => unescaped.push(*byte),
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::{checksum_of, escape, unescape, Connection, Incoming, INTERRUPT};
+
+    /// A connection and the GDB end of it, over loopback
+    fn connect() -> (Connection, TcpStream) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let gdb = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        gdb.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        (Connection::new(stream).unwrap(), gdb)
+    }
+
+    /// Waits until `count` items have been received, or a few seconds have passed
+    fn receive(connection: &mut Connection, count: usize) -> Vec<Incoming> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut received = vec![];
+        while received.len() < count && Instant::now() < deadline {
+            received.extend(connection.receive());
+            thread::sleep(Duration::from_millis(1));
+        }
+        received
+    }
+
+    fn read(gdb: &mut TcpStream, length: usize) -> Vec<u8> {
+        let mut bytes = vec![0x0; length];
+        gdb.read_exact(&mut bytes).unwrap();
+        bytes
+    }
+
+    fn packet(data: &str) -> Vec<u8> {
+        format!("${}#{:02x}", data, checksum_of(data.as_bytes())).into_bytes()
+    }
+
+    #[test]
+    fn checksum_is_the_sum_of_the_bytes_modulo_256() {
+        assert_eq!(checksum_of(b""), 0x00);
+        assert_eq!(checksum_of(b"g"), 0x67);
+        assert_eq!(checksum_of(b"OK"), 0x9a);
+        assert_eq!(checksum_of(&[0xFF, 0x02]), 0x01);
+    }
+
+    #[test]
+    fn special_characters_are_escaped() {
+        assert_eq!(escape(b"a#b$c}d*e"), b"a}\x03b}\x04c}]d}\x0ae");
+        assert_eq!(unescape(b"a}\x03b}\x04c}]d}\x0ae"), b"a#b$c}d*e");
+        assert_eq!(unescape(&escape(b"}}##")), b"}}##");
+    }
+
+    #[test]
+    fn valid_packets_are_acknowledged() {
+        let (mut connection, mut gdb) = connect();
+        gdb.write_all(&packet("m200,4")).unwrap();
+        assert_eq!(
+            receive(&mut connection, 1),
+            vec![Incoming::Packet("m200,4".to_string())]
+        );
+        assert_eq!(read(&mut gdb, 1), b"+");
+    }
+
+    #[test]
+    fn packets_with_a_bad_checksum_are_nacked() {
+        let (mut connection, mut gdb) = connect();
+        gdb.write_all(b"$g#00").unwrap();
+        gdb.write_all(&packet("g")).unwrap();
+        assert_eq!(
+            receive(&mut connection, 1),
+            vec![Incoming::Packet("g".to_string())]
+        );
+        assert_eq!(read(&mut gdb, 2), b"-+");
+    }
+
+    #[test]
+    fn packets_split_across_reads_are_put_together() {
+        let (mut connection, mut gdb) = connect();
+        gdb.write_all(b"+$qC").unwrap();
+        thread::sleep(Duration::from_millis(10));
+        assert!(connection.receive().is_empty());
+        gdb.write_all(b"#b4").unwrap();
+        assert_eq!(
+            receive(&mut connection, 1),
+            vec![Incoming::Packet("qC".to_string())]
+        );
+    }
+
+    #[test]
+    fn interrupts_come_outside_of_packets() {
+        let (mut connection, mut gdb) = connect();
+        gdb.write_all(&[INTERRUPT]).unwrap();
+        gdb.write_all(&packet("?")).unwrap();
+        assert_eq!(
+            receive(&mut connection, 2),
+            vec![Incoming::Interrupt, Incoming::Packet("?".to_string())]
+        );
+    }
+
+    #[test]
+    fn escaped_packets_are_unescaped() {
+        let (mut connection, mut gdb) = connect();
+        gdb.write_all(b"$X}\x03#d8").unwrap();
+        assert_eq!(
+            receive(&mut connection, 1),
+            vec![Incoming::Packet("X#".to_string())]
+        );
+    }
+
+    #[test]
+    fn sent_packets_are_escaped_and_checksummed() {
+        let (mut connection, mut gdb) = connect();
+        connection.send("a#b");
+        assert_eq!(read(&mut gdb, 8), b"$a}\x03b#43");
+    }
+
+    #[test]
+    fn no_ack_mode_stops_acknowledging() {
+        let (mut connection, mut gdb) = connect();
+        connection.disable_acks();
+        gdb.write_all(b"$g#00").unwrap();
+        assert_eq!(
+            receive(&mut connection, 1),
+            vec![Incoming::Packet("g".to_string())]
+        );
+        connection.send("OK");
+        assert_eq!(read(&mut gdb, 6), b"$OK#9a");
+    }
+}
@@ -0,0 +1,602 @@
+use std::{fmt::Write, net::TcpListener};
+
+use log::{debug, error, info};
+
+use crate::cpu::{
+    cpu::CPUIterationDecision,
+    debugger::{Access, Debugger, Stop, WatchKind},
+    error::CpuError,
+};
+
+use super::packet::{Connection, Incoming};
+
+/// V0 to VF, I, PC and SP, in the order of the target description
+const REGISTER_COUNT: usize = 19;
+const I_REGISTER: usize = 16;
+const PC_REGISTER: usize = 17;
+const SP_REGISTER: usize = 18;
+
+/// Largest packet we accept, in bytes
+const PACKET_SIZE: usize = 0x1000;
+
+/// Registers are sent big-endian, like everything else on CHIP-8
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.chippy.chip8">
+    <reg name="v0" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="v1" bitsize="8" type="uint8"/>
+    <reg name="v2" bitsize="8" type="uint8"/>
+    <reg name="v3" bitsize="8" type="uint8"/>
+    <reg name="v4" bitsize="8" type="uint8"/>
+    <reg name="v5" bitsize="8" type="uint8"/>
+    <reg name="v6" bitsize="8" type="uint8"/>
+    <reg name="v7" bitsize="8" type="uint8"/>
+    <reg name="v8" bitsize="8" type="uint8"/>
+    <reg name="v9" bitsize="8" type="uint8"/>
+    <reg name="va" bitsize="8" type="uint8"/>
+    <reg name="vb" bitsize="8" type="uint8"/>
+    <reg name="vc" bitsize="8" type="uint8"/>
+    <reg name="vd" bitsize="8" type="uint8"/>
+    <reg name="ve" bitsize="8" type="uint8"/>
+    <reg name="vf" bitsize="8" type="uint8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+  </feature>
+</target>
+"#;
+
+/// Signals reported to GDB in stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+/// Serves the GDB remote serial protocol for a single client
+///
+/// The stub never blocks once connected: call `run` from the frontend loop, it handles whatever
+/// GDB sent and executes instructions while GDB has the target running.
+pub struct GdbStub {
+    connection: Connection,
+}
+
+impl GdbStub {
+    /// Waits for GDB to connect to `port` on the loopback interface
+    pub fn listen(port: u16) -> Result<Self, String> {
+        let listener = TcpListener::bind(("127.0.0.1", port)).map_err(|err| {
+            error!("Could not listen on port {}: {}", port, err);
+            format!("Could not listen on port {}", port)
+        })?;
+        info!("Waiting for GDB on 127.0.0.1:{}", port);
+        Self::accept(&listener)
+    }
+
+    /// Waits for GDB to connect to `listener`
+    pub fn accept(listener: &TcpListener) -> Result<Self, String> {
+        let (stream, address) = listener.accept().map_err(|err| {
+            error!("Could not accept the GDB connection: {}", err);
+            "Could not accept the GDB connection".to_string()
+        })?;
+        info!("GDB connected from {}", address);
+        Ok(GdbStub {
+            connection: Connection::new(stream)?,
+        })
+    }
+
+    /// Returns `false` once GDB detached or the connection dropped
+    pub fn is_connected(&self) -> bool {
+        !self.connection.is_closed()
+    }
+
+    /// Handles pending packets, then executes up to `max_instructions` if GDB resumed the target
+    ///
+    /// The CPU stays paused until GDB sends `c` or `s`. Errors are reported to GDB as signals
+    /// before being returned, and the program exiting as an exit status.
+    pub fn run(
+        &mut self,
+        debugger: &mut Debugger,
+        max_instructions: usize,
+    ) -> Result<CPUIterationDecision, CpuError> {
+        let mut decision = CPUIterationDecision::Continue;
+        for incoming in self.connection.receive() {
+            match incoming {
+                Incoming::Interrupt => {
+                    if debugger.is_running() {
+                        debugger.pause();
+                        self.send_signal(SIGINT);
+                    }
+                }
+                Incoming::Packet(packet) => {
+                    if let CPUIterationDecision::Halt = self.handle_packet(&packet, debugger)? {
+                        decision = CPUIterationDecision::Halt;
+                    }
+                }
+            }
+        }
+
+        if !self.is_connected() {
+            // Let the program carry on without us
+            debugger.pause();
+            return Ok(decision);
+        }
+        if debugger.is_running() {
+            match debugger.run(max_instructions) {
+                Ok(Some(stop)) => return Ok(self.send_stop(&stop)),
+                Ok(None) => {}
+                Err(err) => return Err(self.send_error(err)),
+            }
+        }
+        Ok(decision)
+    }
+
+    /// Returns `CPUIterationDecision::Halt` if a step made the program exit
+    fn handle_packet(
+        &mut self,
+        packet: &str,
+        debugger: &mut Debugger,
+    ) -> Result<CPUIterationDecision, CpuError> {
+        let command = packet.get(..1).unwrap_or_default();
+        let arguments = packet.get(1..).unwrap_or_default();
+        let reply = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => read_registers(debugger),
+            "G" => ok_or_error(write_registers(debugger, arguments)),
+            "p" => parse_hex(arguments)
+                .and_then(|register| read_register(debugger, register))
+                .unwrap_or_else(|| "E01".to_string()),
+            "P" => ok_or_error(write_register(debugger, arguments)),
+            "m" => read_memory(debugger, arguments).unwrap_or_else(|| "E01".to_string()),
+            "M" => ok_or_error(write_memory(debugger, arguments)),
+            "Z" => ok_or_error(set_breakpoint(debugger, arguments, true)),
+            "z" => ok_or_error(set_breakpoint(debugger, arguments, false)),
+            "c" => {
+                if let Some(address) = parse_hex(arguments) {
+                    debugger.cpu_mut().set_program_counter(address as u16);
+                }
+                debugger.resume();
+                return Ok(CPUIterationDecision::Continue);
+            }
+            "s" => {
+                if let Some(address) = parse_hex(arguments) {
+                    debugger.cpu_mut().set_program_counter(address as u16);
+                }
+                return match debugger.step() {
+                    Ok(stop) => Ok(self.send_stop(&stop)),
+                    Err(err) => Err(self.send_error(err)),
+                };
+            }
+            "D" => {
+                info!("GDB detached");
+                self.connection.send("OK");
+                self.connection.close();
+                return Ok(CPUIterationDecision::Continue);
+            }
+            "k" => {
+                info!("GDB killed the session");
+                self.connection.close();
+                return Ok(CPUIterationDecision::Continue);
+            }
+            "H" => "OK".to_string(),
+            "q" | "Q" => match self.handle_query(packet) {
+                Some(reply) => reply,
+                None => return Ok(CPUIterationDecision::Continue),
+            },
+            _ => {
+                debug!("Unsupported GDB packet: {}", packet);
+                String::new()
+            }
+        };
+        self.connection.send(&reply);
+        Ok(CPUIterationDecision::Continue)
+    }
+
+    /// Returns `None` if the reply has already been sent
+    fn handle_query(&mut self, packet: &str) -> Option<String> {
+        let reply = if packet.starts_with("qSupported") {
+            format!(
+                "PacketSize={:x};qXfer:features:read+;swbreak+;QStartNoAckMode+",
+                PACKET_SIZE
+            )
+        } else if packet == "QStartNoAckMode" {
+            // The OK itself still gets acknowledged
+            self.connection.send("OK");
+            self.connection.disable_acks();
+            return None;
+        } else if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            read_target_description(range).unwrap_or_else(|| "E01".to_string())
+        } else if packet == "qAttached" {
+            "1".to_string()
+        } else if packet == "qC" {
+            "QC1".to_string()
+        } else if packet == "qfThreadInfo" {
+            "m1".to_string()
+        } else if packet == "qsThreadInfo" {
+            "l".to_string()
+        } else {
+            String::new()
+        };
+        Some(reply)
+    }
+
+    fn send_signal(&mut self, signal: u8) {
+        self.connection.send(&format!("S{:02x}", signal));
+    }
+
+    /// Returns `CPUIterationDecision::Halt` if the program exited
+    fn send_stop(&mut self, stop: &Stop) -> CPUIterationDecision {
+        let reply = match stop {
+            Stop::Halted => {
+                self.connection.send("W00");
+                return CPUIterationDecision::Halt;
+            }
+            Stop::Breakpoint(_) => format!("T{:02x}swbreak:;", SIGTRAP),
+            Stop::Watchpoint { address, access } => {
+                let kind = match access {
+                    Access::Read => "rwatch",
+                    Access::Write => "watch",
+                };
+                format!("T{:02x}{}:{:x};", SIGTRAP, kind, address)
+            }
+            _ => format!("S{:02x}", SIGTRAP),
+        };
+        self.connection.send(&reply);
+        CPUIterationDecision::Continue
+    }
+
+    /// Tells GDB the program crashed, handing the error back
+    fn send_error(&mut self, error: CpuError) -> CpuError {
+        let signal = match error {
+            CpuError::UnknownOpcode { .. } | CpuError::MachineCodeCall { .. } => SIGILL,
+            _ => SIGSEGV,
+        };
+        self.send_signal(signal);
+        error
+    }
+}
+
+fn ok_or_error(done: Option<()>) -> String {
+    match done {
+        Some(()) => "OK".to_string(),
+        None => "E01".to_string(),
+    }
+}
+
+fn parse_hex(value: &str) -> Option<usize> {
+    usize::from_str_radix(value, 16).ok()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+/// Size in bytes of a register, as listed in the target description
+fn register_size(register: usize) -> usize {
+    match register {
+        I_REGISTER | PC_REGISTER => 2,
+        _ => 1,
+    }
+}
+
+fn register_bytes(debugger: &Debugger, register: usize) -> Option<Vec<u8>> {
+    let cpu = debugger.cpu();
+    let bytes = match register {
+        0..=15 => vec![cpu.get_register(register as u8)],
+        I_REGISTER => cpu.memory_location().to_be_bytes().to_vec(),
+        PC_REGISTER => cpu.program_counter().to_be_bytes().to_vec(),
+        SP_REGISTER => vec![cpu.stack_pointer()],
+        _ => return None,
+    };
+    Some(bytes)
+}
+
+fn read_registers(debugger: &Debugger) -> String {
+    let bytes: Vec<u8> = (0..REGISTER_COUNT)
+        .filter_map(|register| register_bytes(debugger, register))
+        .flatten()
+        .collect();
+    encode_hex(&bytes)
+}
+
+fn read_register(debugger: &Debugger, register: usize) -> Option<String> {
+    register_bytes(debugger, register).map(|bytes| encode_hex(&bytes))
+}
+
+fn set_register(debugger: &mut Debugger, register: usize, bytes: &[u8]) -> Option<()> {
+    let cpu = debugger.cpu_mut();
+    match (register, bytes) {
+        (0..=15, [value]) => cpu.set_register(register as u8, *value),
+        (I_REGISTER, [high, low]) => cpu.set_memory_location(u16::from_be_bytes([*high, *low])),
+        (PC_REGISTER, [high, low]) => cpu.set_program_counter(u16::from_be_bytes([*high, *low])),
+        (SP_REGISTER, [value]) => return cpu.set_stack_pointer(*value).then_some(()),
+        _ => return None,
+    }
+    Some(())
+}
+
+/// G: all registers, in the same layout as g
+fn write_registers(debugger: &mut Debugger, hex: &str) -> Option<()> {
+    let bytes = decode_hex(hex)?;
+    let mut offset = 0;
+    for register in 0..REGISTER_COUNT {
+        let size = register_size(register);
+        set_register(debugger, register, bytes.get(offset..offset + size)?)?;
+        offset += size;
+    }
+    Some(())
+}
+
+/// P n=value
+fn write_register(debugger: &mut Debugger, arguments: &str) -> Option<()> {
+    let (register, value) = arguments.split_once('=')?;
+    set_register(debugger, parse_hex(register)?, &decode_hex(value)?)
+}
+
+/// m addr,length
+fn read_memory(debugger: &Debugger, arguments: &str) -> Option<String> {
+    let (address, length) = arguments.split_once(',')?;
+    let address = parse_hex(address)?;
+    let length = parse_hex(length)?.min(PACKET_SIZE / 2);
+    let bytes = debugger
+        .cpu()
+        .memory()
+        .get(address..address.checked_add(length)?)?;
+    Some(encode_hex(bytes))
+}
+
+/// M addr,length:bytes
+fn write_memory(debugger: &mut Debugger, arguments: &str) -> Option<()> {
+    let (location, hex) = arguments.split_once(':')?;
+    let (address, length) = location.split_once(',')?;
+    let bytes = decode_hex(hex)?;
+    if bytes.len() != parse_hex(length)? {
+        return None;
+    }
+    debugger
+        .cpu_mut()
+        .write_memory(parse_hex(address)?, &bytes)
+        .ok()
+}
+
+/// Z/z type,addr,kind: 0 and 1 are breakpoints, 2 to 4 are write, read and access watchpoints
+fn set_breakpoint(debugger: &mut Debugger, arguments: &str, insert: bool) -> Option<()> {
+    let mut fields = arguments.split(',');
+    let kind = fields.next()?;
+    let address = parse_hex(fields.next()?)? as u16;
+    let length = parse_hex(fields.next().unwrap_or("1")).unwrap_or(1) as u16;
+
+    let watch = match kind {
+        "0" | "1" => {
+            if insert {
+                debugger.add_breakpoint(address);
+            } else {
+                debugger.remove_breakpoint(address);
+            }
+            return Some(());
+        }
+        "2" => WatchKind::Write,
+        "3" => WatchKind::Read,
+        "4" => WatchKind::ReadWrite,
+        _ => return None,
+    };
+    let addresses = address..address.checked_add(length.max(1))?;
+    if insert {
+        debugger.add_watchpoint(addresses, watch);
+    } else {
+        debugger.remove_watchpoint(addresses, watch);
+    }
+    Some(())
+}
+
+/// Answers qXfer:features:read with the part of the target description in `offset,length`
+fn read_target_description(range: &str) -> Option<String> {
+    let (offset, length) = range.split_once(',')?;
+    let offset = parse_hex(offset)?.min(TARGET_XML.len());
+    let end = offset
+        .saturating_add(parse_hex(length)?)
+        .min(TARGET_XML.len());
+    let prefix = if end == TARGET_XML.len() { 'l' } else { 'm' };
+    Some(format!("{}{}", prefix, &TARGET_XML[offset..end]))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{ErrorKind, Read, Write},
+        net::{TcpListener, TcpStream},
+        sync::mpsc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::GdbStub;
+    use crate::cpu::{
+        cpu::{CPUIterationDecision, CPU},
+        debugger::Debugger,
+        quirks::Quirks,
+    };
+
+    const PROGRAM: [u8; 6] = [
+        0x60, 0x05, // LD V0, 05
+        0x70, 0x01, // ADD V0, 01
+        0x12, 0x04, // JP 204
+    ];
+
+    const EXITING_PROGRAM: [u8; 4] = [
+        0x60, 0x05, // LD V0, 05
+        0x00, 0xFD, // EXIT
+    ];
+
+    /// GDB's end of a session with a stub debugging a program, over loopback
+    struct Session {
+        gdb: TcpStream,
+        stub: GdbStub,
+        debugger: Debugger,
+        incoming: Vec<u8>,
+    }
+
+    impl Session {
+        fn start() -> Self {
+            Self::start_with(&PROGRAM)
+        }
+
+        fn start_with(program: &[u8]) -> Self {
+            let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+            let gdb = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            gdb.set_nonblocking(true).unwrap();
+            let stub = GdbStub::accept(&listener).unwrap();
+
+            let (sound_tx, _) = mpsc::channel();
+            let mut cpu = CPU::new(sound_tx, Quirks::modern());
+            cpu.write_memory(0x200, program).unwrap();
+            cpu.set_program_counter(0x200);
+            Session {
+                gdb,
+                stub,
+                debugger: Debugger::new(cpu),
+                incoming: vec![],
+            }
+        }
+
+        fn send(&mut self, bytes: &[u8]) {
+            self.gdb.write_all(bytes).unwrap();
+        }
+
+        fn send_packet(&mut self, data: &str) {
+            let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+            self.send(format!("${}#{:02x}", data, checksum).as_bytes());
+        }
+
+        /// Sends a packet and runs the stub until it replies, returning the reply data
+        fn request(&mut self, data: &str) -> String {
+            self.send_packet(data);
+            self.reply()
+        }
+
+        /// Runs the stub until the debugger is running, then a little longer
+        fn run_until_running(&mut self) {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while !self.debugger.is_running() {
+                assert!(Instant::now() < deadline, "The stub never resumed");
+                self.stub.run(&mut self.debugger, 100).unwrap();
+                thread::sleep(Duration::from_millis(1));
+            }
+            for _ in 0..10 {
+                self.stub.run(&mut self.debugger, 100).unwrap();
+            }
+        }
+
+        /// Runs the stub until a whole packet has been sent back, skipping acks
+        fn reply(&mut self) -> String {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while Instant::now() < deadline {
+                self.stub.run(&mut self.debugger, 100).unwrap();
+                let mut buffer = [0x0; 256];
+                match self.gdb.read(&mut buffer) {
+                    Ok(length) => self.incoming.extend_from_slice(&buffer[..length]),
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                    Err(err) => panic!("{}", err),
+                }
+
+                while self.incoming.first() == Some(&b'+') {
+                    self.incoming.remove(0);
+                }
+                if let Some(end) = self.incoming.iter().position(|byte| *byte == b'#') {
+                    if self.incoming.len() >= end + 3 {
+                        let packet: Vec<u8> = self.incoming.drain(..end + 3).collect();
+                        assert_eq!(packet[0], b'$');
+                        return String::from_utf8(packet[1..end].to_vec()).unwrap();
+                    }
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+            panic!("No reply from the stub");
+        }
+    }
+
+    #[test]
+    fn registers_can_be_read_and_written() {
+        let mut session = Session::start();
+        session.debugger.cpu_mut().set_register(0x1, 0xAB);
+        // V0 to VF, I, PC then SP
+        let registers = format!("00ab{}{}{}{}", "00".repeat(14), "0000", "0200", "00");
+        assert_eq!(session.request("g"), registers);
+
+        assert_eq!(session.request("p1"), "ab");
+        assert_eq!(session.request("p11"), "0200");
+        assert_eq!(session.request("P10=0300"), "OK");
+        assert_eq!(session.request("p10"), "0300");
+        assert_eq!(session.request("P12=11"), "E01");
+        assert_eq!(session.request("p13"), "E01");
+    }
+
+    #[test]
+    fn memory_can_be_read_and_written() {
+        let mut session = Session::start();
+        assert_eq!(session.request("m200,6"), "600570011204");
+        assert_eq!(session.request("M300,3:c0ffee"), "OK");
+        assert_eq!(session.request("m300,3"), "c0ffee");
+        assert_eq!(
+            session.debugger.cpu().memory()[0x300..0x303],
+            [0xC0, 0xFF, 0xEE]
+        );
+        assert_eq!(session.request("M300,2:c0ffee"), "E01");
+        assert_eq!(session.request("mfffff,1"), "E01");
+    }
+
+    #[test]
+    fn continue_stops_at_breakpoints_and_step_runs_one_instruction() {
+        let mut session = Session::start();
+        assert_eq!(session.request("Z0,202,2"), "OK");
+        assert_eq!(session.request("c"), "T05swbreak:;");
+        assert_eq!(session.request("p11"), "0202");
+        assert_eq!(session.request("p0"), "05");
+
+        assert_eq!(session.request("s"), "S05");
+        assert_eq!(session.request("p11"), "0204");
+        assert_eq!(session.request("p0"), "06");
+
+        // Without the breakpoint the program loops until interrupted
+        assert_eq!(session.request("z0,202,2"), "OK");
+        session.send_packet("c202");
+        session.run_until_running();
+        session.send(&[0x03]);
+        assert_eq!(session.reply(), "S02");
+        assert_eq!(session.request("p0"), "07");
+    }
+
+    #[test]
+    fn exiting_is_reported_as_an_exit_status() {
+        let mut session = Session::start_with(&EXITING_PROGRAM);
+        assert_eq!(session.request("c"), "W00");
+        assert!(!session.debugger.is_running());
+
+        // Stepping onto the exit reports it too, and tells the caller to halt
+        let mut session = Session::start_with(&EXITING_PROGRAM);
+        assert_eq!(session.request("s"), "S05");
+        session.send_packet("s");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            assert!(Instant::now() < deadline, "The stub never halted");
+            let decision = session.stub.run(&mut session.debugger, 100).unwrap();
+            if let CPUIterationDecision::Halt = decision {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(session.reply(), "W00");
+    }
+}
@@ -0,0 +1,278 @@
+use std::{
+    mem,
+    sync::{Arc, Mutex},
+};
+
+use super::{palette::Palette, phosphor::Phosphor, screen::Screen};
+
+/// A finished frame, as RGBA pixels
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Frame {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+
+    /// Rows that changed since the previous frame, bit `y` standing for row `y`
+    pub dirty_rows: u64,
+
+    /// Whether the sound timer was running at the end of the frame
+    pub beeping: bool,
+
+    /// Number of the 60Hz frame this was captured at
+    pub number: u64,
+
+    /// Colours the pixels were painted with
+    pub palette: Palette,
+}
+
+impl Frame {
+    /// Repaints the rows of `screen` that changed since the last capture through `phosphor`, or all
+    /// of them if the resolution or the palette changed
+    ///
+    /// NOTE: Only rows that changed are touched, so this must always be called on the same frame.
+    /// Buffers recycled through `SharedFrame` are brought up to date with `clone_from` instead.
+    pub fn capture(&mut self, screen: &mut Screen, palette: &Palette, phosphor: &mut Phosphor) {
+        let repaint = (self.width, self.height) != (screen.width(), screen.height())
+            || self.palette != *palette;
+        if repaint {
+            self.width = screen.width();
+            self.height = screen.height();
+            self.palette = *palette;
+            self.pixels.resize(self.width * self.height * 4, 0);
+        }
+        self.dirty_rows = phosphor.draw(screen, &mut self.pixels, palette, repaint);
+    }
+
+    /// Copies the dirty rows into `target`, an RGBA buffer of the same size holding the frame
+    /// before this one
+    pub fn copy_dirty_rows(&self, target: &mut [u8]) {
+        let row_length = self.width * 4;
+        let rows = target.chunks_exact_mut(row_length);
+        for (y, (target, row)) in rows.zip(self.pixels.chunks_exact(row_length)).enumerate() {
+            if self.dirty_rows & (1 << y) != 0 {
+                target.copy_from_slice(row);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Slot {
+    frame: Frame,
+
+    /// Set when `frame` hasn't been taken yet
+    fresh: bool,
+}
+
+/// Hands finished frames from the emulation thread to the UI thread
+///
+/// Triple buffered: each side keeps the `Frame` it is working on, and they trade it for the one
+/// in the middle. The lock is only held to swap two buffers, so neither side waits on the other
+/// drawing.
+#[derive(Clone, Debug, Default)]
+pub struct SharedFrame {
+    slot: Arc<Mutex<Slot>>,
+}
+
+impl SharedFrame {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes `frame` the latest one, giving back an older buffer in its place to draw into
+    ///
+    /// If the previous frame wasn't taken, its dirty rows are carried over so the taker doesn't
+    /// miss them.
+    pub fn publish(&self, frame: &mut Frame) {
+        let mut slot = self.slot.lock().unwrap_or_else(|err| err.into_inner());
+        if slot.fresh {
+            frame.dirty_rows |= slot.frame.dirty_rows;
+        }
+        mem::swap(&mut slot.frame, frame);
+        slot.fresh = true;
+    }
+
+    /// Swaps the latest frame into `frame`, returns `false` if nothing was published since the
+    /// last call
+    pub fn take(&self, frame: &mut Frame) -> bool {
+        let mut slot = self.slot.lock().unwrap_or_else(|err| err.into_inner());
+        if !slot.fresh {
+            return false;
+        }
+        mem::swap(&mut slot.frame, frame);
+        slot.fresh = false;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Frame, SharedFrame};
+    use crate::gfx::{
+        palette::Palette,
+        phosphor::{Persistence, Phosphor},
+        screen::{Screen, SpriteEdges},
+    };
+
+    /// Every row of a low resolution screen
+    const ALL_ROWS: u64 = u32::MAX as u64;
+
+    /// A frame that captured a blank screen
+    fn captured(screen: &mut Screen, phosphor: &mut Phosphor) -> Frame {
+        let mut frame = Frame::default();
+        frame.capture(screen, &Palette::CLASSIC, phosphor);
+        frame
+    }
+
+    /// A frame telling buffers apart by their number
+    fn numbered(number: u64, dirty_rows: u64) -> Frame {
+        Frame {
+            number,
+            dirty_rows,
+            pixels: vec![0; 4],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn first_capture_paints_every_row() {
+        let mut screen = Screen::new();
+        let frame = captured(&mut screen, &mut Phosphor::new(Persistence::Off));
+        assert_eq!((frame.width, frame.height), (Screen::WIDTH, Screen::HEIGHT));
+        assert_eq!(frame.pixels.len(), Screen::WIDTH * Screen::HEIGHT * 4);
+        assert_eq!(frame.dirty_rows, ALL_ROWS);
+        assert!(frame
+            .pixels
+            .chunks_exact(4)
+            .all(|pixel| pixel == [0, 0, 0, 0xff]));
+    }
+
+    #[test]
+    fn capture_only_repaints_the_rows_that_changed() {
+        let mut screen = Screen::new();
+        let mut phosphor = Phosphor::new(Persistence::Off);
+        let mut frame = captured(&mut screen, &mut phosphor);
+        // Scribble over the whole frame to see which rows get painted again
+        frame.pixels.fill(0x55);
+
+        screen.draw_sprite(0, 4, &[0x80, 0x80], SpriteEdges::Clip);
+        frame.capture(&mut screen, &Palette::CLASSIC, &mut phosphor);
+        assert_eq!(frame.dirty_rows, 0b11 << 4);
+        let row_length = Screen::WIDTH * 4;
+        for (y, row) in frame.pixels.chunks_exact(row_length).enumerate() {
+            if y == 4 || y == 5 {
+                assert_eq!(row[..4], [0xff; 4]);
+                assert!(row[4..]
+                    .chunks_exact(4)
+                    .all(|pixel| pixel == [0, 0, 0, 0xff]));
+            } else {
+                assert!(
+                    row.iter().all(|byte| *byte == 0x55),
+                    "row {} was repainted",
+                    y
+                );
+            }
+        }
+
+        frame.capture(&mut screen, &Palette::CLASSIC, &mut phosphor);
+        assert_eq!(frame.dirty_rows, 0);
+    }
+
+    #[test]
+    fn capture_repaints_everything_in_another_palette_or_resolution() {
+        let mut screen = Screen::new();
+        let mut phosphor = Phosphor::new(Persistence::Off);
+        let mut frame = captured(&mut screen, &mut phosphor);
+
+        frame.capture(&mut screen, &Palette::AMBER, &mut phosphor);
+        assert_eq!(frame.dirty_rows, ALL_ROWS);
+        assert_eq!(frame.palette, Palette::AMBER);
+        assert_eq!(frame.pixels[..4], Palette::AMBER.colour(0));
+
+        screen.set_high_resolution(true);
+        frame.capture(&mut screen, &Palette::AMBER, &mut phosphor);
+        assert_eq!(frame.dirty_rows, u64::MAX);
+        assert_eq!(
+            (frame.width, frame.height),
+            (Screen::HIRES_WIDTH, Screen::HIRES_HEIGHT)
+        );
+        assert_eq!(
+            frame.pixels.len(),
+            Screen::HIRES_WIDTH * Screen::HIRES_HEIGHT * 4
+        );
+    }
+
+    #[test]
+    fn only_dirty_rows_are_copied() {
+        let frame = Frame {
+            width: 1,
+            height: 3,
+            pixels: vec![1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3],
+            dirty_rows: 0b101,
+            ..Default::default()
+        };
+        let mut target = [0; 12];
+        frame.copy_dirty_rows(&mut target);
+        assert_eq!(target, [1, 1, 1, 1, 0, 0, 0, 0, 3, 3, 3, 3]);
+    }
+
+    #[test]
+    fn nothing_is_taken_before_a_publish() {
+        let frames = SharedFrame::new();
+        let mut frame = numbered(1, 0);
+        assert!(!frames.take(&mut frame));
+        assert_eq!(frame.number, 1);
+    }
+
+    #[test]
+    fn take_gives_the_newest_frame_once() {
+        let frames = SharedFrame::new();
+        let (mut first, mut second) = (numbered(1, 0b001), numbered(2, 0b100));
+        let mut taken = numbered(0, 0);
+
+        frames.publish(&mut first);
+        frames.publish(&mut second);
+        assert!(frames.take(&mut taken));
+        assert_eq!(taken.number, 2);
+        // The first frame was never taken, so its rows still have to be drawn
+        assert_eq!(taken.dirty_rows, 0b101);
+        assert!(!frames.take(&mut taken));
+        assert_eq!(taken.number, 2);
+
+        let mut third = numbered(3, 0b010);
+        frames.publish(&mut third);
+        assert!(frames.take(&mut taken));
+        assert_eq!(taken.number, 3);
+        assert_eq!(taken.dirty_rows, 0b010);
+    }
+
+    #[test]
+    fn buffers_are_traded_and_never_shared() {
+        let frames = SharedFrame::new();
+        let mut emulation = numbered(1, 0);
+        let mut ui = numbered(0, 0);
+        frames.publish(&mut emulation);
+        // Got back the empty frame the slot started with, swap in a buffer of our own
+        emulation = numbered(2, 0);
+        let mut buffers = [&emulation, &ui]
+            .map(|frame| frame.pixels.as_ptr())
+            .to_vec();
+
+        let mut published = None;
+        for number in 2..12 {
+            if number % 3 == 0 {
+                assert!(frames.take(&mut ui));
+                assert_eq!(Some(ui.pixels.as_ptr()), published);
+            } else {
+                emulation.number = number;
+                published = Some(emulation.pixels.as_ptr());
+                frames.publish(&mut emulation);
+                buffers.extend(published);
+            }
+            assert_ne!(emulation.pixels.as_ptr(), ui.pixels.as_ptr());
+        }
+        buffers.sort();
+        buffers.dedup();
+        assert_eq!(buffers.len(), 3);
+    }
+}
@@ -0,0 +1,118 @@
+use std::{
+    fs::OpenOptions,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use log::error;
+
+/// RGBA pixels, row by row
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RgbaImage {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl RgbaImage {
+    /// Blows every pixel up to a `scale` x `scale` square, a scale of 0 counting as 1
+    pub fn scaled(&self, scale: usize) -> RgbaImage {
+        let scale = scale.max(1);
+        let row_length = self.width * 4;
+        let mut pixels = Vec::with_capacity(self.pixels.len() * scale * scale);
+        for row in self.pixels.chunks_exact(row_length) {
+            let start = pixels.len();
+            for pixel in row.chunks_exact(4) {
+                for _ in 0..scale {
+                    pixels.extend_from_slice(pixel);
+                }
+            }
+            for _ in 1..scale {
+                pixels.extend_from_within(start..start + row_length * scale);
+            }
+        }
+        RgbaImage {
+            width: self.width * scale,
+            height: self.height * scale,
+            pixels,
+        }
+    }
+
+    pub fn write_png<W: Write>(&self, writer: W) -> Result<(), String> {
+        let mut encoder = png::Encoder::new(writer, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(&self.pixels))
+            .map_err(|err| {
+                error!("Could not encode PNG: {}", err);
+                "Could not encode PNG".to_string()
+            })
+    }
+
+    pub fn save_png(&self, path: &Path) -> Result<(), String> {
+        let file = match OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)
+        {
+            Ok(file) => file,
+            Err(err) => {
+                error!("Could not open file: {}", err.kind().to_string());
+                return Err("Could not open file".to_string());
+            }
+        };
+        let mut writer = BufWriter::new(file);
+        self.write_png(&mut writer)?;
+        writer
+            .flush()
+            .map_err(|err| format!("Error writing to file: {}", err.kind()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RgbaImage;
+
+    /// Two by two pixels of different colours
+    fn checker() -> RgbaImage {
+        RgbaImage {
+            width: 2,
+            height: 2,
+            pixels: vec![1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4],
+        }
+    }
+
+    #[test]
+    fn scaling_replicates_every_pixel() {
+        let image = checker().scaled(3);
+        assert_eq!((image.width, image.height), (6, 6));
+        assert_eq!(image.pixels.len(), 6 * 6 * 4);
+        for (index, pixel) in image.pixels.chunks_exact(4).enumerate() {
+            let (x, y) = (index % 6, index / 6);
+            let original = (y / 3 * 2 + x / 3 + 1) as u8;
+            assert_eq!(pixel, [original; 4], "pixel ({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn scales_below_two_keep_the_image() {
+        assert_eq!(checker().scaled(1), checker());
+        assert_eq!(checker().scaled(0), checker());
+    }
+
+    #[test]
+    fn png_holds_the_pixels() {
+        let mut png = vec![];
+        checker().write_png(&mut png).unwrap();
+
+        let mut reader = png::Decoder::new(png.as_slice()).read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        assert_eq!((info.width, info.height), (2, 2));
+        assert_eq!(info.color_type, png::ColorType::Rgba);
+        assert_eq!(pixels, checker().pixels);
+    }
+}
@@ -1 +1,6 @@
+pub mod framebuffer;
+pub mod image;
+pub mod palette;
+pub mod phosphor;
 pub mod screen;
+pub mod video;
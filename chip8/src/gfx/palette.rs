@@ -0,0 +1,89 @@
+/// RGBA colours for every combination of the two XO-CHIP bitplanes
+///
+/// Indexed by the planes a pixel holds: background, first plane, second plane, then both.
+/// Programs that only use one plane just show the first two.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Palette {
+    pub colours: [[u8; 4]; 4],
+}
+
+impl Palette {
+    /// White on black
+    pub const CLASSIC: Palette = Palette::new([0x00, 0x00, 0x00], [0xff, 0xff, 0xff]);
+
+    pub const AMBER: Palette = Palette::new([0x1a, 0x0f, 0x00], [0xff, 0xb0, 0x00]);
+
+    pub const GREEN_PHOSPHOR: Palette = Palette::new([0x00, 0x14, 0x05], [0x33, 0xff, 0x66]);
+
+    /// Dark green on a pale olive screen, like early handhelds
+    pub const LCD: Palette = Palette::new([0x9b, 0xbc, 0x0f], [0x0f, 0x38, 0x0f]);
+
+    /// Palettes that can be picked by name
+    pub const BUILT_IN: [(&'static str, Palette); 4] = [
+        ("classic", Palette::CLASSIC),
+        ("amber", Palette::AMBER),
+        ("green-phosphor", Palette::GREEN_PHOSPHOR),
+        ("lcd", Palette::LCD),
+    ];
+
+    /// Two-colour palette, the second plane colours being shades in between
+    pub const fn new(background: [u8; 3], foreground: [u8; 3]) -> Self {
+        Self::with_planes(
+            background,
+            foreground,
+            blend(background, foreground, 2),
+            blend(background, foreground, 1),
+        )
+    }
+
+    /// Palette with a colour for each combination of planes
+    pub const fn with_planes(
+        background: [u8; 3],
+        foreground: [u8; 3],
+        second_plane: [u8; 3],
+        both_planes: [u8; 3],
+    ) -> Self {
+        Palette {
+            colours: [
+                opaque(background),
+                opaque(foreground),
+                opaque(second_plane),
+                opaque(both_planes),
+            ],
+        }
+    }
+
+    pub fn built_in(name: &str) -> Option<Palette> {
+        Self::BUILT_IN
+            .iter()
+            .find(|(built_in, _)| *built_in == name)
+            .map(|(_, palette)| *palette)
+    }
+
+    /// RGBA colour of a pixel holding `planes`
+    pub fn colour(&self, planes: u8) -> [u8; 4] {
+        self.colours[(planes & 0b11) as usize]
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self::CLASSIC
+    }
+}
+
+const fn opaque([r, g, b]: [u8; 3]) -> [u8; 4] {
+    [r, g, b, 0xff]
+}
+
+/// Colour `thirds` thirds of the way from `from` to `to`
+const fn blend(from: [u8; 3], to: [u8; 3], thirds: u16) -> [u8; 3] {
+    let mut blended = [0; 3];
+    let mut i = 0;
+    while i < 3 {
+        let (from, to) = (from[i] as u16, to[i] as u16);
+        blended[i] = ((from * (3 - thirds) + to * thirds) / 3) as u8;
+        i += 1;
+    }
+    blended
+}
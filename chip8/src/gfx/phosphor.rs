@@ -0,0 +1,264 @@
+use super::{palette::Palette, screen::Screen};
+
+/// Pixels fainter than this are shown as background
+const MIN_BRIGHTNESS: u8 = 8;
+
+/// How pixels that turn off are shown, to hide the flicker of sprites being erased and redrawn
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Persistence {
+    /// Pixels show exactly what the screen holds
+    #[default]
+    Off,
+
+    /// Pixels that turn off fade out, losing `decay` of their brightness every frame, from 0
+    /// (never) to 1 (at once)
+    Fade { decay: f32 },
+
+    /// Each frame shows the last two ORed together
+    FrameBlend,
+}
+
+/// What a pixel showed in the previous frame
+#[derive(Clone, Copy, Debug, Default)]
+struct Glow {
+    /// Planes held by the screen
+    planes: u8,
+
+    /// Planes the pixel is lit in, kept while fading out
+    lit: u8,
+
+    /// 255 while lit
+    brightness: u8,
+}
+
+/// Paints the screen into a frame through a `Persistence` filter, remembering what every pixel
+/// showed before
+#[derive(Clone, Debug, Default)]
+pub struct Phosphor {
+    persistence: Persistence,
+    width: usize,
+    glows: Vec<Glow>,
+
+    /// Rows that still show pixels from previous frames, they change even if the screen doesn't
+    fading_rows: u64,
+}
+
+impl Phosphor {
+    pub fn new(persistence: Persistence) -> Self {
+        let persistence = match persistence {
+            Persistence::Fade { decay } => Persistence::Fade {
+                decay: decay.clamp(0.0, 1.0),
+            },
+            other => other,
+        };
+        Phosphor {
+            persistence,
+            ..Default::default()
+        }
+    }
+
+    /// Returns `true` if the next frame will look different even if the screen doesn't change
+    pub fn is_fading(&self) -> bool {
+        self.fading_rows != 0
+    }
+
+    /// Writes the rows that changed or are fading out to `frame` as RGBA, or every row if
+    /// `repaint` is set, e.g. when the resolution or the palette changed
+    ///
+    /// Returns the rows that were written, as a bitmask with bit `y` standing for row `y`
+    pub fn draw(
+        &mut self,
+        screen: &mut Screen,
+        frame: &mut [u8],
+        palette: &Palette,
+        repaint: bool,
+    ) -> u64 {
+        if self.persistence == Persistence::Off {
+            if repaint {
+                screen.take_dirty_rows();
+                screen.render(frame, palette);
+                return u64::MAX >> (u64::BITS as usize - screen.height());
+            }
+            return screen.draw(frame, palette);
+        }
+
+        let size = screen.width() * screen.height();
+        if self.width != screen.width() || self.glows.len() != size {
+            // Nothing carries over to another resolution
+            self.width = screen.width();
+            self.glows = vec![Glow::default(); size];
+        }
+        let mut rows = screen.take_dirty_rows() | self.fading_rows;
+        if repaint {
+            rows = u64::MAX >> (u64::BITS as usize - screen.height());
+        }
+
+        self.fading_rows = 0;
+        for y in (0..screen.height()).filter(|y| rows & (1 << y) != 0) {
+            let start = y * self.width;
+            let pixels = frame[start * 4..(start + self.width) * 4].chunks_exact_mut(4);
+            for (x, pixel) in pixels.enumerate() {
+                let glow = &mut self.glows[start + x];
+                let (colour, fading) = shine(self.persistence, glow, screen.pixel(x, y), palette);
+                pixel.copy_from_slice(&colour);
+                if fading {
+                    self.fading_rows |= 1 << y;
+                }
+            }
+        }
+        rows
+    }
+}
+
+/// Moves `glow` on to a frame where the screen holds `planes`
+///
+/// Returns the colour to show, and whether it will change next frame if `planes` doesn't
+fn shine(
+    persistence: Persistence,
+    glow: &mut Glow,
+    planes: u8,
+    palette: &Palette,
+) -> ([u8; 4], bool) {
+    let previous = glow.planes;
+    glow.planes = planes;
+    match persistence {
+        Persistence::Off => (palette.colour(planes), false),
+        Persistence::FrameBlend => {
+            let shown = planes | previous;
+            (palette.colour(shown), shown != planes)
+        }
+        Persistence::Fade { .. } if planes != 0 => {
+            glow.lit = planes;
+            glow.brightness = u8::MAX;
+            (palette.colour(planes), false)
+        }
+        Persistence::Fade { decay } => {
+            let brightness = glow.brightness as f32 * (1.0 - decay);
+            glow.brightness = if brightness < MIN_BRIGHTNESS as f32 {
+                0
+            } else {
+                brightness as u8
+            };
+            let colour = mix(palette.colour(0), palette.colour(glow.lit), glow.brightness);
+            (colour, glow.brightness != 0)
+        }
+    }
+}
+
+/// `brightness` 255ths of the way from `from` to `to`
+fn mix(from: [u8; 4], to: [u8; 4], brightness: u8) -> [u8; 4] {
+    let brightness = brightness as u16;
+    let mut mixed = [0; 4];
+    for (mixed, (from, to)) in mixed.iter_mut().zip(from.into_iter().zip(to)) {
+        *mixed = ((from as u16 * (255 - brightness) + to as u16 * brightness) / 255) as u8;
+    }
+    mixed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Persistence, Phosphor};
+    use crate::gfx::{
+        palette::Palette,
+        screen::{Screen, SpriteEdges},
+    };
+
+    const BLACK: [u8; 4] = [0, 0, 0, 0xff];
+    const WHITE: [u8; 4] = [0xff, 0xff, 0xff, 0xff];
+
+    /// A screen with its top left pixel lit, and a frame it was drawn to
+    fn lit_screen(phosphor: &mut Phosphor) -> (Screen, Vec<u8>) {
+        let mut screen = Screen::new();
+        let mut frame = vec![0; Screen::WIDTH * Screen::HEIGHT * 4];
+        screen.draw_sprite(0, 0, &[0x80], SpriteEdges::Clip);
+        phosphor.draw(&mut screen, &mut frame, &Palette::CLASSIC, true);
+        (screen, frame)
+    }
+
+    /// Turns the top left pixel off again
+    fn erase(screen: &mut Screen) {
+        screen.draw_sprite(0, 0, &[0x80], SpriteEdges::Clip);
+    }
+
+    /// Draws the next frame, returns the colour of the top left pixel and the rows written
+    fn next(phosphor: &mut Phosphor, screen: &mut Screen, frame: &mut [u8]) -> ([u8; 4], u64) {
+        let rows = phosphor.draw(screen, frame, &Palette::CLASSIC, false);
+        (frame[..4].try_into().unwrap(), rows)
+    }
+
+    #[test]
+    fn off_shows_the_screen_as_it_is() {
+        let mut phosphor = Phosphor::new(Persistence::Off);
+        let (mut screen, mut frame) = lit_screen(&mut phosphor);
+        assert_eq!(frame[..4], WHITE);
+
+        erase(&mut screen);
+        assert_eq!(next(&mut phosphor, &mut screen, &mut frame), (BLACK, 1));
+        assert!(!phosphor.is_fading());
+        assert_eq!(next(&mut phosphor, &mut screen, &mut frame), (BLACK, 0));
+    }
+
+    #[test]
+    fn fade_loses_brightness_every_frame_until_it_is_too_faint() {
+        let mut phosphor = Phosphor::new(Persistence::Fade { decay: 0.5 });
+        let (mut screen, mut frame) = lit_screen(&mut phosphor);
+        assert_eq!(frame[..4], WHITE);
+        assert!(!phosphor.is_fading());
+
+        erase(&mut screen);
+        // 255 halves down to 7, which is shown as background
+        for brightness in [127, 63, 31, 15] {
+            let colour = [brightness, brightness, brightness, 0xff];
+            assert_eq!(next(&mut phosphor, &mut screen, &mut frame), (colour, 1));
+            assert!(phosphor.is_fading());
+        }
+        assert_eq!(next(&mut phosphor, &mut screen, &mut frame), (BLACK, 1));
+        assert!(!phosphor.is_fading());
+        assert_eq!(next(&mut phosphor, &mut screen, &mut frame), (BLACK, 0));
+    }
+
+    #[test]
+    fn lighting_a_fading_pixel_brings_it_back_at_full_brightness() {
+        let mut phosphor = Phosphor::new(Persistence::Fade { decay: 0.5 });
+        let (mut screen, mut frame) = lit_screen(&mut phosphor);
+        erase(&mut screen);
+        next(&mut phosphor, &mut screen, &mut frame);
+
+        erase(&mut screen);
+        assert_eq!(next(&mut phosphor, &mut screen, &mut frame), (WHITE, 1));
+        assert!(!phosphor.is_fading());
+    }
+
+    #[test]
+    fn full_decay_turns_pixels_off_at_once() {
+        let mut phosphor = Phosphor::new(Persistence::Fade { decay: 3.0 });
+        let (mut screen, mut frame) = lit_screen(&mut phosphor);
+        erase(&mut screen);
+        assert_eq!(next(&mut phosphor, &mut screen, &mut frame), (BLACK, 1));
+        assert!(!phosphor.is_fading());
+    }
+
+    #[test]
+    fn frame_blend_keeps_pixels_for_one_more_frame() {
+        let mut phosphor = Phosphor::new(Persistence::FrameBlend);
+        let (mut screen, mut frame) = lit_screen(&mut phosphor);
+        erase(&mut screen);
+        assert_eq!(next(&mut phosphor, &mut screen, &mut frame), (WHITE, 1));
+        assert!(phosphor.is_fading());
+        assert_eq!(next(&mut phosphor, &mut screen, &mut frame), (BLACK, 1));
+        assert!(!phosphor.is_fading());
+        assert_eq!(next(&mut phosphor, &mut screen, &mut frame), (BLACK, 0));
+    }
+
+    #[test]
+    fn fading_starts_over_in_another_resolution() {
+        let mut phosphor = Phosphor::new(Persistence::FrameBlend);
+        let (mut screen, _) = lit_screen(&mut phosphor);
+        screen.set_high_resolution(true);
+        let mut frame = vec![0; Screen::HIRES_WIDTH * Screen::HIRES_HEIGHT * 4];
+        let rows = phosphor.draw(&mut screen, &mut frame, &Palette::CLASSIC, true);
+        assert_eq!(rows, u64::MAX);
+        assert_eq!(frame[..4], BLACK);
+        assert!(!phosphor.is_fading());
+    }
+}
@@ -1,83 +1,366 @@
+use super::{image::RgbaImage, palette::Palette};
+
+/// Bits in a row word, a row takes one word in low resolution and two in high resolution
+const WORD_BITS: usize = u64::BITS as usize;
+
+/// What happens to the parts of a sprite that fall off the screen
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpriteEdges {
+    /// They are not drawn
+    Clip,
+
+    /// They come back in from the opposite edge
+    Wrap,
+}
+
 pub struct Screen {
-    buffer: [u8; Screen::WIDTH * Screen::HEIGHT],
-    changed: bool,
+    /// One bitmask per row for each bitplane, the most significant bit being the leftmost pixel
+    ///
+    /// Rows are stored as `width / 64` words, and handled as a single `u128` while drawing
+    planes_rows: [Vec<u64>; 2],
+    width: usize,
+    height: usize,
+
+    /// Bitmask of the planes affected by drawing, clearing and scrolling
+    planes: u8,
+
+    /// Bit `y` is set when row `y` changed since it was last drawn
+    dirty_rows: u64,
 }
 
 impl Screen {
     pub const HEIGHT: usize = 32;
     pub const WIDTH: usize = 64;
+
+    /// SUPER-CHIP high resolution mode
+    pub const HIRES_HEIGHT: usize = 64;
+    pub const HIRES_WIDTH: usize = 128;
+
+    /// XO-CHIP has two bitplanes, giving four colours
+    pub const PLANES: u8 = 0b11;
 }
 
 impl Screen {
     pub fn new() -> Self {
         Screen {
-            buffer: [0x00; Self::WIDTH * Self::HEIGHT],
-            changed: false,
+            planes_rows: Self::blank_rows(Self::WIDTH, Self::HEIGHT),
+            width: Self::WIDTH,
+            height: Self::HEIGHT,
+            planes: 0b01,
+            dirty_rows: 0,
+        }
+    }
+
+    fn blank_rows(width: usize, height: usize) -> [Vec<u64>; 2] {
+        let words = width / WORD_BITS * height;
+        [vec![0; words], vec![0; words]]
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Every pixel as a byte holding the planes set at that position, row by row
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.width * self.height);
+        for y in 0..self.height {
+            bytes.extend((0..self.width).map(|x| self.pixel(x, y)));
+        }
+        bytes
+    }
+
+    /// Replaces the whole display, e.g. when loading a save state
+    ///
+    /// `buffer` holds a byte per pixel, as given by `to_bytes`
+    pub fn restore(&mut self, width: usize, height: usize, planes: u8, buffer: &[u8]) {
+        self.width = width;
+        self.height = height;
+        self.planes = planes & Self::PLANES;
+        self.planes_rows = Self::blank_rows(width, height);
+        for (y, pixels) in buffer.chunks(width).take(height).enumerate() {
+            for plane in 0..2 {
+                let row = pixels.iter().fold(0u128, |row, pixel| {
+                    (row << 1) | ((*pixel >> plane) & 1) as u128
+                });
+                self.set_row(plane, y, row);
+            }
+        }
+        self.mark_all_dirty();
+    }
+
+    /// Returns the planes set at (x, y), 0 being an empty pixel
+    pub fn pixel(&self, x: usize, y: usize) -> u8 {
+        let words_per_row = self.width / WORD_BITS;
+        let index = y * words_per_row + x / WORD_BITS;
+        let shift = WORD_BITS - 1 - x % WORD_BITS;
+        let low = (self.planes_rows[0][index] >> shift) & 1;
+        let high = (self.planes_rows[1][index] >> shift) & 1;
+        (low | (high << 1)) as u8
+    }
+
+    pub fn planes(&self) -> u8 {
+        self.planes
+    }
+
+    /// Selects the XO-CHIP bitplanes that following operations work on
+    pub fn set_planes(&mut self, planes: u8) {
+        self.planes = planes & Self::PLANES;
+    }
+
+    /// Switches between 64x32 and 128x64, clearing the screen
+    pub fn set_high_resolution(&mut self, high_resolution: bool) {
+        let (width, height) = if high_resolution {
+            (Self::HIRES_WIDTH, Self::HIRES_HEIGHT)
+        } else {
+            (Self::WIDTH, Self::HEIGHT)
+        };
+        self.width = width;
+        self.height = height;
+        self.planes_rows = Self::blank_rows(width, height);
+        self.mark_all_dirty();
+    }
+
+    /// Indices of the planes selected in `self.planes`
+    fn selected_planes(&self) -> impl Iterator<Item = usize> {
+        let planes = self.planes;
+        (0..2).filter(move |plane| planes & (1 << plane) != 0)
+    }
+
+    /// Row `y` of `plane`, right-aligned: the leftmost pixel is bit `width - 1`
+    fn row(&self, plane: usize, y: usize) -> u128 {
+        let words_per_row = self.width / WORD_BITS;
+        self.planes_rows[plane][y * words_per_row..(y + 1) * words_per_row]
+            .iter()
+            .fold(0, |row, word| (row << WORD_BITS) | *word as u128)
+    }
+
+    fn set_row(&mut self, plane: usize, y: usize, row: u128) {
+        let words_per_row = self.width / WORD_BITS;
+        let words = &mut self.planes_rows[plane][y * words_per_row..(y + 1) * words_per_row];
+        for (index, word) in words.iter_mut().enumerate() {
+            *word = (row >> ((words_per_row - 1 - index) * WORD_BITS)) as u64;
+        }
+        self.dirty_rows |= 1 << y;
+    }
+
+    /// Every pixel of a row set
+    fn row_mask(&self) -> u128 {
+        u128::MAX >> (u128::BITS as usize - self.width)
+    }
+
+    fn mark_all_dirty(&mut self) {
+        self.dirty_rows = u64::MAX >> (u64::BITS as usize - self.height);
+    }
+
+    /// Returns `true` if a filled pixel has been erased
+    ///
+    /// The starting position always wraps around, `edges` decides what happens to the parts of the
+    /// sprite that then fall off the right or bottom edge.
+    /// With more than one plane selected, `sprite` holds the data for each plane one after the other
+    pub fn draw_sprite(&mut self, x: usize, y: usize, sprite: &[u8], edges: SpriteEdges) -> bool {
+        log::debug!("Sprite: {:0x?}", sprite);
+        let mut did_erase_pixel = false;
+        for (plane, sprite) in self.split_by_plane(sprite) {
+            let rows = sprite.iter().map(|byte| *byte as u16);
+            did_erase_pixel |= self.draw_rows(x, y, rows, 8, plane, edges);
         }
+        did_erase_pixel
     }
+
+    /// Draws a SUPER-CHIP 16x16 sprite, made of 16 rows of two bytes each
+    ///
     /// Returns `true` if a filled pixel has been erased
-    pub fn draw_sprite(&mut self, mut x: usize, mut y: usize, sprite: &Vec<u8>) -> bool {
+    pub fn draw_large_sprite(
+        &mut self,
+        x: usize,
+        y: usize,
+        sprite: &[u8],
+        edges: SpriteEdges,
+    ) -> bool {
+        log::debug!("Large sprite: {:0x?}", sprite);
         let mut did_erase_pixel = false;
-        log::debug!("Sprite: {:0x?}", sprite);
+        for (plane, sprite) in self.split_by_plane(sprite) {
+            let rows = sprite
+                .chunks_exact(2)
+                .map(|row| ((row[0] as u16) << 8) | row[1] as u16);
+            did_erase_pixel |= self.draw_rows(x, y, rows, 16, plane, edges);
+        }
+        did_erase_pixel
+    }
 
-        x %= Screen::WIDTH;
-        y %= Screen::HEIGHT;
+    /// Pairs the index of every selected plane with its part of the sprite data
+    fn split_by_plane<'a>(&self, sprite: &'a [u8]) -> Vec<(usize, &'a [u8])> {
+        let planes: Vec<usize> = self.selected_planes().collect();
+        if planes.is_empty() {
+            return vec![];
+        }
+        let length = sprite.len() / planes.len();
+        planes
+            .into_iter()
+            .zip(sprite.chunks(length.max(1)))
+            .collect()
+    }
 
-        // For every byte (0b01010101) in the sprite, we need to write 8 bytes into our memory
-        for sprite_byte in sprite {
-            // Convert the (x,y) into a 1D index
-            let index = (y * Screen::WIDTH) + x;
+    /// XORs `rows` onto `plane`, the most significant of the `row_width` bits being the leftmost
+    /// pixel
+    fn draw_rows<I>(
+        &mut self,
+        x: usize,
+        y: usize,
+        rows: I,
+        row_width: usize,
+        plane: usize,
+        edges: SpriteEdges,
+    ) -> bool
+    where
+        I: Iterator<Item = u16>,
+    {
+        let mut did_erase_pixel = false;
 
-            // For every bit
-            for bit in 0..8 {
-                // Find out whether it's a 1 or a 0 (white or black pixel)
-                let mask = 1 << bit;
-                let masked_sprite_byte = sprite_byte & mask;
-                let draw_byte: u8 = if masked_sprite_byte != 0 { 0xff } else { 0x00 };
+        let x = x % self.width;
+        let y = y % self.height;
 
-                // "Mirror effect", draw from index + 7 to index
-                let index = index + (7 - bit);
+        for (row, sprite_row) in rows.enumerate() {
+            let pixel_y = match edges {
+                SpriteEdges::Clip if y + row >= self.height => break,
+                SpriteEdges::Clip => y + row,
+                SpriteEdges::Wrap => (y + row) % self.height,
+            };
 
-                // VF calculation and XOR onto screen
-                did_erase_pixel |= (self.buffer[index] == 0xff) && draw_byte == 0xff;
-                self.buffer[index] ^= draw_byte;
+            // Line the sprite up with the left edge, then move it to `x`
+            let aligned = (sprite_row as u128) << (self.width - row_width);
+            let mut sprite_row = aligned >> x;
+            if edges == SpriteEdges::Wrap && x > 0 {
+                // What fell off the right edge comes back on the left
+                sprite_row |= (aligned << (self.width - x)) & self.row_mask();
+            }
+            if sprite_row == 0 {
+                continue;
             }
-            y += 1;
+
+            // VF calculation and XOR onto screen
+            let screen_row = self.row(plane, pixel_y);
+            did_erase_pixel |= screen_row & sprite_row != 0;
+            self.set_row(plane, pixel_y, screen_row ^ sprite_row);
         }
 
-        self.changed = true;
         did_erase_pixel
     }
 
+    /// Scrolls the display down by `rows` pixels
+    pub fn scroll_down(&mut self, rows: usize) {
+        self.scroll(0, rows as isize);
+    }
+
+    /// Scrolls the display up by `rows` pixels
+    pub fn scroll_up(&mut self, rows: usize) {
+        self.scroll(0, -(rows as isize));
+    }
+
+    /// Scrolls the display right by `columns` pixels
+    pub fn scroll_right(&mut self, columns: usize) {
+        self.scroll(columns as isize, 0);
+    }
+
+    /// Scrolls the display left by `columns` pixels
+    pub fn scroll_left(&mut self, columns: usize) {
+        self.scroll(-(columns as isize), 0);
+    }
+
+    /// Moves the selected planes by (dx, dy), filling the uncovered area with blank pixels
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let mask = self.row_mask();
+        let planes: Vec<usize> = self.selected_planes().collect();
+        for plane in planes {
+            let source: Vec<u128> = (0..self.height).map(|y| self.row(plane, y)).collect();
+            for y in 0..self.height {
+                let source_y = y as isize - dy;
+                let row = match usize::try_from(source_y) {
+                    Ok(source_y) if source_y < self.height => source[source_y],
+                    _ => 0,
+                };
+                let row = if dx >= 0 {
+                    row.checked_shr(dx as u32).unwrap_or(0)
+                } else {
+                    row.checked_shl(dx.unsigned_abs() as u32).unwrap_or(0) & mask
+                };
+                self.set_row(plane, y, row);
+            }
+        }
+    }
+
+    /// Clears the selected planes
     pub fn clear(&mut self) {
-        self.buffer.fill(0x00);
-        self.changed = true;
+        let planes: Vec<usize> = self.selected_planes().collect();
+        for plane in planes {
+            self.planes_rows[plane].fill(0);
+        }
+        self.mark_all_dirty();
     }
 
+    /// Sets every pixel to the selected planes, or clears every plane
     pub fn fill(&mut self, fill: bool) {
-        self.buffer.fill(if fill { 0xFF } else { 0x00 });
+        for plane in 0..2 {
+            let selected = fill && self.planes & (1 << plane) != 0;
+            self.planes_rows[plane].fill(if selected { u64::MAX } else { 0 });
+        }
+        self.mark_all_dirty();
     }
 
-    pub fn draw(&mut self, frame: &mut [u8]) -> bool {
-        if !self.changed {
-            return false;
+    /// Writes the rows that changed since the last call to `frame` as RGBA, in `palette` colours
+    ///
+    /// Returns the rows that were written, as a bitmask with bit `y` standing for row `y`
+    pub fn draw(&mut self, frame: &mut [u8], palette: &Palette) -> u64 {
+        let dirty_rows = self.take_dirty_rows();
+        for y in (0..self.height).filter(|y| dirty_rows & (1 << y) != 0) {
+            self.render_row(frame, palette, y);
         }
+        dirty_rows
+    }
 
-        let mut pixel_vector: Vec<&mut [u8]> = frame.chunks_exact_mut(4).collect();
+    /// Returns `true` if the display changed since it was last drawn
+    pub fn has_changed(&self) -> bool {
+        self.dirty_rows != 0
+    }
 
-        // For every pixel (byte)
-        for (index, px) in self.buffer.iter().enumerate() {
-            // Pick the individual bit
-            let slice: [u8; 4] = if *px == 0xff {
-                [0xff, 0xff, 0xff, 0xff]
-            } else {
-                [0, 0, 0, 0]
-            };
-            pixel_vector[index].copy_from_slice(&slice);
+    /// Returns the rows that changed since the last call, with bit `y` standing for row `y`
+    pub fn take_dirty_rows(&mut self) -> u64 {
+        std::mem::take(&mut self.dirty_rows)
+    }
+
+    /// Writes every pixel to `frame` as RGBA, whether the display changed or not
+    pub fn render(&self, frame: &mut [u8], palette: &Palette) {
+        for y in 0..self.height {
+            self.render_row(frame, palette, y);
+        }
+    }
+
+    /// Copy of the display in `palette` colours, every pixel blown up to a `scale` x `scale` square
+    pub fn to_rgba_image(&self, palette: &Palette, scale: usize) -> RgbaImage {
+        let mut pixels = vec![0; self.width * self.height * 4];
+        self.render(&mut pixels, palette);
+        RgbaImage {
+            width: self.width,
+            height: self.height,
+            pixels,
         }
+        .scaled(scale)
+    }
 
-        self.changed = false;
-        true
+    fn render_row(&self, frame: &mut [u8], palette: &Palette, y: usize) {
+        let low = self.row(0, y);
+        let high = self.row(1, y);
+        let start = y * self.width * 4;
+        let pixels = frame[start..start + self.width * 4].chunks_exact_mut(4);
+        for (x, pixel) in pixels.enumerate() {
+            let shift = self.width - 1 - x;
+            let planes = ((low >> shift) & 1) | (((high >> shift) & 1) << 1);
+            pixel.copy_from_slice(&palette.colour(planes as u8));
+        }
     }
 }
 
@@ -86,3 +369,350 @@ impl Default for Screen {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Screen, SpriteEdges};
+    use crate::gfx::palette::Palette;
+
+    /// A full 8x8 square
+    const SQUARE: [u8; 8] = [0xFF; 8];
+
+    /// Coordinates of every lit pixel, row by row
+    fn lit(screen: &Screen) -> Vec<(usize, usize)> {
+        let mut pixels = vec![];
+        for y in 0..screen.height() {
+            for x in 0..screen.width() {
+                if screen.pixel(x, y) != 0 {
+                    pixels.push((x, y));
+                }
+            }
+        }
+        pixels
+    }
+
+    /// Coordinates of a `width` x `height` rectangle starting at (x, y), row by row
+    fn rectangle(x: usize, y: usize, width: usize, height: usize) -> Vec<(usize, usize)> {
+        let mut pixels = vec![];
+        for y in y..y + height {
+            for x in x..x + width {
+                pixels.push((x, y));
+            }
+        }
+        pixels
+    }
+
+    fn sorted(mut pixels: Vec<(usize, usize)>) -> Vec<(usize, usize)> {
+        pixels.sort_by_key(|(x, y)| (*y, *x));
+        pixels
+    }
+
+    /// Coordinates of every pixel set in `plane`, row by row
+    fn lit_in_plane(screen: &Screen, plane: usize) -> Vec<(usize, usize)> {
+        lit(screen)
+            .into_iter()
+            .filter(|(x, y)| screen.pixel(*x, *y) & (1 << plane) != 0)
+            .collect()
+    }
+
+    /// Moves `pixels` by (dx, dy), dropping the ones that leave a `width` x `height` screen
+    fn moved(
+        pixels: &[(usize, usize)],
+        dx: isize,
+        dy: isize,
+        width: usize,
+        height: usize,
+    ) -> Vec<(usize, usize)> {
+        let pixels = pixels.iter().filter_map(|(x, y)| {
+            let x = x.checked_add_signed(dx).filter(|x| *x < width)?;
+            let y = y.checked_add_signed(dy).filter(|y| *y < height)?;
+            Some((x, y))
+        });
+        sorted(pixels.collect())
+    }
+
+    type Scroll = fn(&mut Screen, usize);
+
+    /// Every scroll, with how far it moves pixels when given 4
+    const SCROLLS: [(Scroll, isize, isize); 4] = [
+        (Screen::scroll_down, 0, 4),
+        (Screen::scroll_up, 0, -4),
+        (Screen::scroll_right, 4, 0),
+        (Screen::scroll_left, -4, 0),
+    ];
+
+    /// A low or high resolution screen with a different shape in each plane
+    ///
+    /// The shapes straddle the middle of the screen, so the high resolution ones cross the two
+    /// words of their rows
+    fn two_plane_screen(high_resolution: bool) -> Screen {
+        let mut screen = Screen::new();
+        screen.set_high_resolution(high_resolution);
+        let (x, y) = (screen.width() / 2 - 4, screen.height() / 2 - 4);
+        screen.set_planes(0b01);
+        screen.draw_sprite(x, y, &SQUARE, SpriteEdges::Clip);
+        screen.set_planes(0b10);
+        screen.draw_sprite(x + 2, y - 2, &[0xF0; 4], SpriteEdges::Clip);
+        screen
+    }
+
+    #[test]
+    fn sprite_bits_are_drawn_left_to_right() {
+        for edges in [SpriteEdges::Clip, SpriteEdges::Wrap] {
+            let mut screen = Screen::new();
+            screen.draw_sprite(0, 0, &[0b1000_0001, 0b0100_0000], edges);
+            assert_eq!(lit(&screen), vec![(0, 0), (7, 0), (1, 1)]);
+        }
+    }
+
+    #[test]
+    fn sprite_inside_the_screen_is_the_same_in_both_modes() {
+        for edges in [SpriteEdges::Clip, SpriteEdges::Wrap] {
+            let mut screen = Screen::new();
+            screen.draw_sprite(56, 24, &SQUARE, edges);
+            assert_eq!(lit(&screen), rectangle(56, 24, 8, 8));
+        }
+    }
+
+    #[test]
+    fn starting_position_wraps_in_both_modes() {
+        for edges in [SpriteEdges::Clip, SpriteEdges::Wrap] {
+            let mut screen = Screen::new();
+            screen.draw_sprite(64 + 5, 32 + 3, &[0x80], edges);
+            assert_eq!(lit(&screen), vec![(5, 3)]);
+        }
+    }
+
+    #[test]
+    fn clip_at_the_right_edge_does_not_bleed_into_the_next_row() {
+        let mut screen = Screen::new();
+        screen.draw_sprite(60, 0, &SQUARE, SpriteEdges::Clip);
+        assert_eq!(lit(&screen), rectangle(60, 0, 4, 8));
+    }
+
+    #[test]
+    fn wrap_at_the_right_edge_continues_on_the_left_of_the_same_row() {
+        let mut screen = Screen::new();
+        screen.draw_sprite(60, 0, &SQUARE, SpriteEdges::Wrap);
+        let mut expected = rectangle(60, 0, 4, 8);
+        expected.extend(rectangle(0, 0, 4, 8));
+        assert_eq!(lit(&screen), sorted(expected));
+    }
+
+    #[test]
+    fn clip_at_the_bottom_edge() {
+        let mut screen = Screen::new();
+        screen.draw_sprite(0, 28, &SQUARE, SpriteEdges::Clip);
+        assert_eq!(lit(&screen), rectangle(0, 28, 8, 4));
+    }
+
+    #[test]
+    fn wrap_at_the_bottom_edge_continues_at_the_top() {
+        let mut screen = Screen::new();
+        screen.draw_sprite(0, 28, &SQUARE, SpriteEdges::Wrap);
+        let mut expected = rectangle(0, 28, 8, 4);
+        expected.extend(rectangle(0, 0, 8, 4));
+        assert_eq!(lit(&screen), sorted(expected));
+    }
+
+    #[test]
+    fn clip_at_the_bottom_right_corner() {
+        let mut screen = Screen::new();
+        screen.draw_sprite(63, 31, &SQUARE, SpriteEdges::Clip);
+        assert_eq!(lit(&screen), vec![(63, 31)]);
+    }
+
+    #[test]
+    fn wrap_at_the_bottom_right_corner_reaches_every_corner() {
+        let mut screen = Screen::new();
+        screen.draw_sprite(60, 28, &SQUARE, SpriteEdges::Wrap);
+        let mut expected = rectangle(60, 28, 4, 4);
+        expected.extend(rectangle(0, 28, 4, 4));
+        expected.extend(rectangle(60, 0, 4, 4));
+        expected.extend(rectangle(0, 0, 4, 4));
+        assert_eq!(lit(&screen), sorted(expected));
+    }
+
+    #[test]
+    fn fifteen_row_sprite_at_the_last_row() {
+        let sprite = [0xFF; 15];
+
+        let mut screen = Screen::new();
+        screen.draw_sprite(0, 31, &sprite, SpriteEdges::Clip);
+        assert_eq!(lit(&screen), rectangle(0, 31, 8, 1));
+
+        let mut screen = Screen::new();
+        screen.draw_sprite(0, 31, &sprite, SpriteEdges::Wrap);
+        let mut expected = rectangle(0, 31, 8, 1);
+        expected.extend(rectangle(0, 0, 8, 14));
+        assert_eq!(lit(&screen), sorted(expected));
+    }
+
+    #[test]
+    fn clipped_pixels_do_not_collide() {
+        let mut screen = Screen::new();
+        screen.draw_sprite(0, 0, &SQUARE, SpriteEdges::Clip);
+        assert!(!screen.draw_sprite(60, 0, &SQUARE, SpriteEdges::Clip));
+    }
+
+    #[test]
+    fn wrapped_pixels_collide() {
+        let mut screen = Screen::new();
+        screen.draw_sprite(0, 0, &SQUARE, SpriteEdges::Wrap);
+        assert!(screen.draw_sprite(60, 0, &SQUARE, SpriteEdges::Wrap));
+        let mut expected = rectangle(4, 0, 4, 8);
+        expected.extend(rectangle(60, 0, 4, 8));
+        assert_eq!(lit(&screen), sorted(expected));
+    }
+
+    #[test]
+    fn drawing_the_same_wrapped_sprite_twice_erases_it() {
+        let mut screen = Screen::new();
+        assert!(!screen.draw_sprite(60, 28, &SQUARE, SpriteEdges::Wrap));
+        assert!(screen.draw_sprite(60, 28, &SQUARE, SpriteEdges::Wrap));
+        assert!(lit(&screen).is_empty());
+    }
+
+    #[test]
+    fn large_sprite_at_the_high_resolution_corner() {
+        let sprite = [0xFF; 32];
+
+        let mut screen = Screen::new();
+        screen.set_high_resolution(true);
+        screen.draw_large_sprite(120, 56, &sprite, SpriteEdges::Clip);
+        assert_eq!(lit(&screen), rectangle(120, 56, 8, 8));
+
+        let mut screen = Screen::new();
+        screen.set_high_resolution(true);
+        screen.draw_large_sprite(120, 56, &sprite, SpriteEdges::Wrap);
+        let mut expected = rectangle(120, 56, 8, 8);
+        expected.extend(rectangle(0, 56, 8, 8));
+        expected.extend(rectangle(120, 0, 8, 8));
+        expected.extend(rectangle(0, 0, 8, 8));
+        assert_eq!(lit(&screen), sorted(expected));
+    }
+
+    #[test]
+    fn large_sprite_clipped_at_the_low_resolution_right_edge() {
+        let mut screen = Screen::new();
+        screen.draw_large_sprite(56, 0, &[0xFF; 32], SpriteEdges::Clip);
+        assert_eq!(lit(&screen), rectangle(56, 0, 8, 16));
+    }
+
+    #[test]
+    fn wrapping_only_touches_the_selected_plane() {
+        let mut screen = Screen::new();
+        screen.set_planes(0b10);
+        screen.draw_sprite(63, 31, &[0xC0, 0xC0], SpriteEdges::Wrap);
+        for (x, y) in [(63, 31), (0, 31), (63, 0), (0, 0)] {
+            assert_eq!(screen.pixel(x, y), 0b10);
+        }
+        assert_eq!(lit(&screen).len(), 4);
+    }
+
+    #[test]
+    fn scrolling_moves_only_the_selected_planes() {
+        for high_resolution in [false, true] {
+            for planes in [0b01, 0b10, 0b11] {
+                for (scroll, dx, dy) in SCROLLS {
+                    let mut screen = two_plane_screen(high_resolution);
+                    let (width, height) = (screen.width(), screen.height());
+                    let before = [lit_in_plane(&screen, 0), lit_in_plane(&screen, 1)];
+
+                    screen.set_planes(planes);
+                    scroll(&mut screen, 4);
+
+                    for (plane, before) in before.iter().enumerate() {
+                        let expected = if planes & (1 << plane) != 0 {
+                            moved(before, dx, dy, width, height)
+                        } else {
+                            before.clone()
+                        };
+                        assert_eq!(
+                            lit_in_plane(&screen, plane),
+                            expected,
+                            "plane {} of {:#04b}, high resolution: {}, moved by ({}, {})",
+                            plane,
+                            planes,
+                            high_resolution,
+                            dx,
+                            dy
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn scrolling_blanks_the_uncovered_area_without_wrapping() {
+        for high_resolution in [false, true] {
+            for (scroll, dx, dy) in SCROLLS {
+                let mut screen = Screen::new();
+                screen.set_high_resolution(high_resolution);
+                screen.fill(true);
+                let (width, height) = (screen.width(), screen.height());
+
+                scroll(&mut screen, 4);
+
+                let x = dx.max(0) as usize;
+                let y = dy.max(0) as usize;
+                let expected =
+                    rectangle(x, y, width - dx.unsigned_abs(), height - dy.unsigned_abs());
+                assert_eq!(lit(&screen), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn restoring_the_bytes_gives_back_the_same_screen() {
+        for high_resolution in [false, true] {
+            let screen = two_plane_screen(high_resolution);
+            let bytes = screen.to_bytes();
+            assert_eq!(bytes.len(), screen.width() * screen.height());
+
+            let mut restored = Screen::new();
+            restored.restore(screen.width(), screen.height(), screen.planes(), &bytes);
+            assert_eq!(restored.width(), screen.width());
+            assert_eq!(restored.height(), screen.height());
+            assert_eq!(restored.planes(), screen.planes());
+            assert_eq!(restored.to_bytes(), bytes);
+            for plane in 0..2 {
+                assert_eq!(lit_in_plane(&restored, plane), lit_in_plane(&screen, plane));
+            }
+            assert_eq!(
+                restored.take_dirty_rows().count_ones() as usize,
+                screen.height()
+            );
+        }
+    }
+
+    #[test]
+    fn dirty_rows_follow_drawing_clearing_and_resolution_switches() {
+        let palette = Palette::default();
+        let mut frame = vec![0; Screen::HIRES_WIDTH * Screen::HIRES_HEIGHT * 4];
+        let mut screen = Screen::new();
+        assert!(!screen.has_changed());
+
+        screen.draw_sprite(0, 5, &[0x80, 0x00, 0x80], SpriteEdges::Clip);
+        assert!(screen.has_changed());
+        assert_eq!(screen.draw(&mut frame, &palette), 0b101 << 5);
+        assert!(!screen.has_changed());
+        assert_eq!(screen.draw(&mut frame, &palette), 0);
+
+        // Wrapped rows are marked where they end up
+        screen.draw_sprite(0, 30, &[0x80; 4], SpriteEdges::Wrap);
+        assert_eq!(screen.take_dirty_rows(), (0b11 << 30) | 0b11);
+
+        screen.clear();
+        assert_eq!(screen.take_dirty_rows(), u32::MAX as u64);
+
+        screen.set_high_resolution(true);
+        assert_eq!(screen.take_dirty_rows(), u64::MAX);
+        screen.draw_large_sprite(0, 60, &[0xFF; 32], SpriteEdges::Clip);
+        assert_eq!(screen.draw(&mut frame, &palette), 0b1111 << 60);
+
+        screen.set_high_resolution(false);
+        assert_eq!(screen.take_dirty_rows(), u32::MAX as u64);
+    }
+}
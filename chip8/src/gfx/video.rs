@@ -0,0 +1,449 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use log::{error, info};
+
+use super::{palette::Palette, screen::Screen};
+use crate::cpu::scheduler::FRAMES_PER_SECOND;
+
+/// GIF codes are at most 12 bits long
+const MAX_GIF_CODES: u16 = 4096;
+
+/// Colour indices take 2 bits, for the 4 palette colours
+const GIF_MIN_CODE_SIZE: u8 = 2;
+const GIF_CLEAR_CODE: u16 = 1 << GIF_MIN_CODE_SIZE;
+const GIF_END_CODE: u16 = GIF_CLEAR_CODE + 1;
+
+/// Browsers slow down GIF frames shorter than this, in hundredths of a second
+const GIF_MIN_DELAY: u64 = 2;
+
+/// What a video is encoded as, picked from the file extension
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VideoFormat {
+    /// Animated GIF, with one image per change of the display
+    Gif,
+
+    /// Uncompressed YUV4MPEG2 at 60 frames per second, for piping into external encoders
+    Y4m,
+}
+
+impl VideoFormat {
+    pub fn from_path(path: &Path) -> Option<VideoFormat> {
+        match path.extension()?.to_str()? {
+            "gif" => Some(VideoFormat::Gif),
+            "y4m" => Some(VideoFormat::Y4m),
+            _ => None,
+        }
+    }
+}
+
+/// Writes every 60Hz frame of the screen to a video file
+///
+/// Videos are always as big as the high resolution display, so programs can switch resolution
+/// while recording: low resolution pixels are twice as big.
+pub struct VideoRecorder {
+    format: VideoFormat,
+    writer: BufWriter<File>,
+    palette: Palette,
+
+    /// Size of a high resolution pixel in the video
+    scale: usize,
+
+    /// Palette index of every pixel of the latest frame, not yet written for GIFs
+    pixels: Vec<u8>,
+
+    /// The latest frame as Y4M planes, written again for as long as the screen doesn't change
+    planes: Vec<u8>,
+
+    /// Frames recorded so far
+    frames: u64,
+
+    /// Where the GIF images written so far end, in hundredths of a second
+    gif_time: u64,
+}
+
+impl VideoRecorder {
+    /// Starts a video in the format matching the extension of `path`, either `.gif` or `.y4m`
+    pub fn create(path: &Path, palette: Palette, scale: usize) -> Result<Self, String> {
+        let Some(format) = VideoFormat::from_path(path) else {
+            return Err(format!(
+                "Unknown video format for {}, expected .gif or .y4m",
+                path.display()
+            ));
+        };
+        let scale = scale.max(1);
+        let width = Screen::HIRES_WIDTH * scale;
+        if format == VideoFormat::Gif && width > u16::MAX as usize {
+            return Err(format!(
+                "Video scale {} is too large, GIF videos can be at most {} pixels wide",
+                scale,
+                u16::MAX
+            ));
+        }
+        let file = match OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)
+        {
+            Ok(file) => file,
+            Err(err) => {
+                error!("Could not open file: {}", err.kind().to_string());
+                return Err("Could not open file".to_string());
+            }
+        };
+
+        let mut recorder = VideoRecorder {
+            format,
+            writer: BufWriter::new(file),
+            palette,
+            scale,
+            pixels: vec![],
+            planes: vec![],
+            frames: 0,
+            gif_time: 0,
+        };
+        recorder.write_header().map_err(write_error)?;
+        info!("Recording video to {}", path.display());
+        Ok(recorder)
+    }
+
+    pub fn width(&self) -> usize {
+        Screen::HIRES_WIDTH * self.scale
+    }
+
+    pub fn height(&self) -> usize {
+        Screen::HIRES_HEIGHT * self.scale
+    }
+
+    /// Adds one frame showing `screen`
+    ///
+    /// `changed` tells whether the display changed since the previous frame, the screen is only
+    /// read when it did
+    pub fn record_frame(&mut self, screen: &Screen, changed: bool) -> Result<(), String> {
+        if changed || self.frames == 0 {
+            if self.format == VideoFormat::Gif && self.frames > 0 {
+                self.write_gif_image().map_err(write_error)?;
+            }
+            self.pixels = self.palette_indices(screen);
+            if self.format == VideoFormat::Y4m {
+                self.planes = self.y4m_planes();
+            }
+        }
+        if self.format == VideoFormat::Y4m {
+            self.writer.write_all(b"FRAME\n").map_err(write_error)?;
+            self.writer.write_all(&self.planes).map_err(write_error)?;
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    /// Writes what's left and closes the file
+    pub fn finish(mut self) -> Result<(), String> {
+        if self.format == VideoFormat::Gif {
+            if self.frames > 0 {
+                self.write_gif_image().map_err(write_error)?;
+            }
+            // Trailer
+            self.writer.write_all(&[0x3B]).map_err(write_error)?;
+        }
+        self.writer.flush().map_err(write_error)?;
+        info!("Recorded {} frames", self.frames);
+        Ok(())
+    }
+
+    fn palette_indices(&self, screen: &Screen) -> Vec<u8> {
+        let pixel_size = self.scale * Screen::HIRES_WIDTH / screen.width();
+        let mut pixels = Vec::with_capacity(self.width() * self.height());
+        for y in 0..self.height() {
+            let y = y / pixel_size;
+            for x in 0..self.width() {
+                pixels.push(screen.pixel(x / pixel_size, y));
+            }
+        }
+        pixels
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        match self.format {
+            VideoFormat::Gif => self.write_gif_header(),
+            VideoFormat::Y4m => writeln!(
+                self.writer,
+                "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C444",
+                self.width(),
+                self.height(),
+                FRAMES_PER_SECOND
+            ),
+        }
+    }
+
+    fn write_gif_header(&mut self) -> io::Result<()> {
+        self.writer.write_all(b"GIF89a")?;
+        self.writer
+            .write_all(&(self.width() as u16).to_le_bytes())?;
+        self.writer
+            .write_all(&(self.height() as u16).to_le_bytes())?;
+        // Global colour table of 4 colours, 2 bits per channel of resolution
+        self.writer.write_all(&[0b1001_0001, 0, 0])?;
+        for [r, g, b, _] in self.palette.colours {
+            self.writer.write_all(&[r, g, b])?;
+        }
+        // Loop forever
+        self.writer.write_all(&[0x21, 0xFF, 11])?;
+        self.writer.write_all(b"NETSCAPE2.0")?;
+        self.writer.write_all(&[3, 1, 0, 0, 0])
+    }
+
+    /// Writes the latest frame, shown until the current one
+    ///
+    /// A frame shown for longer than a GIF delay can hold is written as several identical images
+    fn write_gif_image(&mut self) -> io::Result<()> {
+        let end = self.frames * 100 / FRAMES_PER_SECOND as u64;
+        let delay = end.saturating_sub(self.gif_time).max(GIF_MIN_DELAY);
+        self.gif_time += delay;
+
+        let data = lzw_compress(&self.pixels);
+        for delay in split_delay(delay) {
+            self.write_gif_image_data(delay, &data)?;
+        }
+        Ok(())
+    }
+
+    /// Writes one image made of already compressed `data`, shown for `delay` hundredths of a second
+    fn write_gif_image_data(&mut self, delay: u16, data: &[u8]) -> io::Result<()> {
+        // Graphic control extension, only holding the delay
+        self.writer.write_all(&[0x21, 0xF9, 4, 0])?;
+        self.writer.write_all(&delay.to_le_bytes())?;
+        self.writer.write_all(&[0, 0])?;
+
+        // Image descriptor covering the whole screen, with no local colour table
+        self.writer.write_all(&[0x2C, 0, 0, 0, 0])?;
+        self.writer
+            .write_all(&(self.width() as u16).to_le_bytes())?;
+        self.writer
+            .write_all(&(self.height() as u16).to_le_bytes())?;
+        self.writer.write_all(&[0, GIF_MIN_CODE_SIZE])?;
+
+        for block in data.chunks(255) {
+            self.writer.write_all(&[block.len() as u8])?;
+            self.writer.write_all(block)?;
+        }
+        self.writer.write_all(&[0])
+    }
+
+    /// Y, U then V planes at full resolution, in BT.601 studio range
+    fn y4m_planes(&self) -> Vec<u8> {
+        let colours = self.palette.colours.map(|[r, g, b, _]| {
+            let (r, g, b) = (r as f32, g as f32, b as f32);
+            let y = 16.0 + (65.481 * r + 128.553 * g + 24.966 * b) / 255.0;
+            let u = 128.0 + (-37.797 * r - 74.203 * g + 112.0 * b) / 255.0;
+            let v = 128.0 + (112.0 * r - 93.786 * g - 18.214 * b) / 255.0;
+            [y.round() as u8, u.round() as u8, v.round() as u8]
+        });
+        (0..3)
+            .flat_map(|plane| {
+                self.pixels
+                    .iter()
+                    .map(move |index| colours[*index as usize][plane])
+            })
+            .collect()
+    }
+}
+
+fn write_error(err: io::Error) -> String {
+    error!("Could not write video: {}", err.kind().to_string());
+    "Could not write video".to_string()
+}
+
+/// Splits a delay into as few parts as fit in a GIF delay each, as even as possible
+fn split_delay(delay: u64) -> impl Iterator<Item = u16> {
+    let parts = delay.div_ceil(u16::MAX as u64).max(1);
+    (0..parts).map(move |part| (delay / parts + u64::from(part < delay % parts)) as u16)
+}
+
+/// Packs variable length codes, least significant bit first
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u16, size: u32) {
+        self.buffer |= (code as u32) << self.bits;
+        self.bits += size;
+        while self.bits >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.bits -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+        self.bytes
+    }
+}
+
+/// Compresses palette indices the way GIF image data is
+fn lzw_compress(pixels: &[u8]) -> Vec<u8> {
+    // Bits needed for the codes the decoder can have seen, one behind the encoder's table
+    let code_size = |next_code: u16| (u16::BITS - (next_code - 1).leading_zeros()).min(12);
+
+    // Code of every string followed by each of the 4 indices, 0 for none as no string is code 0
+    let mut table: Vec<[u16; 4]> = vec![[0; 4]; MAX_GIF_CODES as usize];
+    let mut next_code = GIF_END_CODE + 1;
+    let mut output = BitWriter::default();
+    output.write(GIF_CLEAR_CODE, code_size(next_code));
+
+    let Some((&first, pixels)) = pixels.split_first() else {
+        output.write(GIF_END_CODE, code_size(next_code));
+        return output.finish();
+    };
+    let mut string = (first & 0b11) as u16;
+    for &index in pixels {
+        let index = (index & 0b11) as usize;
+        let longer = table[string as usize][index];
+        if longer != 0 {
+            string = longer;
+            continue;
+        }
+
+        output.write(string, code_size(next_code));
+        if next_code < MAX_GIF_CODES {
+            table[string as usize][index] = next_code;
+            next_code += 1;
+        } else {
+            output.write(GIF_CLEAR_CODE, code_size(next_code));
+            table.fill([0; 4]);
+            next_code = GIF_END_CODE + 1;
+        }
+        string = index as u16;
+    }
+    output.write(string, code_size(next_code));
+    output.write(GIF_END_CODE, code_size((next_code + 1).min(MAX_GIF_CODES)));
+    output.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lzw_compress, split_delay, GIF_CLEAR_CODE, GIF_END_CODE, MAX_GIF_CODES};
+
+    /// Reads variable length codes, least significant bit first
+    struct BitReader<'a> {
+        bytes: &'a [u8],
+        position: usize,
+    }
+
+    impl BitReader<'_> {
+        fn read(&mut self, size: usize) -> Option<u16> {
+            let mut code = 0;
+            for bit in 0..size {
+                let byte = self.bytes.get(self.position / 8)?;
+                code |= (((byte >> (self.position % 8)) & 1) as u16) << bit;
+                self.position += 1;
+            }
+            Some(code)
+        }
+    }
+
+    /// Decodes GIF image data the way decoders do, panicking on anything they would reject
+    fn lzw_decompress(data: &[u8]) -> Vec<u8> {
+        let mut reader = BitReader {
+            bytes: data,
+            position: 0,
+        };
+        let first_code_size = 3;
+        let mut code_size = first_code_size;
+        let mut table: Vec<Vec<u8>> = (0..GIF_CLEAR_CODE).map(|index| vec![index as u8]).collect();
+        let mut previous: Option<Vec<u8>> = None;
+        let mut pixels = vec![];
+        assert_eq!(reader.read(code_size), Some(GIF_CLEAR_CODE));
+        loop {
+            let code = reader.read(code_size).expect("missing end code");
+            if code == GIF_CLEAR_CODE {
+                code_size = first_code_size;
+                table.truncate(GIF_CLEAR_CODE as usize);
+                previous = None;
+                continue;
+            }
+            if code == GIF_END_CODE {
+                break;
+            }
+
+            // Codes past the end code are numbered from it, the table leaves those two out
+            let next_code = table.len() as u16 + 2;
+            let string = match (code.checked_sub(2), &previous) {
+                _ if code < GIF_CLEAR_CODE => table[code as usize].clone(),
+                (Some(index), _) if index < table.len() as u16 => table[index as usize].clone(),
+                (_, Some(previous)) if code == next_code => {
+                    let mut string = previous.clone();
+                    string.push(previous[0]);
+                    string
+                }
+                _ => panic!("code {} is not in the table", code),
+            };
+            pixels.extend_from_slice(&string);
+
+            if let Some(mut previous) = previous.replace(string.clone()) {
+                if next_code < MAX_GIF_CODES {
+                    previous.push(string[0]);
+                    table.push(previous);
+                    if next_code + 1 == 1 << code_size && code_size < 12 {
+                        code_size += 1;
+                    }
+                }
+            }
+        }
+        assert!(
+            data.len() * 8 - reader.position < 8,
+            "data goes on after the end code"
+        );
+        pixels
+    }
+
+    #[test]
+    fn compressed_pixels_decode_to_the_same_pixels() {
+        // Enough pixels with little repetition to fill the code table several times
+        let mut state = 1u32;
+        let noise: Vec<u8> = (0..100_000)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8 & 0b11
+            })
+            .collect();
+        let stripes: Vec<u8> = (0..128 * 64 * 4)
+            .map(|index| (index / 7 % 4) as u8)
+            .collect();
+        let inputs = [
+            vec![],
+            vec![3],
+            vec![0; 10_000],
+            vec![1, 2, 1, 2, 1, 2, 1],
+            stripes,
+            noise,
+        ];
+        for pixels in inputs {
+            assert_eq!(lzw_decompress(&lzw_compress(&pixels)), pixels);
+        }
+    }
+
+    #[test]
+    fn long_delays_are_split_evenly() {
+        assert_eq!(split_delay(2).collect::<Vec<_>>(), vec![2]);
+        assert_eq!(split_delay(65535).collect::<Vec<_>>(), vec![65535]);
+        assert_eq!(split_delay(65536).collect::<Vec<_>>(), vec![32768, 32768]);
+        assert_eq!(
+            split_delay(131_071).collect::<Vec<_>>(),
+            vec![43691, 43690, 43690]
+        );
+        let delay = 100 * 60 * 60 * 24;
+        assert_eq!(split_delay(delay).map(u64::from).sum::<u64>(), delay);
+    }
+}
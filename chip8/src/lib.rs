@@ -1,4 +1,6 @@
 pub mod cpu;
 pub mod dumper;
+pub mod gdb;
 pub mod gfx;
+pub mod movie;
 pub mod sound;
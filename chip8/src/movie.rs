@@ -0,0 +1,377 @@
+use std::{
+    fmt,
+    fs::OpenOptions,
+    io::{Read, Write},
+    path::Path,
+};
+
+use log::{debug, error};
+
+use crate::cpu::{
+    cpu::CPU,
+    quirks::{LoadStoreIndex, Quirks},
+    rng::RndAlgorithm,
+};
+
+/// First line of every movie file, bumped every time the format changes
+const HEADER: &str = "chippy-movie 2";
+
+/// Gives access to one of the quirk flags
+type QuirkFlag = fn(&mut Quirks) -> &mut bool;
+
+/// How the quirk flags are named on the `quirks` line, which lists the ones that are set
+const QUIRK_FLAGS: [(&str, QuirkFlag); 7] = [
+    ("shift-uses-vy", |quirks| &mut quirks.shift_uses_vy),
+    ("vf-reset", |quirks| &mut quirks.vf_reset),
+    ("jump-uses-vx", |quirks| &mut quirks.jump_uses_vx),
+    ("clip-sprites", |quirks| &mut quirks.clip_sprites),
+    ("display-wait", |quirks| &mut quirks.display_wait),
+    ("sys-is-error", |quirks| &mut quirks.sys_is_error),
+    ("extended-memory", |quirks| &mut quirks.extended_memory),
+];
+
+/// A key press or release, applied at the start of `frame`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MovieEvent {
+    pub frame: u64,
+    pub key: u8,
+    pub pressed: bool,
+}
+
+impl MovieEvent {
+    /// Parses a `<frame> <press|release> <key>` line, the key being a hexadecimal digit
+    pub fn from_line(line: &str) -> Option<Self> {
+        let [frame, action, key] = line.split_whitespace().collect::<Vec<_>>()[..] else {
+            return None;
+        };
+        let pressed = match action {
+            "press" => true,
+            "release" => false,
+            _ => return None,
+        };
+        Some(MovieEvent {
+            frame: frame.parse().ok()?,
+            key: u8::from_str_radix(key, 16).ok().filter(|key| *key <= 0xF)?,
+            pressed,
+        })
+    }
+}
+
+impl fmt::Display for MovieEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = if self.pressed { "press" } else { "release" };
+        write!(f, "{} {} {:X}", self.frame, action, self.key)
+    }
+}
+
+/// Lines of `text` that aren't blank once their `#` comment is cut off, numbered from 1
+pub fn content_lines(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.lines()
+        .enumerate()
+        .map(|(number, line)| {
+            (
+                number + 1,
+                line.split('#').next().unwrap_or_default().trim(),
+            )
+        })
+        .filter(|(_, line)| !line.is_empty())
+}
+
+/// Everything needed to play a program again exactly as it was played
+///
+/// Movies are plain text:
+///
+/// ```text
+/// chippy-movie 2
+/// seed 1234
+/// rnd splitmix
+/// frequency 500
+/// quirks clip-sprites
+/// load-store-index unchanged
+/// # frame action key
+/// 120 press 5
+/// 130 release 5
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Movie {
+    /// What the RND generator was seeded with
+    pub seed: u64,
+
+    /// Which RND algorithm the seed was fed to
+    pub rnd: RndAlgorithm,
+
+    /// CPU frequency the movie was recorded at, which decides how many instructions run per frame
+    pub frequency: u32,
+
+    /// Interpreter behaviour the movie was recorded with, the CPU playing it back must match
+    pub quirks: Quirks,
+
+    events: Vec<MovieEvent>,
+}
+
+impl Movie {
+    /// Returns an empty movie to record into, the CPU must be running with the same RND setup
+    pub fn new(seed: u64, rnd: RndAlgorithm, frequency: u32, quirks: Quirks) -> Self {
+        Movie {
+            seed,
+            rnd,
+            frequency,
+            quirks,
+            events: vec![],
+        }
+    }
+
+    pub fn events(&self) -> &[MovieEvent] {
+        &self.events
+    }
+
+    /// Adds a key event, events must be recorded in frame order
+    pub fn record(&mut self, frame: u64, key: u8, pressed: bool) {
+        debug!(
+            "Recording key {:X} {} at frame {}",
+            key,
+            if pressed { "press" } else { "release" },
+            frame
+        );
+        self.events.push(MovieEvent {
+            frame,
+            key: key & 0xF,
+            pressed,
+        });
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut quirks = self.quirks;
+        let flags: Vec<&str> = QUIRK_FLAGS
+            .iter()
+            .filter(|(_, flag)| *flag(&mut quirks))
+            .map(|(name, _)| *name)
+            .collect();
+        let mut text = format!(
+            "{}\nseed {}\nrnd {}\nfrequency {}\nquirks {}\nload-store-index {}\n",
+            HEADER,
+            self.seed,
+            self.rnd.name(),
+            self.frequency,
+            flags.join(" "),
+            self.quirks.load_store_index.name()
+        );
+        for event in &self.events {
+            text.push_str(&format!("{}\n", event));
+        }
+        text
+    }
+
+    pub fn from_text(text: &str) -> Result<Self, String> {
+        let mut lines = content_lines(text);
+
+        if lines.next().map(|(_, line)| line) != Some(HEADER) {
+            return Err("Not a chippy movie, or an unsupported version".to_string());
+        }
+
+        let mut seed = None;
+        let mut rnd = RndAlgorithm::default();
+        let mut frequency = None;
+        let mut quirks = None;
+        let mut load_store_index = None;
+        let mut events = vec![];
+        for (number, line) in lines {
+            let invalid = || format!("Invalid movie line {}: {}", number, line);
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            match parts.as_slice() {
+                ["seed", value] => seed = Some(value.parse::<u64>().map_err(|_| invalid())?),
+                ["rnd", name] => rnd = RndAlgorithm::from_name(name).ok_or_else(invalid)?,
+                ["frequency", value] => {
+                    frequency = Some(value.parse::<u32>().map_err(|_| invalid())?)
+                }
+                ["quirks", names @ ..] => {
+                    // Every flag is off in the modern profile
+                    let mut flags = Quirks::modern();
+                    for name in names {
+                        let (_, flag) = QUIRK_FLAGS
+                            .iter()
+                            .find(|(flag_name, _)| flag_name == name)
+                            .ok_or_else(invalid)?;
+                        *flag(&mut flags) = true;
+                    }
+                    quirks = Some(flags);
+                }
+                ["load-store-index", name] => {
+                    load_store_index = Some(LoadStoreIndex::from_name(name).ok_or_else(invalid)?)
+                }
+                _ => events.push(MovieEvent::from_line(line).ok_or_else(invalid)?),
+            }
+        }
+
+        // Stable, so events within the same frame keep their order
+        events.sort_by_key(|event| event.frame);
+        let mut quirks: Quirks = quirks.ok_or("Movie has no quirks")?;
+        quirks.load_store_index = load_store_index.ok_or("Movie has no load-store-index")?;
+        Ok(Movie {
+            seed: seed.ok_or("Movie has no seed")?,
+            rnd,
+            frequency: frequency.ok_or("Movie has no frequency")?,
+            quirks,
+            events,
+        })
+    }
+
+    pub fn save_to_file(&self, path: &Path) -> Result<(), String> {
+        let mut file = match OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)
+        {
+            Ok(file) => file,
+            Err(err) => {
+                error!("Could not open movie file: {}", err);
+                return Err("Could not open movie file".to_string());
+            }
+        };
+        file.write_all(self.to_text().as_bytes()).map_err(|err| {
+            error!("Could not write movie file: {}", err);
+            "Could not write movie file".to_string()
+        })
+    }
+
+    pub fn load_from_file(path: &Path) -> Result<Self, String> {
+        let mut file = match OpenOptions::new().read(true).open(path) {
+            Ok(file) => file,
+            Err(err) => {
+                error!("Could not open movie file: {}", err);
+                return Err("Could not open movie file".to_string());
+            }
+        };
+        let mut text = String::new();
+        if let Err(err) = file.read_to_string(&mut text) {
+            error!("Could not read movie file: {}", err);
+            return Err("Could not read movie file".to_string());
+        }
+        Self::from_text(&text)
+    }
+
+    /// Sets up the RND of `cpu` and returns a player feeding it the recorded events
+    ///
+    /// Fails if `cpu` wasn't created with the quirks the movie was recorded with
+    pub fn play(self, cpu: &mut CPU) -> Result<MoviePlayer, String> {
+        if cpu.quirks() != self.quirks {
+            return Err("The movie was recorded with different quirks".to_string());
+        }
+        cpu.set_random_source(self.rnd.source(self.seed));
+        Ok(MoviePlayer {
+            events: self.events,
+            next: 0,
+        })
+    }
+}
+
+/// Feeds the events of a movie to a CPU, frame by frame
+pub struct MoviePlayer {
+    events: Vec<MovieEvent>,
+    next: usize,
+}
+
+impl MoviePlayer {
+    /// Applies every event up to `frame`, call it at the start of each frame
+    pub fn apply(&mut self, frame: u64, cpu: &mut CPU) {
+        while let Some(event) = self
+            .events
+            .get(self.next)
+            .filter(|event| event.frame <= frame)
+        {
+            if event.pressed {
+                cpu.press_key(event.key);
+            } else {
+                cpu.release_key(event.key);
+            }
+            self.next += 1;
+        }
+    }
+
+    /// Returns `true` once every event has been applied
+    pub fn is_finished(&self) -> bool {
+        self.next >= self.events.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::{content_lines, Movie, MovieEvent};
+    use crate::cpu::{cpu::CPU, quirks::Quirks, rng::RndAlgorithm};
+
+    #[test]
+    fn text_round_trip_keeps_everything() {
+        for quirks in [Quirks::cosmac_vip(), Quirks::chip48(), Quirks::modern()] {
+            let mut movie = Movie::new(1234, RndAlgorithm::CosmacVip, 600, quirks);
+            movie.record(10, 0x5, true);
+            movie.record(12, 0x5, false);
+            assert_eq!(Movie::from_text(&movie.to_text()), Ok(movie));
+        }
+    }
+
+    #[test]
+    fn playing_needs_the_recorded_quirks() {
+        let movie = Movie::new(0, RndAlgorithm::SplitMix, 500, Quirks::cosmac_vip());
+        let (sound_tx, _) = mpsc::channel();
+        assert!(movie
+            .clone()
+            .play(&mut CPU::new(sound_tx.clone(), Quirks::modern()))
+            .is_err());
+        assert!(movie
+            .play(&mut CPU::new(sound_tx, Quirks::cosmac_vip()))
+            .is_ok());
+    }
+
+    #[test]
+    fn event_lines_round_trip() {
+        let event = MovieEvent {
+            frame: 120,
+            key: 0xA,
+            pressed: true,
+        };
+        assert_eq!(event.to_string(), "120 press A");
+        assert_eq!(MovieEvent::from_line("120 press A"), Some(event));
+        assert_eq!(
+            MovieEvent::from_line(" 7\trelease  f "),
+            Some(MovieEvent {
+                frame: 7,
+                key: 0xF,
+                pressed: false,
+            })
+        );
+    }
+
+    #[test]
+    fn bad_event_lines_are_rejected() {
+        for line in [
+            "120 press",
+            "120 press 5 6",
+            "120 hold 5",
+            "120 press 10",
+            "120 press G",
+            "-1 press 5",
+            "frame press 5",
+        ] {
+            assert_eq!(MovieEvent::from_line(line), None, "{}", line);
+        }
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_skipped() {
+        let text = "# frame action key\n\n120 press 5 # jump\n   \n130 release 5\n";
+        let lines: Vec<(usize, &str)> = content_lines(text).collect();
+        assert_eq!(lines, [(3, "120 press 5"), (5, "130 release 5")]);
+    }
+
+    #[test]
+    fn invalid_lines_are_reported_by_number() {
+        let mut movie = Movie::new(1, RndAlgorithm::SplitMix, 500, Quirks::modern()).to_text();
+        movie.push_str("\n120 jump 5\n");
+        let number = movie.lines().count();
+        let expected = format!("Invalid movie line {}: 120 jump 5", number);
+        assert_eq!(Movie::from_text(&movie), Err(expected));
+    }
+}
@@ -1,121 +1,220 @@
-use std::sync::mpsc::Receiver;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
 
 use cpal::{
     traits::{DeviceTrait, HostTrait, StreamTrait},
-    Host, SupportedStreamConfig,
+    Stream,
 };
 use log::{debug, error};
 
-use super::message::SoundMessage;
+use super::{message::SoundMessage, pattern::AudioPattern, sink::AudioSink, tone::Tone};
 
-pub struct Sound {
-    _host: Host,
-    config: SupportedStreamConfig,
-    message_rx: Receiver<SoundMessage>,
-    playing: bool,
+/// Plays the beep on the default output device
+///
+/// cpal streams can't be moved across threads, so the stream lives on a thread of its own. The
+/// stream plays all along, the thread waits for messages and turns the tone on and off.
+pub struct CpalSink {
+    thread: JoinHandle<()>,
 }
 
-impl Sound {
-    pub fn is_playing(&self) -> bool {
-        self.playing
-    }
-
-    pub fn new(message_rx: Receiver<SoundMessage>) -> Self {
-        let host = cpal::default_host();
-
-        let device = host.default_output_device().unwrap();
-
-        let config = device.default_output_config().unwrap();
-        debug!("Default output config: {:?}", config);
-        let mut sound = Sound {
-            _host: host,
-            config,
-            message_rx,
-            playing: false,
+impl CpalSink {
+    /// Starts a silent stream on the default output device
+    ///
+    /// If there is no device or it can't be opened, `message_rx` is given back with the error so
+    /// another sink can take over. It is only handed to the sound thread once the stream plays, so
+    /// it is given back even if the thread panics while opening it.
+    pub fn open(
+        message_rx: Receiver<SoundMessage>,
+    ) -> Result<Self, (String, Receiver<SoundMessage>)> {
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let (handover_tx, handover_rx) = mpsc::channel::<Receiver<SoundMessage>>();
+        let spawned = thread::Builder::new()
+            .name("sound".to_string())
+            .spawn(move || {
+                let tone_on = Arc::new(AtomicBool::new(false));
+                let pattern = Arc::new(Mutex::new(None));
+                // Dropping the stream at the end of the thread stops it
+                let _stream = match open_stream(Arc::clone(&tone_on), Arc::clone(&pattern)) {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        // Only fails if `open` isn't waiting anymore, which it always is
+                        ready_tx.send(Err(err)).ok();
+                        return;
+                    }
+                };
+                ready_tx.send(Ok(())).ok();
+                if let Ok(message_rx) = handover_rx.recv() {
+                    let sound = Sound {
+                        message_rx,
+                        tone_on,
+                        pattern,
+                    };
+                    sound.run();
+                }
+            });
+        let thread = match spawned {
+            Ok(thread) => thread,
+            Err(err) => {
+                error!("Could not spawn the sound thread: {}", err);
+                return Err(("Could not start the sound thread".to_string(), message_rx));
+            }
         };
 
-        sound.exec();
-        sound
+        match ready_rx.recv() {
+            Ok(Ok(())) => match handover_tx.send(message_rx) {
+                Ok(()) => Ok(CpalSink { thread }),
+                Err(mpsc::SendError(message_rx)) => Err((
+                    "The sound thread stopped after opening a stream".to_string(),
+                    message_rx,
+                )),
+            },
+            Ok(Err(err)) => Err((err, message_rx)),
+            Err(_) => Err((
+                "The sound thread stopped before opening a stream".to_string(),
+                message_rx,
+            )),
+        }
     }
+}
 
-    pub fn exec(&mut self) {
-        let config_copy = self.config.clone();
-
-        let device = self._host.default_output_device().unwrap();
-        debug!("Output device: {}", device.name().unwrap());
+impl AudioSink for CpalSink {
+    /// The stream plays in real time, messages are handled as soon as they come
+    fn end_frame(&mut self) -> Result<(), String> {
+        Ok(())
+    }
 
-        match self.config.sample_format() {
-            cpal::SampleFormat::F32 => self.run::<f32>(&device, &config_copy.into()),
-            cpal::SampleFormat::I16 => self.run::<i16>(&device, &config_copy.into()),
-            cpal::SampleFormat::U16 => self.run::<u16>(&device, &config_copy.into()),
-        };
+    fn finish(self: Box<Self>) -> Result<(), String> {
+        self.thread
+            .join()
+            .map_err(|_| "The sound thread panicked".to_string())
     }
+}
 
-    pub fn run<T>(&mut self, device: &cpal::Device, config: &cpal::StreamConfig)
-    where
-        T: cpal::Sample,
-    {
-        let sample_rate = config.sample_rate.0 as f32;
-        let channels = config.channels as usize;
-
-        // Produce a sinusoid of maximum amplitude.
-        let mut sample_clock = 0f32;
-        let mut next_value = move || {
-            sample_clock = (sample_clock + 1.0) % sample_rate;
-            (sample_clock * 220.0 * 2.0 * std::f32::consts::PI / sample_rate).sin()
-        };
+/// Starts a stream on the default output device, playing the default tone or `pattern` while
+/// `tone_on` is set and silence otherwise
+fn open_stream(
+    tone_on: Arc<AtomicBool>,
+    pattern: Arc<Mutex<Option<AudioPattern>>>,
+) -> Result<Stream, String> {
+    let host = cpal::default_host();
+    let device = host
+        .default_output_device()
+        .ok_or_else(|| "No audio output device".to_string())?;
+    debug!("Output device: {}", device.name().unwrap_or_default());
+
+    let config = device.default_output_config().map_err(|err| {
+        error!("Could not get the output config: {}", err);
+        "Could not get the output config".to_string()
+    })?;
+    debug!("Default output config: {:?}", config);
+
+    let stream = match config.sample_format() {
+        cpal::SampleFormat::F32 => build_stream::<f32>(&device, &config.into(), tone_on, pattern),
+        cpal::SampleFormat::I16 => build_stream::<i16>(&device, &config.into(), tone_on, pattern),
+        cpal::SampleFormat::U16 => build_stream::<u16>(&device, &config.into(), tone_on, pattern),
+    }?;
+    stream.play().map_err(|err| {
+        error!("Could not play the stream: {}", err);
+        "Could not play the stream".to_string()
+    })?;
+    Ok(stream)
+}
 
-        let err_fn = |err| error!("an error occurred on stream: {}", err);
-
-        let stream = device
-            .build_output_stream(
-                config,
-                move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
-                    Self::write_data(data, channels, &mut next_value)
-                },
-                err_fn,
-            )
-            .unwrap();
-        stream.pause().unwrap();
-
-        loop {
-            if let Ok(message) = self.message_rx.try_recv() {
-                match message {
-                    SoundMessage::Play if !self.playing => {
-                        debug!("Received play message");
-                        stream.play().unwrap_or_else(|err| {
-                            error!("Error with PlayStream: {:?}", err);
-                        });
-                        self.playing = true;
+fn build_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    tone_on: Arc<AtomicBool>,
+    pattern: Arc<Mutex<Option<AudioPattern>>>,
+) -> Result<Stream, String>
+where
+    T: cpal::Sample,
+{
+    let sample_rate = config.sample_rate.0 as f32;
+    let channels = config.channels as usize;
+
+    let mut tone = Tone::new(sample_rate);
+    let mut current_pattern = None;
+
+    let err_fn = |err| error!("an error occurred on stream: {}", err);
+
+    device
+        .build_output_stream(
+            config,
+            move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+                // Never wait on the control thread here, a pattern arriving late is played from
+                // the next buffer
+                if let Ok(pattern) = pattern.try_lock() {
+                    current_pattern = *pattern;
+                }
+                // Read for every sample, so the tone starts and stops in the middle of a buffer
+                let mut next_value = || {
+                    if tone_on.load(Ordering::Relaxed) {
+                        tone.next_sample(current_pattern)
+                    } else {
+                        0.0
                     }
-                    SoundMessage::Pause if self.playing => {
-                        debug!("Received pause message");
+                };
+                write_data(data, channels, &mut next_value)
+            },
+            err_fn,
+        )
+        .map_err(|err| {
+            error!("Could not build the output stream: {}", err);
+            "Could not build the output stream".to_string()
+        })
+}
 
-                        stream.pause().unwrap_or_else(|err| {
-                            error!("Error with PauseStream: {:?}", err);
-                        });
-                        self.playing = false;
-                    }
-                    SoundMessage::Stop => {
-                        return;
-                    }
-                    _ => {
-                        debug!("Ignoring no-ops");
-                    }
+struct Sound {
+    message_rx: Receiver<SoundMessage>,
+
+    /// Shared with the stream callback, which plays silence while it's unset
+    tone_on: Arc<AtomicBool>,
+
+    /// Shared with the stream callback, `None` plays the default tone
+    pattern: Arc<Mutex<Option<AudioPattern>>>,
+}
+
+impl Sound {
+    /// Turns the tone on and off as told, sleeping in between, until stopped or the CPU is gone
+    fn run(&self) {
+        while let Ok(message) = self.message_rx.recv() {
+            match message {
+                SoundMessage::Play => {
+                    debug!("Received play message");
+                    self.tone_on.store(true, Ordering::Relaxed);
+                }
+                SoundMessage::Pause => {
+                    debug!("Received pause message");
+                    self.tone_on.store(false, Ordering::Relaxed);
+                }
+                SoundMessage::Stop => {
+                    self.tone_on.store(false, Ordering::Relaxed);
+                    return;
+                }
+                SoundMessage::Pattern(pattern) => {
+                    debug!("Received pattern message: {:?}", pattern);
+                    *self.pattern.lock().unwrap() = Some(pattern);
                 }
             }
         }
     }
+}
 
-    pub fn write_data<T>(output: &mut [T], channels: usize, next_sample: &mut dyn FnMut() -> f32)
-    where
-        T: cpal::Sample,
-    {
-        for frame in output.chunks_mut(channels) {
-            let value: T = cpal::Sample::from::<f32>(&next_sample());
-            for sample in frame.iter_mut() {
-                *sample = value;
-            }
+/// Fills every channel of each frame of `output` with the next sample
+fn write_data<T>(output: &mut [T], channels: usize, next_sample: &mut dyn FnMut() -> f32)
+where
+    T: cpal::Sample,
+{
+    for frame in output.chunks_mut(channels) {
+        let value: T = cpal::Sample::from::<f32>(&next_sample());
+        for sample in frame.iter_mut() {
+            *sample = value;
         }
     }
 }
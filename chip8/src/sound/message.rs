@@ -1,5 +1,10 @@
+use super::pattern::AudioPattern;
+
 pub enum SoundMessage {
     Play,
     Pause,
     Stop,
+
+    /// Switch from the default tone to an XO-CHIP audio pattern
+    Pattern(AudioPattern),
 }
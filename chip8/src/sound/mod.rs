@@ -1,2 +1,6 @@
 pub mod beep;
 pub mod message;
+pub mod pattern;
+pub mod sink;
+pub mod tone;
+pub mod wav;
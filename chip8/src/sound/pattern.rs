@@ -0,0 +1,26 @@
+/// XO-CHIP 1-bit audio pattern, played back while the sound timer is active
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AudioPattern {
+    pub buffer: [u8; 16],
+    pub pitch: u8,
+}
+
+impl AudioPattern {
+    /// Pitch that plays the pattern at 4000 bits per second
+    pub const DEFAULT_PITCH: u8 = 64;
+
+    pub fn new(buffer: [u8; 16], pitch: u8) -> Self {
+        AudioPattern { buffer, pitch }
+    }
+
+    /// Playback rate in bits per second
+    pub fn bit_rate(&self) -> f32 {
+        4000.0 * 2f32.powf((self.pitch as f32 - 64.0) / 48.0)
+    }
+
+    /// Returns whether the bit at `position` (0 to 127) is set
+    pub fn bit(&self, position: usize) -> bool {
+        let byte = self.buffer[(position / 8) % 16];
+        byte & (0x80 >> (position % 8)) != 0
+    }
+}
@@ -0,0 +1,39 @@
+use std::sync::mpsc::Receiver;
+
+use super::message::SoundMessage;
+
+/// Where the beep goes
+///
+/// Sinks own the receiving end of the channel the CPU sends `SoundMessage`s to. `end_frame` is
+/// called after every emulated 60Hz frame, so sinks that don't play in real time can follow the
+/// frame clock.
+pub trait AudioSink: Send {
+    /// Handles the messages sent during the frame that just ran
+    fn end_frame(&mut self) -> Result<(), String>;
+
+    /// Closes the sink, once the CPU has sent `SoundMessage::Stop`
+    fn finish(self: Box<Self>) -> Result<(), String>;
+}
+
+/// Throws the sound away, for machines without an audio device
+pub struct NullSink {
+    message_rx: Receiver<SoundMessage>,
+}
+
+impl NullSink {
+    pub fn new(message_rx: Receiver<SoundMessage>) -> Self {
+        NullSink { message_rx }
+    }
+}
+
+impl AudioSink for NullSink {
+    fn end_frame(&mut self) -> Result<(), String> {
+        // Keeps the channel from growing for as long as the program runs
+        self.message_rx.try_iter().for_each(drop);
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), String> {
+        Ok(())
+    }
+}
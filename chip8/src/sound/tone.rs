@@ -0,0 +1,38 @@
+use super::pattern::AudioPattern;
+
+/// Frequency of the default beep, in Hz
+const TONE_FREQUENCY: f32 = 220.0;
+
+/// Produces the beep one sample at a time: a sinusoid of maximum amplitude, or the XO-CHIP
+/// pattern if there is one
+#[derive(Clone, Debug)]
+pub struct Tone {
+    sample_rate: f32,
+    sample_clock: f32,
+    pattern_position: f32,
+}
+
+impl Tone {
+    pub fn new(sample_rate: f32) -> Self {
+        Tone {
+            sample_rate,
+            sample_clock: 0.0,
+            pattern_position: 0.0,
+        }
+    }
+
+    /// Next sample, from -1 to 1
+    pub fn next_sample(&mut self, pattern: Option<AudioPattern>) -> f32 {
+        if let Some(pattern) = pattern {
+            self.pattern_position =
+                (self.pattern_position + pattern.bit_rate() / self.sample_rate) % 128.0;
+            return if pattern.bit(self.pattern_position as usize) {
+                1.0
+            } else {
+                -1.0
+            };
+        }
+        self.sample_clock = (self.sample_clock + 1.0) % self.sample_rate;
+        (self.sample_clock * TONE_FREQUENCY * 2.0 * std::f32::consts::PI / self.sample_rate).sin()
+    }
+}
@@ -0,0 +1,337 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+    sync::mpsc::Receiver,
+};
+
+use log::{error, info};
+
+use super::{message::SoundMessage, pattern::AudioPattern, sink::AudioSink, tone::Tone};
+use crate::cpu::scheduler::FRAMES_PER_SECOND;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+/// Bytes before the samples, where their size is written once known
+const HEADER_SIZE: u32 = 44;
+
+/// Samples are 16-bit mono
+const BYTES_PER_SAMPLE: u16 = 2;
+
+/// Most sample bytes a file can hold, the RIFF size counting them along with most of the header
+/// has to fit in 32 bits
+const MAX_DATA_SIZE: u32 = u32::MAX - (HEADER_SIZE - 8);
+
+/// Writes the sound to a 16-bit mono WAV file instead of playing it
+///
+/// Audio follows the emulated frame clock rather than wall time: every call to `end_frame` adds
+/// exactly 1/60th of a second, however long the frame actually took to run.
+pub struct WavSink<W: Write + Seek = BufWriter<File>> {
+    message_rx: Receiver<SoundMessage>,
+    writer: W,
+    sample_rate: u32,
+
+    /// Most sample bytes to write before giving up, `MAX_DATA_SIZE` unless testing
+    max_data_size: u32,
+    tone: Tone,
+    pattern: Option<AudioPattern>,
+    playing: bool,
+
+    /// Frames rendered so far
+    frames: u64,
+
+    /// Samples written so far
+    samples: u64,
+}
+
+impl WavSink {
+    pub fn create(
+        path: &Path,
+        sample_rate: u32,
+        message_rx: Receiver<SoundMessage>,
+    ) -> Result<Self, String> {
+        // Checked by `new` as well, but before leaving an empty file behind
+        if sample_rate == 0 || sample_rate.checked_mul(BYTES_PER_SAMPLE as u32).is_none() {
+            return Err(format!("Invalid sample rate {}Hz", sample_rate));
+        }
+        let file = match OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)
+        {
+            Ok(file) => file,
+            Err(err) => {
+                error!("Could not open file: {}", err.kind().to_string());
+                return Err("Could not open file".to_string());
+            }
+        };
+
+        let sink = WavSink::new(BufWriter::new(file), sample_rate, message_rx)?;
+        info!("Writing audio to {}", path.display());
+        Ok(sink)
+    }
+}
+
+impl<W: Write + Seek> WavSink<W> {
+    /// Writes the WAV file to `writer`, which should be empty
+    pub fn new(
+        writer: W,
+        sample_rate: u32,
+        message_rx: Receiver<SoundMessage>,
+    ) -> Result<Self, String> {
+        if sample_rate == 0 || sample_rate.checked_mul(BYTES_PER_SAMPLE as u32).is_none() {
+            return Err(format!("Invalid sample rate {}Hz", sample_rate));
+        }
+        let mut sink = WavSink {
+            message_rx,
+            writer,
+            sample_rate,
+            max_data_size: MAX_DATA_SIZE,
+            tone: Tone::new(sample_rate as f32),
+            pattern: None,
+            playing: false,
+            frames: 0,
+            samples: 0,
+        };
+        // Sizes are left at 0 until `finish`
+        sink.write_header(0).map_err(write_error)?;
+        Ok(sink)
+    }
+
+    fn write_header(&mut self, data_size: u32) -> io::Result<()> {
+        let channels: u16 = 1;
+        let bits_per_sample = BYTES_PER_SAMPLE * 8;
+        let block_align = channels * BYTES_PER_SAMPLE;
+
+        self.writer.write_all(b"RIFF")?;
+        self.writer
+            .write_all(&(HEADER_SIZE - 8).saturating_add(data_size).to_le_bytes())?;
+        self.writer.write_all(b"WAVE")?;
+
+        self.writer.write_all(b"fmt ")?;
+        self.writer.write_all(&16u32.to_le_bytes())?;
+        // PCM
+        self.writer.write_all(&1u16.to_le_bytes())?;
+        self.writer.write_all(&channels.to_le_bytes())?;
+        self.writer.write_all(&self.sample_rate.to_le_bytes())?;
+        self.writer
+            .write_all(&(self.sample_rate * block_align as u32).to_le_bytes())?;
+        self.writer.write_all(&block_align.to_le_bytes())?;
+        self.writer.write_all(&bits_per_sample.to_le_bytes())?;
+
+        self.writer.write_all(b"data")?;
+        self.writer.write_all(&data_size.to_le_bytes())
+    }
+
+    /// Fills in the sizes in the header for the samples written so far
+    fn write_sizes(&mut self) -> io::Result<()> {
+        let data_size = u32::try_from(self.samples * BYTES_PER_SAMPLE as u64)
+            .map_or(self.max_data_size, |size| size.min(self.max_data_size));
+        self.writer.flush()?;
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.write_header(data_size)?;
+        self.writer.flush()?;
+        self.writer.seek(SeekFrom::End(0)).map(|_| ())
+    }
+}
+
+impl<W: Write + Seek + Send> AudioSink for WavSink<W> {
+    /// Renders the frame that just ran, from the sound messages sent during it
+    ///
+    /// A frame is audible if the sound was on at any point in it, so a sound timer set to `n`
+    /// always lasts `n` frames.
+    fn end_frame(&mut self) -> Result<(), String> {
+        let mut audible = self.playing;
+        for message in self.message_rx.try_iter() {
+            match message {
+                SoundMessage::Play => {
+                    self.playing = true;
+                    audible = true;
+                }
+                SoundMessage::Pause | SoundMessage::Stop => self.playing = false,
+                SoundMessage::Pattern(pattern) => self.pattern = Some(pattern),
+            }
+        }
+
+        // Frames don't all get the same number of samples when the rate isn't a multiple of 60
+        self.frames += 1;
+        let end = self.frames * self.sample_rate as u64 / FRAMES_PER_SECOND as u64;
+        if end * BYTES_PER_SAMPLE as u64 > self.max_data_size as u64 {
+            // What was written so far is still a valid file
+            self.write_sizes().map_err(write_error)?;
+            return Err("The WAV file is full, it can hold at most 4 GiB of audio".to_string());
+        }
+        for _ in self.samples..end {
+            let value = if audible {
+                self.tone.next_sample(self.pattern)
+            } else {
+                0.0
+            };
+            let sample = (value * i16::MAX as f32) as i16;
+            self.writer
+                .write_all(&sample.to_le_bytes())
+                .map_err(write_error)?;
+        }
+        self.samples = end;
+        Ok(())
+    }
+
+    /// Fills in the sizes in the header and closes the file
+    fn finish(mut self: Box<Self>) -> Result<(), String> {
+        self.write_sizes().map_err(write_error)?;
+        info!(
+            "Wrote {} frames of audio, {} samples",
+            self.frames, self.samples
+        );
+        Ok(())
+    }
+}
+
+fn write_error(err: io::Error) -> String {
+    error!("Could not write audio: {}", err.kind().to_string());
+    "Could not write audio".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Cursor,
+        sync::mpsc::{channel, Sender},
+    };
+
+    use super::{WavSink, HEADER_SIZE};
+    use crate::sound::{message::SoundMessage, pattern::AudioPattern, sink::AudioSink};
+
+    /// Square wave at full volume, so every audible sample is the same
+    const LOUD: AudioPattern = AudioPattern {
+        buffer: [0xFF; 16],
+        pitch: AudioPattern::DEFAULT_PITCH,
+    };
+
+    fn sink(
+        file: &mut Vec<u8>,
+        sample_rate: u32,
+    ) -> (WavSink<Cursor<&mut Vec<u8>>>, Sender<SoundMessage>) {
+        let (sound_tx, sound_rx) = channel();
+        let sink = WavSink::new(Cursor::new(file), sample_rate, sound_rx).unwrap();
+        sound_tx.send(SoundMessage::Pattern(LOUD)).unwrap();
+        (sink, sound_tx)
+    }
+
+    fn u16_at(file: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(file[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u32_at(file: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(file[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn samples(file: &[u8]) -> Vec<i16> {
+        file[HEADER_SIZE as usize..]
+            .chunks_exact(2)
+            .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+            .collect()
+    }
+
+    #[test]
+    fn header_describes_16_bit_mono_pcm() {
+        let mut file = vec![];
+        let (mut sink, _sound_tx) = sink(&mut file, 6000);
+        sink.end_frame().unwrap();
+        Box::new(sink).finish().unwrap();
+
+        assert_eq!(file.len(), HEADER_SIZE as usize + 200);
+        assert_eq!(&file[0..4], b"RIFF");
+        assert_eq!(u32_at(&file, 4), HEADER_SIZE - 8 + 200);
+        assert_eq!(&file[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(&file, 16), 16);
+        // PCM, one channel
+        assert_eq!((u16_at(&file, 20), u16_at(&file, 22)), (1, 1));
+        assert_eq!(u32_at(&file, 24), 6000);
+        assert_eq!(u32_at(&file, 28), 12000);
+        // Two bytes a sample
+        assert_eq!((u16_at(&file, 32), u16_at(&file, 34)), (2, 16));
+        assert_eq!(&file[36..40], b"data");
+        assert_eq!(u32_at(&file, 40), 200);
+    }
+
+    #[test]
+    fn sizes_are_left_at_0_until_finished() {
+        let mut file = vec![];
+        let (mut sink, _sound_tx) = sink(&mut file, 6000);
+        sink.end_frame().unwrap();
+        drop(sink);
+        assert_eq!((u32_at(&file, 4), u32_at(&file, 40)), (HEADER_SIZE - 8, 0));
+    }
+
+    #[test]
+    fn frames_share_the_samples_of_a_second() {
+        let mut file = vec![];
+        let (mut sink, _sound_tx) = sink(&mut file, 1000);
+        let mut lengths = vec![];
+        for _ in 0..60 {
+            sink.end_frame().unwrap();
+            lengths.push(sink.samples);
+        }
+        // 16.67 samples a frame
+        assert_eq!(lengths[..3], [16, 33, 50]);
+        assert_eq!(lengths[59], 1000);
+        Box::new(sink).finish().unwrap();
+        assert_eq!(samples(&file).len(), 1000);
+    }
+
+    #[test]
+    fn frames_are_audible_if_the_sound_was_on_at_any_point() {
+        let mut file = vec![];
+        let (mut sink, sound_tx) = sink(&mut file, 6000);
+        sink.end_frame().unwrap();
+        // On and off again before the frame ends
+        sound_tx.send(SoundMessage::Play).unwrap();
+        sound_tx.send(SoundMessage::Pause).unwrap();
+        sink.end_frame().unwrap();
+        sink.end_frame().unwrap();
+        sound_tx.send(SoundMessage::Play).unwrap();
+        sink.end_frame().unwrap();
+        // Still on from the last frame until stopped
+        sound_tx.send(SoundMessage::Stop).unwrap();
+        sink.end_frame().unwrap();
+        sink.end_frame().unwrap();
+        Box::new(sink).finish().unwrap();
+
+        let audible: Vec<bool> = samples(&file)
+            .chunks(100)
+            .map(|frame| {
+                let loud = frame.iter().all(|sample| *sample == i16::MAX);
+                assert!(loud || frame.iter().all(|sample| *sample == 0));
+                loud
+            })
+            .collect();
+        assert_eq!(audible, [false, true, false, true, true, false]);
+    }
+
+    #[test]
+    fn full_files_stop_with_valid_sizes() {
+        let mut file = vec![];
+        let (mut sink, _sound_tx) = sink(&mut file, 6000);
+        sink.max_data_size = 300;
+        sink.end_frame().unwrap();
+        assert!(sink.end_frame().is_err());
+        drop(sink);
+
+        // The frame that didn't fit isn't written
+        assert_eq!(file.len(), HEADER_SIZE as usize + 200);
+        assert_eq!(
+            (u32_at(&file, 4), u32_at(&file, 40)),
+            (HEADER_SIZE - 8 + 200, 200)
+        );
+    }
+
+    #[test]
+    fn invalid_sample_rates_are_rejected() {
+        for sample_rate in [0, u32::MAX] {
+            let (_, sound_rx) = channel();
+            assert!(WavSink::new(Cursor::new(vec![]), sample_rate, sound_rx).is_err());
+        }
+    }
+}
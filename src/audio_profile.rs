@@ -0,0 +1,13 @@
+use clap::ValueEnum;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum AudioProfile {
+    /// Play on the default output device, or stay silent if there is none
+    Auto,
+
+    /// Play on the default output device, failing if there is none
+    Device,
+
+    /// Stay silent
+    None,
+}
@@ -7,7 +7,10 @@ use std::{
     path::PathBuf,
 };
 
-use chip8::cpu::instruction::{parse_instruction, Instruction, InstructionParseResult};
+use chip8::cpu::{
+    error::CpuError,
+    instruction::{parse_instruction, Instruction, InstructionParseResult},
+};
 use clap::{command, Parser, ValueEnum};
 use log::error;
 
@@ -125,7 +128,7 @@ fn write_file_full(
             return Err("Could not open file".to_string());
         }
     };
-    let instructions = disassemble(bytes);
+    let instructions = disassemble(bytes)?;
 
     let mut address = 0x200;
     for (instruction, op_code) in instructions.iter().zip(bytes.chunks_exact(2)) {
@@ -185,18 +188,23 @@ where
     Ok(buffer)
 }
 
-fn disassemble(input_bytes: &Vec<u8>) -> Vec<Instruction> {
+fn disassemble(input_bytes: &Vec<u8>) -> Result<Vec<Instruction>, String> {
     let mut result = Vec::new();
-    for vector in input_bytes.chunks_exact(2) {
+    for (index, vector) in input_bytes.chunks_exact(2).enumerate() {
         let number = ((vector[0] as u16) << 8) | vector[1] as u16;
         result.push(match parse_instruction(number) {
             InstructionParseResult::Ok(instruction) => instruction,
             InstructionParseResult::Unparsed => {
-                panic!("Cannot parse instruction: {:04X}", number);
+                let error = CpuError::UnknownOpcode {
+                    opcode: number,
+                    address: 0x200 + index as u16 * 2,
+                };
+                error!("{}", error);
+                return Err(error.to_string());
             }
         })
     }
-    result
+    Ok(result)
 }
 
 fn main() -> Result<(), String> {
@@ -211,7 +219,7 @@ fn main() -> Result<(), String> {
     if args.format == DisassembleFormat::Hex {
         return write_file_hex(output_file, &input_bytes, show_memory_address);
     } else {
-        let instructions = disassemble(&input_bytes);
+        let instructions = disassemble(&input_bytes)?;
         if args.format == DisassembleFormat::Instructions {
             return write_file_instructions(output_file, &instructions, show_memory_address);
         } else {
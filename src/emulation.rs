@@ -0,0 +1,377 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::mpsc::{Receiver, RecvTimeoutError},
+    thread::{self, JoinHandle},
+    time::Instant,
+};
+
+use chip8::{
+    cpu::{cpu::CPUIterationDecision, debugger::Debugger, error::CpuError, scheduler::Scheduler},
+    dumper::{dump_cpu, DumpMemory},
+    gdb::stub::GdbStub,
+    gfx::{
+        framebuffer::{Frame, SharedFrame},
+        palette::Palette,
+        phosphor::{Persistence, Phosphor},
+        video::VideoRecorder,
+    },
+    movie::{Movie, MoviePlayer},
+    sound::sink::AudioSink,
+};
+use log::{debug, error, info};
+use tao::event_loop::EventLoopProxy;
+
+use crate::save_states::{self, SlotAction};
+
+/// Screenshots and videos live next to the program, named after the frame they start at, e.g.
+/// `game.ch8` at frame 600 saves a screenshot to `game.600.png`
+fn capture_path(program_path: &Path, frame: u64, extension: &str) -> PathBuf {
+    program_path.with_extension(format!("{}.{}", frame, extension))
+}
+
+/// What the UI thread asks the emulation thread to do
+#[derive(Debug)]
+pub enum Command {
+    /// A key of the CHIP-8 keypad going up or down
+    Keypad {
+        key: u8,
+        pressed: bool,
+    },
+    Slot(SlotAction),
+
+    /// Start or stop walking back through the rewind history
+    Rewind(bool),
+    SpeedUp,
+    SlowDown,
+    DumpState,
+
+    /// Save the display to a PNG file next to the program
+    Screenshot,
+
+    /// Start recording a GIF next to the program, or stop the current recording
+    ToggleVideo,
+
+    /// Repaint the display in other colours
+    SetPalette(Palette),
+    Quit,
+}
+
+/// What the emulation thread tells the UI thread, waking up its event loop
+#[derive(Debug)]
+pub enum Notification {
+    /// A new frame has been published to the `SharedFrame`
+    FrameReady,
+
+    /// Execution stopped with an error, until rewound
+    Stopped(CpuError),
+
+    /// Rewinding got past an error
+    Resumed,
+}
+
+/// Everything the emulation thread owns, the UI thread only sees the frames it publishes
+pub struct Emulation {
+    pub debugger: Debugger,
+    pub scheduler: Scheduler,
+
+    /// Execution is driven by GDB while it is connected
+    pub gdb: Option<GdbStub>,
+
+    /// Keyboard input is ignored while a movie plays
+    pub player: Option<MoviePlayer>,
+    pub recording: Option<(Movie, PathBuf)>,
+
+    /// Save state slots are named after the program
+    pub program_path: PathBuf,
+    pub frames: SharedFrame,
+    pub palette: Palette,
+
+    /// Size of a CHIP-8 pixel in screenshots
+    pub screenshot_scale: usize,
+
+    /// Records every frame while set
+    pub video: Option<(VideoRecorder, PathBuf)>,
+
+    /// Size of a high resolution pixel in videos
+    pub video_scale: usize,
+
+    /// Where the beep goes, dropped if it fails
+    pub audio: Option<Box<dyn AudioSink>>,
+    pub persistence: Persistence,
+    pub proxy: EventLoopProxy<Notification>,
+}
+
+/// Where a running emulation is at
+struct State {
+    /// What the screen looks like, only the rows that changed are repainted
+    image: Frame,
+
+    /// Pixels that turned off and are still shown in `image`
+    phosphor: Phosphor,
+
+    /// The next frame to publish, the other two buffers are in `SharedFrame` and the UI
+    frame: Frame,
+
+    /// Set when the program halts, nothing runs after that but rewinding
+    halted: bool,
+
+    /// Set when the CPU fails, until rewound
+    failed: bool,
+
+    /// Set while the rewind key is held, the CPU is paused and walks back one frame per tick
+    rewinding: bool,
+
+    /// Whether the last published frame was beeping
+    beeping: bool,
+}
+
+impl Emulation {
+    pub fn spawn(self, commands: Receiver<Command>) -> JoinHandle<()> {
+        thread::Builder::new()
+            .name("emulation".to_string())
+            .spawn(move || self.run(commands))
+            .expect("Could not spawn the emulation thread")
+    }
+
+    /// Runs frames as they are due, handling commands in between, until told to quit
+    fn run(mut self, commands: Receiver<Command>) {
+        let mut state = State {
+            image: Frame::default(),
+            phosphor: Phosphor::new(self.persistence),
+            frame: Frame::default(),
+            halted: false,
+            failed: false,
+            rewinding: false,
+            beeping: false,
+        };
+        // Show the first frame right away
+        self.publish(&mut state);
+
+        loop {
+            let timeout = self
+                .scheduler
+                .next_frame()
+                .saturating_duration_since(Instant::now());
+            match commands.recv_timeout(timeout) {
+                Ok(Command::Quit) | Err(RecvTimeoutError::Disconnected) => break,
+                Ok(command) => {
+                    self.handle_command(command, &mut state);
+                    continue;
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+
+            self.run_frame(&mut state);
+            self.record_video_frame();
+            self.write_audio_frame();
+            if self.debugger.cpu().screen().has_changed()
+                || state.phosphor.is_fading()
+                || self.debugger.cpu().is_beeping() != state.beeping
+            {
+                self.publish(&mut state);
+            }
+        }
+
+        self.finish();
+    }
+
+    fn handle_command(&mut self, command: Command, state: &mut State) {
+        match command {
+            Command::Keypad { .. } if self.player.is_some() => {}
+            Command::Keypad { key, pressed } => {
+                let cpu = self.debugger.cpu_mut();
+                if pressed {
+                    cpu.press_key(key);
+                } else {
+                    cpu.release_key(key);
+                }
+                if let Some((movie, _)) = self.recording.as_mut() {
+                    // Applied before the next frame runs, same as when playing back
+                    movie.record(self.scheduler.frame(), key, pressed);
+                }
+            }
+            // Movies only hold key events, going back in time would make them play out differently
+            Command::Slot(SlotAction::Load(_)) | Command::Rewind(true) if self.has_movie() => {
+                println!("Can't load states or rewind while a movie is recording or playing");
+            }
+            Command::Slot(action) => {
+                save_states::apply(action, self.debugger.cpu_mut(), &self.program_path);
+            }
+            Command::Rewind(rewinding) => state.rewinding = rewinding,
+            // Movies are played back at the single frequency they were recorded at
+            Command::SpeedUp | Command::SlowDown if self.has_movie() => {
+                println!("Can't change the speed while a movie is recording or playing");
+            }
+            Command::SpeedUp => {
+                self.scheduler.speed_up();
+                println!("New frequency: {}", self.scheduler.frequency());
+            }
+            Command::SlowDown => {
+                self.scheduler.slow_down();
+                println!("New frequency: {}", self.scheduler.frequency());
+            }
+            Command::DumpState => dump_cpu(self.debugger.cpu(), DumpMemory::Yes),
+            Command::Screenshot => self.screenshot(),
+            Command::ToggleVideo => self.toggle_video(),
+            Command::SetPalette(palette) => {
+                self.palette = palette;
+                // Shown right away, even if the program is halted
+                self.publish(state);
+            }
+            Command::Quit => {}
+        }
+    }
+
+    fn has_movie(&self) -> bool {
+        self.recording.is_some() || self.player.is_some()
+    }
+
+    fn run_frame(&mut self, state: &mut State) {
+        if state.rewinding {
+            if self.debugger.cpu_mut().rewind() {
+                state.halted = false;
+                if state.failed {
+                    state.failed = false;
+                    self.notify(Notification::Resumed);
+                }
+            }
+            self.scheduler.skip_frame();
+            return;
+        }
+        if state.halted || state.failed {
+            self.scheduler.skip_frame();
+            return;
+        }
+
+        if let Some(player) = self.player.as_mut() {
+            player.apply(self.scheduler.frame(), self.debugger.cpu_mut());
+        }
+        let result = match self.gdb.as_mut() {
+            Some(stub) => {
+                let instructions = self.scheduler.take_frame_instructions() as usize;
+                stub.run(&mut self.debugger, instructions)
+                    .inspect(|_| self.scheduler.end_frame(self.debugger.cpu_mut()))
+            }
+            None => self.scheduler.run_frame(self.debugger.cpu_mut()),
+        };
+        if self.gdb.as_ref().is_some_and(|stub| !stub.is_connected()) {
+            info!("GDB disconnected, resuming execution");
+            self.gdb = None;
+        }
+
+        match result {
+            Ok(CPUIterationDecision::Continue) => {}
+            Ok(CPUIterationDecision::Halt) => {
+                info!("Program halted at frame {}", self.scheduler.frame());
+                state.halted = true;
+                self.scheduler.skip_frame();
+            }
+            Err(err) => {
+                error!("Execution stopped: {}", err);
+                state.failed = true;
+                self.scheduler.skip_frame();
+                self.notify(Notification::Stopped(err));
+            }
+        }
+    }
+
+    fn screenshot(&self) {
+        let path = capture_path(&self.program_path, self.scheduler.frame(), "png");
+        let image = self
+            .debugger
+            .cpu()
+            .screen()
+            .to_rgba_image(&self.palette, self.screenshot_scale);
+        match image.save_png(&path) {
+            Ok(()) => println!("Saved screenshot to {}", path.display()),
+            Err(err) => error!("Could not save screenshot: {}", err),
+        }
+    }
+
+    fn toggle_video(&mut self) {
+        if self.video.is_some() {
+            self.save_video();
+            return;
+        }
+
+        let path = capture_path(&self.program_path, self.scheduler.frame(), "gif");
+        match VideoRecorder::create(&path, self.palette, self.video_scale) {
+            Ok(video) => {
+                println!("Recording video to {}", path.display());
+                self.video = Some((video, path));
+            }
+            Err(err) => error!("Could not record video: {}", err),
+        }
+    }
+
+    /// Adds the frame that just ran to the video being recorded, if any
+    ///
+    /// NOTE: Must run before the frame is published, which takes the changes of the screen
+    fn record_video_frame(&mut self) {
+        let Some((video, _)) = self.video.as_mut() else {
+            return;
+        };
+        let screen = self.debugger.cpu().screen();
+        if let Err(err) = video.record_frame(screen, screen.has_changed()) {
+            error!("Stopped recording video: {}", err);
+            self.video = None;
+        }
+    }
+
+    fn write_audio_frame(&mut self) {
+        let Some(sink) = self.audio.as_mut() else {
+            return;
+        };
+        if let Err(err) = sink.end_frame() {
+            error!("Stopped the sound: {}", err);
+            self.audio = None;
+        }
+    }
+
+    fn publish(&mut self, state: &mut State) {
+        let cpu = self.debugger.cpu_mut();
+        state.beeping = cpu.is_beeping();
+        state.image.beeping = state.beeping;
+        state.image.number = self.scheduler.frame();
+        state
+            .image
+            .capture(cpu.screen_mut(), &self.palette, &mut state.phosphor);
+        // The buffer we got back last time holds an older frame, so all of it is overwritten
+        state.frame.clone_from(&state.image);
+        self.frames.publish(&mut state.frame);
+        self.notify(Notification::FrameReady);
+    }
+
+    fn notify(&self, notification: Notification) {
+        // Only fails once the event loop is gone, when nobody is left to tell
+        if let Err(err) = self.proxy.send_event(notification) {
+            debug!("Could not notify the UI thread: {}", err);
+        }
+    }
+
+    fn save_video(&mut self) {
+        if let Some((video, path)) = self.video.take() {
+            match video.finish() {
+                Ok(()) => println!("Saved video to {}", path.display()),
+                Err(err) => println!("Could not save video: {}", err),
+            }
+        }
+    }
+
+    fn finish(mut self) {
+        self.save_video();
+        if let Some((movie, path)) = self.recording.take() {
+            match movie.save_to_file(&path) {
+                Ok(()) => println!("Saved movie to {}", path.display()),
+                Err(err) => println!("Could not save movie: {}", err),
+            }
+        }
+        // Sinks playing on a thread of their own only return once told to stop
+        self.debugger.cpu().force_audio_stop();
+        if let Some(sink) = self.audio.take() {
+            if let Err(err) = sink.finish() {
+                println!("Could not close the sound: {}", err);
+            }
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    fs::OpenOptions,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use chip8::{
+    cpu::{
+        cpu::{CPUIterationDecision, CPU},
+        rng::RndAlgorithm,
+        scheduler::Scheduler,
+    },
+    dumper::{dump_cpu, DumpMemory},
+    gfx::{palette::Palette, screen::Screen, video::VideoRecorder},
+    movie::{content_lines, Movie, MovieEvent},
+    sound::sink::AudioSink,
+};
+use log::{debug, error, info};
+
+pub struct HeadlessOptions {
+    /// Instructions per second, spread evenly across frames
+    pub frequency: u32,
+
+    /// Stop after this many frames, or run until the program halts
+    pub frames: Option<u64>,
+
+    pub input_script: Option<PathBuf>,
+
+    /// What RND starts from, unless replaying a movie
+    pub seed: u64,
+    pub rnd: RndAlgorithm,
+
+    /// Movie to feed key events and the RND seed from
+    pub replay: Option<Movie>,
+
+    /// Where to save the key events of this run, as a movie
+    pub record: Option<PathBuf>,
+
+    /// Where to write the final screen, as a PNG image if the extension is `.png` and a PBM image
+    /// otherwise
+    pub screen_output: Option<PathBuf>,
+
+    /// Colours and pixel size of PNG screen output
+    pub palette: Palette,
+    pub screenshot_scale: usize,
+
+    /// Where to record every frame, as a GIF or Y4M video
+    pub record_video: Option<PathBuf>,
+    pub video_scale: usize,
+
+    /// Where the sound goes, one frame at a time
+    pub audio: Box<dyn AudioSink>,
+}
+
+/// Parses an input script, made of the event lines of a movie:
+///
+/// ```text
+/// # frame action key
+/// 120 press 5
+/// 130 release 5
+/// ```
+fn parse_input_script(data: &str) -> Result<Vec<MovieEvent>, String> {
+    let mut events = content_lines(data)
+        .map(|(number, line)| {
+            MovieEvent::from_line(line)
+                .ok_or_else(|| format!("Invalid input script line {}: {}", number, line))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    events.sort_by_key(|event| event.frame);
+    Ok(events)
+}
+
+fn read_input_script(path: PathBuf) -> Result<Vec<MovieEvent>, String> {
+    let mut file = match OpenOptions::new().read(true).open(path) {
+        Ok(file) => file,
+        Err(err) => {
+            error!("Could not open input script: {}", err.kind().to_string());
+            return Err("Could not open input script".to_string());
+        }
+    };
+
+    let mut data = String::new();
+    if let Err(err) = file.read_to_string(&mut data) {
+        error!("Could not read input script: {}", err.kind().to_string());
+        return Err("Could not read input script".to_string());
+    }
+
+    parse_input_script(&data)
+}
+
+/// Writes the screen as a plain PBM image, every pixel with at least a plane set is black
+fn write_pbm(path: &Path, screen: &Screen) -> Result<(), String> {
+    let mut file = match OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(err) => {
+            error!("Could not open file: {}", err.kind().to_string());
+            return Err("Could not open file".to_string());
+        }
+    };
+
+    let mut image = format!("P1\n{} {}\n", screen.width(), screen.height());
+    for y in 0..screen.height() {
+        let row: Vec<&str> = (0..screen.width())
+            .map(|x| if screen.pixel(x, y) != 0 { "1" } else { "0" })
+            .collect();
+        image.push_str(&row.join(" "));
+        image.push('\n');
+    }
+
+    file.write_all(image.as_bytes())
+        .map_err(|err| format!("Error writing to file: {}", err.kind()))
+}
+
+/// Runs the CPU without a window or an audio device, driving the timers from an emulated 60Hz clock
+pub fn run(cpu: &mut CPU, options: HeadlessOptions) -> Result<(), String> {
+    let mut input = match options.input_script {
+        Some(path) => read_input_script(path)?,
+        None => vec![],
+    }
+    .into_iter()
+    .peekable();
+
+    cpu.set_random_source(options.rnd.source(options.seed));
+    let mut player = options.replay.map(|movie| movie.play(cpu)).transpose()?;
+    let mut recording = options
+        .record
+        .as_ref()
+        .map(|_| Movie::new(options.seed, options.rnd, options.frequency, cpu.quirks()));
+
+    let mut audio = options.audio;
+    let mut video = options
+        .record_video
+        .as_deref()
+        .map(|path| VideoRecorder::create(path, options.palette, options.video_scale))
+        .transpose()?;
+
+    // Frames run back to back, the scheduler is only used to count them
+    let mut scheduler = Scheduler::from_frequency(options.frequency);
+    info!("Running headless at {}Hz", scheduler.frequency());
+
+    let mut result = Ok(());
+    while options
+        .frames
+        .is_none_or(|frames| scheduler.frame() < frames)
+    {
+        let frame = scheduler.frame();
+        while let Some(event) = input.next_if(|event| event.frame <= frame) {
+            debug!(
+                "Frame {}: key {:X} {}",
+                frame,
+                event.key,
+                if event.pressed { "pressed" } else { "released" }
+            );
+            if event.pressed {
+                cpu.press_key(event.key);
+            } else {
+                cpu.release_key(event.key);
+            }
+            if let Some(movie) = recording.as_mut() {
+                movie.record(frame, event.key, event.pressed);
+            }
+        }
+        if let Some(player) = player.as_mut() {
+            player.apply(frame, cpu);
+        }
+
+        let decision = scheduler.run_frame(cpu);
+        if let Some(video) = video.as_mut() {
+            // Nothing else takes the changes of the screen when running headless
+            let changed = cpu.screen_mut().take_dirty_rows() != 0;
+            video.record_frame(cpu.screen(), changed)?;
+        }
+        audio.end_frame()?;
+        match decision {
+            Ok(CPUIterationDecision::Continue) => {}
+            Ok(CPUIterationDecision::Halt) => {
+                info!("Program halted at frame {}", frame);
+                break;
+            }
+            Err(err) => {
+                error!("Execution stopped at frame {}: {}", frame, err);
+                dump_cpu(cpu, DumpMemory::Yes);
+                result = Err(err.to_string());
+                break;
+            }
+        }
+    }
+    info!("Ran {} frames", scheduler.frame());
+
+    if let Some(path) = options.screen_output {
+        if path.extension().is_some_and(|extension| extension == "png") {
+            cpu.screen()
+                .to_rgba_image(&options.palette, options.screenshot_scale)
+                .save_png(&path)?;
+        } else {
+            write_pbm(&path, cpu.screen())?;
+        }
+    }
+    if let Some(video) = video {
+        video.finish()?;
+    }
+    audio.finish()?;
+    if let (Some(movie), Some(path)) = (recording, options.record) {
+        movie.save_to_file(&path)?;
+    }
+
+    result
+}
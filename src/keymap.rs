@@ -3,15 +3,24 @@ use std::{collections::HashMap, fs::OpenOptions, io::Read, path::PathBuf};
 
 use serde::{Deserialize, Serialize};
 use serde_json::Result;
-use tao::keyboard::KeyCode;
 
+/// Keypad keys by the physical key they are bound to, named like `Digit1` or `KeyQ`
+///
+/// Names are the same whatever the frontend, each one maps its own key codes to them.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Keymap {
-    pub keys: HashMap<KeyCode, u8>,
+    pub keys: HashMap<String, u8>,
+}
+
+impl Keymap {
+    /// Keypad key bound to the physical key called `name`
+    pub fn key(&self, name: &str) -> Option<u8> {
+        self.keys.get(name).copied()
+    }
 }
 
 fn parse_string(data: &str) -> Result<Keymap> {
-    serde_json::from_str(&data)
+    serde_json::from_str(data)
 }
 
 pub fn read_keymap(path: PathBuf) -> Result<Keymap> {
@@ -27,23 +36,28 @@ pub fn read_keymap(path: PathBuf) -> Result<Keymap> {
 }
 
 pub fn default_keymap() -> Keymap {
-    let keys: HashMap<KeyCode, u8> = HashMap::from([
-        (KeyCode::Digit1, 1),
-        (KeyCode::Digit2, 2),
-        (KeyCode::Digit3, 3),
-        (KeyCode::Digit4, 12),
-        (KeyCode::KeyQ, 4),
-        (KeyCode::KeyW, 5),
-        (KeyCode::KeyE, 6),
-        (KeyCode::KeyR, 13),
-        (KeyCode::KeyA, 7),
-        (KeyCode::KeyS, 8),
-        (KeyCode::KeyD, 9),
-        (KeyCode::KeyF, 14),
-        (KeyCode::KeyZ, 10),
-        (KeyCode::KeyX, 0),
-        (KeyCode::KeyC, 11),
-        (KeyCode::KeyV, 15),
-    ]);
-    Keymap { keys }
+    let keys = [
+        ("Digit1", 1),
+        ("Digit2", 2),
+        ("Digit3", 3),
+        ("Digit4", 12),
+        ("KeyQ", 4),
+        ("KeyW", 5),
+        ("KeyE", 6),
+        ("KeyR", 13),
+        ("KeyA", 7),
+        ("KeyS", 8),
+        ("KeyD", 9),
+        ("KeyF", 14),
+        ("KeyZ", 10),
+        ("KeyX", 0),
+        ("KeyC", 11),
+        ("KeyV", 15),
+    ];
+    Keymap {
+        keys: keys
+            .into_iter()
+            .map(|(name, key)| (name.to_string(), key))
+            .collect(),
+    }
 }
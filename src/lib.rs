@@ -0,0 +1,7 @@
+#![forbid(unsafe_code)]
+#![deny(clippy::all)]
+//! What the window and the terminal frontends share, none of it depends on either of them
+pub mod keymap;
+pub mod logs;
+pub mod palette;
+pub mod quirks_profile;
@@ -3,7 +3,8 @@ use std::time::SystemTime;
 use chrono::{DateTime, Utc};
 use fern::colors::{Color, ColoredLevelConfig};
 
-pub fn log_init(debug_enabled: bool) -> Result<(), log::SetLoggerError> {
+/// Logs to a file in the working directory, and to stdout unless it's used for something else
+pub fn log_init(debug_enabled: bool, stdout_enabled: bool) -> Result<(), log::SetLoggerError> {
     let colors = ColoredLevelConfig::new()
         .info(Color::Green)
         .warn(Color::Yellow)
@@ -40,7 +41,7 @@ pub fn log_init(debug_enabled: bool) -> Result<(), log::SetLoggerError> {
         })
         .chain(fern::log_file(filename).unwrap());
 
-    fern::Dispatch::new()
+    let dispatcher = fern::Dispatch::new()
         // Ignore non-interesting logs from other sources
         .level(log::LevelFilter::Warn)
         // Keep our logs
@@ -52,8 +53,12 @@ pub fn log_init(debug_enabled: bool) -> Result<(), log::SetLoggerError> {
                 log::LevelFilter::Info
             },
         )
-        .chain(stdout_dispatcher)
-        .chain(file_dispatcher)
-        .apply()?;
+        .chain(file_dispatcher);
+
+    if stdout_enabled {
+        dispatcher.chain(stdout_dispatcher).apply()?;
+    } else {
+        dispatcher.apply()?;
+    }
     Ok(())
 }
@@ -1,11 +1,24 @@
 #![forbid(unsafe_code)]
 #![deny(clippy::all)]
-mod keymap;
-mod logs;
-
+mod audio_profile;
+mod emulation;
+mod headless;
+mod persistence_profile;
+mod rnd_profile;
+mod save_states;
+
+use audio_profile::AudioProfile;
+use chippy::{
+    keymap::{self, Keymap},
+    logs, palette,
+    quirks_profile::{QuirksProfile, SpriteEdgesProfile},
+};
 use clap::{arg, command, Parser};
-use keymap::Keymap;
+use emulation::{Command, Emulation, Notification};
+use headless::HeadlessOptions;
+use persistence_profile::PersistenceProfile;
 use pixels::{Pixels, SurfaceTexture};
+use rnd_profile::RndProfile;
 
 use tao::{
     accelerator::{Accelerator, SysMods},
@@ -13,35 +26,53 @@ use tao::{
     event::{ElementState, Event, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
     keyboard::KeyCode,
-    menu::{MenuBar, MenuId, MenuItem, MenuItemAttributes},
+    menu::{CustomMenuItem, MenuBar, MenuId, MenuItem, MenuItemAttributes},
     window::{Window, WindowBuilder},
 };
 
 use std::{
+    collections::HashMap,
     path::PathBuf,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        mpsc::{self, Receiver, Sender},
-        Arc,
-    },
-    thread::{self, JoinHandle},
-    time::Duration,
+    sync::mpsc::{self, Receiver},
+    thread::JoinHandle,
 };
 
 use chip8::{
     cpu::{
-        cpu::{CPUIterationDecision, CPU},
-        keyboard::parse_key_code,
+        cpu::CPU,
+        debugger::Debugger,
+        quirks::Quirks,
+        rng::{random_seed, RndAlgorithm},
+        scheduler::Scheduler,
+    },
+    gdb::stub::GdbStub,
+    gfx::{
+        framebuffer::{Frame, SharedFrame},
+        palette::Palette,
+        video::VideoRecorder,
+    },
+    movie::Movie,
+    sound::{
+        beep::CpalSink,
+        message::SoundMessage,
+        sink::{AudioSink, NullSink},
+        wav::{WavSink, DEFAULT_SAMPLE_RATE},
     },
-    sound::{beep::Sound, message::SoundMessage},
 };
 
-use log::{debug, error, info};
+use log::{debug, info, warn};
 
 const SCALING_FACTOR: u32 = 10;
 const DISPLAY_ROWS: u32 = 32;
 const DISPLAY_COLUMNS: u32 = 64;
 
+/// Ten seconds of history at 60 ticks per second
+const REWIND_FRAMES: usize = 10 * 60;
+const REWIND_KEY: KeyCode = KeyCode::Backspace;
+
+/// Menu items of the palettes are numbered from here, in the order they are listed
+const PALETTE_MENU_ID: u16 = 100;
+
 /// A Work-In-Progress CHIP-8 emulator
 #[derive(Parser, Debug)]
 #[command(name = "Chippy")]
@@ -49,7 +80,7 @@ const DISPLAY_COLUMNS: u32 = 64;
 #[command(version = "0.1.0")]
 #[command(about, long_about = None)]
 struct Cli {
-    /// .ch8 file to load program from
+    /// .ch8, .sc8 or .xo8 file to load program from
     #[arg(short, long, required = true)]
     file: PathBuf,
 
@@ -57,16 +88,151 @@ struct Cli {
     #[arg(short, long)]
     keymap: Option<PathBuf>,
 
+    /// Display colours: classic, amber, green-phosphor, lcd, or a theme .json file
+    #[arg(long, value_name = "NAME|FILE", default_value = "classic")]
+    palette: String,
+
+    /// Size of a CHIP-8 pixel in screenshots and PNG screen output
+    #[arg(long, value_name = "PIXELS", default_value_t = SCALING_FACTOR as usize)]
+    screenshot_scale: usize,
+
+    /// Record every frame to a .gif or .y4m video file, until exiting
+    #[arg(long, value_name = "FILE")]
+    record_video: Option<PathBuf>,
+
+    /// Size of a high resolution pixel in videos, low resolution pixels are twice as big
+    #[arg(long, value_name = "PIXELS", default_value_t = 4)]
+    video_scale: usize,
+
+    /// Where to play the sound, --audio-out overrides it
+    #[arg(value_enum, long, default_value_t = AudioProfile::Auto)]
+    audio: AudioProfile,
+
+    /// Write the sound to this WAV file instead of playing it, one 60Hz frame at a time
+    #[arg(long, value_name = "FILE")]
+    audio_out: Option<PathBuf>,
+
+    /// Sample rate of the WAV file written with --audio-out
+    #[arg(long, default_value_t = DEFAULT_SAMPLE_RATE, requires = "audio_out")]
+    sample_rate: u32,
+
+    /// Keep pixels that turn off on screen for a while, to hide sprite flicker
+    #[arg(value_enum, long, default_value_t = PersistenceProfile::Off)]
+    persistence: PersistenceProfile,
+
+    /// Brightness lost every frame by fading pixels, from 0 (never) to 1 (at once)
+    #[arg(long, default_value_t = 0.4)]
+    decay: f32,
+
     /// Frequency in Hz for the CPU
     #[arg(short = 'F', long, default_value_t = 500)]
     frequency: u32,
 
+    /// Interpreter behaviour to emulate
+    #[arg(value_enum, short, long, default_value_t = QuirksProfile::Modern)]
+    quirks: QuirksProfile,
+
+    /// Clip or wrap sprites at the screen edges, instead of what the quirks profile does
+    #[arg(value_enum, long)]
+    sprite_edges: Option<SpriteEdgesProfile>,
+
+    /// RND algorithm to emulate
+    #[arg(value_enum, long, default_value_t = RndProfile::SplitMix)]
+    rnd: RndProfile,
+
+    /// Seed for RND, random unless running headless, where it defaults to 0
+    #[arg(long, conflicts_with = "replay")]
+    seed: Option<u64>,
+
+    /// Stop with an error on 0nnn machine code calls instead of ignoring them
+    #[arg(long)]
+    sys_error: bool,
+
+    /// Run without a window or audio, e.g. on CI
+    #[arg(long)]
+    headless: bool,
+
+    /// Number of 60Hz frames to run in headless mode, runs until the program halts if missing
+    #[arg(long, requires = "headless")]
+    frames: Option<u64>,
+
+    /// Key presses and releases to feed in headless mode, one "<frame> <press|release> <key>" per line
+    #[arg(long, requires = "headless")]
+    input_script: Option<PathBuf>,
+
+    /// Write the screen to this file when the headless run ends, as PNG if it ends in .png and
+    /// PBM otherwise
+    #[arg(long, requires = "headless")]
+    screen_output: Option<PathBuf>,
+
+    /// Save the key presses and RND seed of this run to a movie file, on exit
+    #[arg(long, value_name = "FILE", conflicts_with = "replay")]
+    record: Option<PathBuf>,
+
+    /// Play a movie file back, ignoring the keyboard
+    #[arg(long, value_name = "FILE")]
+    replay: Option<PathBuf>,
+
+    /// Wait for GDB to connect to this port on localhost, and let it drive execution
+    #[arg(long, value_name = "PORT", conflicts_with = "headless")]
+    gdb: Option<u16>,
+
     /// Turn debugging information on
     #[arg(short, long)]
     debug: bool,
 }
 
-fn create_window(width: f64, height: f64, event_loop: &EventLoop<()>) -> Window {
+impl Cli {
+    fn quirks(&self) -> Quirks {
+        let mut quirks: Quirks = self.quirks.into();
+        quirks.sys_is_error |= self.sys_error;
+        if let Some(edges) = self.sprite_edges {
+            quirks.clip_sprites = edges == SpriteEdgesProfile::Clip;
+        }
+        quirks
+    }
+
+    /// Movies only play back the same way with the quirks they were recorded with
+    fn quirks_for(&self, replay: Option<&Movie>) -> Quirks {
+        replay.map_or_else(|| self.quirks(), |movie| movie.quirks)
+    }
+
+    /// Headless runs are reproducible out of the box, so they can be compared across runs
+    fn seed(&self) -> u64 {
+        match self.seed {
+            Some(seed) => seed,
+            None if self.headless => 0,
+            None => random_seed(),
+        }
+    }
+}
+
+/// Lists `palettes` with the one at `selected` ticked, returns the items so they can be ticked later
+fn create_palette_menu(
+    palettes: &[(String, Palette)],
+    selected: usize,
+) -> (MenuBar, Vec<CustomMenuItem>) {
+    let mut palette_menu = MenuBar::new();
+    let items = palettes
+        .iter()
+        .enumerate()
+        .map(|(index, (name, _))| {
+            palette_menu.add_item(
+                MenuItemAttributes::new(name)
+                    .with_id(MenuId(PALETTE_MENU_ID + index as u16))
+                    .with_selected(index == selected),
+            )
+        })
+        .collect();
+    (palette_menu, items)
+}
+
+fn create_window(
+    width: f64,
+    height: f64,
+    palette_menu: MenuBar,
+    event_loop: &EventLoop<Notification>,
+) -> Window {
     let mut file_menu = MenuBar::new();
     file_menu.add_native_item(MenuItem::Quit);
     file_menu.add_item(
@@ -79,9 +245,25 @@ fn create_window(width: f64, height: f64, event_loop: &EventLoop<()>) -> Window
             .with_id(MenuId(2))
             .with_accelerators(&Accelerator::new(SysMods::Cmd, KeyCode::ArrowDown)),
     );
+    file_menu.add_item(
+        MenuItemAttributes::new("Dump &state")
+            .with_id(MenuId(3))
+            .with_accelerators(&Accelerator::new(SysMods::Cmd, KeyCode::KeyD)),
+    );
+    file_menu.add_item(
+        MenuItemAttributes::new("Save s&creenshot")
+            .with_id(MenuId(4))
+            .with_accelerators(&Accelerator::new(SysMods::Cmd, KeyCode::KeyS)),
+    );
+    file_menu.add_item(
+        MenuItemAttributes::new("Start/stop recording &video")
+            .with_id(MenuId(5))
+            .with_accelerators(&Accelerator::new(SysMods::Cmd, KeyCode::KeyR)),
+    );
 
     let mut menu = MenuBar::new();
     menu.add_submenu("File", true, file_menu);
+    menu.add_submenu("Palette", true, palette_menu);
 
     let size = LogicalSize::new(width, height);
 
@@ -96,84 +278,141 @@ fn create_window(width: f64, height: f64, event_loop: &EventLoop<()>) -> Window
         .unwrap()
 }
 
-fn exit(timer_tick_stop: Arc<AtomicBool>, control_flow: &mut ControlFlow) {
-    timer_tick_stop.store(true, Ordering::Relaxed);
+fn exit(control_flow: &mut ControlFlow) {
     *control_flow = ControlFlow::Exit;
 }
 
+/// Keypad keys by the physical key of window events
+type WindowKeys = HashMap<KeyCode, u8>;
+
+/// Looks the keys of `keymap` up by their name in tao, which is how keymap files name them
+fn window_keys(keymap: &Keymap) -> WindowKeys {
+    let mut keys = WindowKeys::new();
+    for (name, key) in &keymap.keys {
+        match serde_json::from_value(serde_json::Value::String(name.clone())) {
+            Ok(key_code) => {
+                keys.insert(key_code, *key);
+            }
+            Err(_) => warn!("Unknown key {} in the keymap, it is left out", name),
+        }
+    }
+    keys
+}
+
+/// Returns what the emulation thread should do about `event`, if anything
 fn handle_window_event(
     event: WindowEvent,
     pixels: &mut Pixels,
     control_flow: &mut ControlFlow,
-    cpu: &mut CPU,
-    timer_tick_stop: Arc<AtomicBool>,
-    keymap: &Keymap,
-) {
+    keys: &WindowKeys,
+) -> Option<Command> {
     match event {
         WindowEvent::Resized(size) => {
             pixels.resize_surface(size.width, size.height).unwrap();
             pixels.render().unwrap();
         }
         WindowEvent::CloseRequested => {
-            exit(timer_tick_stop, control_flow);
+            exit(control_flow);
         }
         WindowEvent::KeyboardInput { event, .. } => {
             if event.physical_key == KeyCode::Escape {
-                exit(timer_tick_stop, control_flow);
+                exit(control_flow);
             }
 
-            let relevant = parse_key_code(event.physical_key, &keymap.keys);
+            let pressed = event.state == ElementState::Pressed;
+            if event.physical_key == REWIND_KEY {
+                // Rewind for as long as the key is held
+                return Some(Command::Rewind(pressed));
+            }
+            if let Some(action) = save_states::slot_action(event.physical_key) {
+                return pressed.then_some(Command::Slot(action));
+            }
 
-            if event.state == ElementState::Released || relevant.is_none() {
-                cpu.set_key_pressed(None);
-            } else {
-                cpu.set_key_pressed(relevant);
-            };
+            // Keys outside of the keymap don't affect the keypad
+            return keys
+                .get(&event.physical_key)
+                .map(|key| Command::Keypad { key: *key, pressed });
         }
 
         _ => {}
     };
+    None
 }
 
-fn init_60hz_clock(tx: Sender<u64>, stop_signal: Arc<AtomicBool>) -> thread::JoinHandle<()> {
-    let mut ticks = 0;
-    let clock_closure = move || loop {
-        if stop_signal.load(Ordering::Relaxed) {
-            break;
-        }
-        thread::sleep(Duration::from_millis(16));
-        tx.send(ticks).expect("Could not send the tick");
-        ticks += 1;
-    };
-    thread::spawn(clock_closure)
+/// Picks where the sound goes, falling back to silence when there is no device to play it on
+fn open_audio_sink(
+    args: &Cli,
+    message_rx: Receiver<SoundMessage>,
+) -> Result<Box<dyn AudioSink>, String> {
+    if let Some(path) = args.audio_out.as_deref() {
+        return Ok(Box::new(WavSink::create(
+            path,
+            args.sample_rate,
+            message_rx,
+        )?));
+    }
+    match args.audio {
+        AudioProfile::None => Ok(Box::new(NullSink::new(message_rx))),
+        AudioProfile::Device => match CpalSink::open(message_rx) {
+            Ok(sink) => Ok(Box::new(sink)),
+            Err((err, _)) => Err(err),
+        },
+        AudioProfile::Auto => match CpalSink::open(message_rx) {
+            Ok(sink) => Ok(Box::new(sink)),
+            Err((err, message_rx)) => {
+                warn!("{}, sound is disabled", err);
+                println!("{}, sound is disabled", err);
+                Ok(Box::new(NullSink::new(message_rx)))
+            }
+        },
+    }
 }
 
-fn init_beep(message_rx: Receiver<SoundMessage>) -> thread::JoinHandle<()> {
-    let closure = move || {
-        let _beep = Sound::new(message_rx);
+fn run_headless(args: Cli) -> Result<(), String> {
+    // There is no audio device to play to, the sound is thrown away unless written to a file
+    let (sound_message_tx, sound_message_rx) = mpsc::channel();
+    let audio: Box<dyn AudioSink> = match args.audio_out.as_deref() {
+        Some(path) => Box::new(WavSink::create(path, args.sample_rate, sound_message_rx)?),
+        None => Box::new(NullSink::new(sound_message_rx)),
+    };
+
+    let replay = args
+        .replay
+        .as_deref()
+        .map(Movie::load_from_file)
+        .transpose()?;
+
+    let seed = args.seed();
+    let palette = palette::load_palette(&args.palette)?.palette;
+    let mut cpu = CPU::new(sound_message_tx, args.quirks_for(replay.as_ref()));
+    cpu.load_program_from_file(args.file)?;
+
+    let options = HeadlessOptions {
+        // Movies only play back the same way at the frequency they were recorded at
+        frequency: replay
+            .as_ref()
+            .map_or(args.frequency, |movie| movie.frequency),
+        seed,
+        rnd: args.rnd.into(),
+        frames: args.frames,
+        input_script: args.input_script,
+        replay,
+        record: args.record,
+        screen_output: args.screen_output,
+        palette,
+        screenshot_scale: args.screenshot_scale,
+        record_video: args.record_video,
+        video_scale: args.video_scale,
+        audio,
     };
-    thread::spawn(closure)
+    headless::run(&mut cpu, options)
 }
 
 fn main() -> Result<(), String> {
     let args = Cli::parse();
     debug!("Parsed CLI arguments");
 
-    let (clock_tx, clock_rx) = mpsc::channel();
-    let (sound_message_tx, sound_message_rx) = mpsc::channel();
-    sound_message_tx
-        .send(SoundMessage::Pause)
-        .unwrap_or_else(|err| {
-            error!("Error pausing sound: {:?}", err);
-        });
-    let timer_tick_stop = Arc::new(AtomicBool::new(false));
-
-    let join_clock = init_60hz_clock(clock_tx, Arc::clone(&timer_tick_stop));
-    let join_sound = init_beep(sound_message_rx);
-
-    let mut cpu = CPU::new(sound_message_tx, args.frequency);
-
-    match logs::log_init(args.debug) {
+    match logs::log_init(args.debug, true) {
         Ok(()) => {
             info!("Logger setup successfully")
         }
@@ -182,45 +421,124 @@ fn main() -> Result<(), String> {
         }
     };
 
+    if args.headless {
+        return run_headless(args);
+    }
+
+    let (sound_message_tx, sound_message_rx) = mpsc::channel();
+    let audio = open_audio_sink(&args, sound_message_rx)?;
+
+    let replay = args
+        .replay
+        .as_deref()
+        .map(Movie::load_from_file)
+        .transpose()?;
+    let frequency = replay
+        .as_ref()
+        .map_or(args.frequency, |movie| movie.frequency);
+
+    let seed = args.seed();
+    let rnd: RndAlgorithm = args.rnd.into();
+    info!("RND seed: {}", seed);
+
+    let scheduler = Scheduler::from_frequency(frequency);
+    let mut cpu = CPU::new(sound_message_tx, args.quirks_for(replay.as_ref()));
+    cpu.set_random_source(rnd.source(seed));
+    cpu.enable_rewind(REWIND_FRAMES);
+
+    // Movies start from the very first frame, with the keyboard ignored while playing one back
+    let player = replay.map(|movie| movie.play(&mut cpu)).transpose()?;
+    let recording = args
+        .record
+        .clone()
+        .map(|path| (Movie::new(seed, rnd, frequency, cpu.quirks()), path));
+
     let keymap: Keymap = if let Some(keymap) = args.keymap {
         keymap::read_keymap(keymap).unwrap()
     } else {
         keymap::default_keymap()
     };
     println!("{:?}", keymap);
+    let keys = window_keys(&keymap);
+
+    let (palettes, selected) = palette::palettes(palette::load_palette(&args.palette)?);
 
+    let program_path = args.file.clone();
     cpu.load_program_from_file(args.file)?;
 
+    let video = match args.record_video {
+        Some(path) => Some((
+            VideoRecorder::create(&path, palettes[selected].1, args.video_scale)?,
+            path,
+        )),
+        None => None,
+    };
+
+    let gdb = match args.gdb {
+        Some(port) => {
+            println!("Waiting for GDB on 127.0.0.1:{}", port);
+            Some(GdbStub::listen(port)?)
+        }
+        None => None,
+    };
+
     // GUI Init
-    let event_loop = EventLoop::new();
+    let event_loop = EventLoop::with_user_event();
     let window_width = DISPLAY_COLUMNS * SCALING_FACTOR;
     let window_height = DISPLAY_ROWS * SCALING_FACTOR;
 
-    let window = create_window(window_width as f64, window_height as f64, &event_loop);
+    let (palette_menu, mut palette_items) = create_palette_menu(&palettes, selected);
+    let window = create_window(
+        window_width as f64,
+        window_height as f64,
+        palette_menu,
+        &event_loop,
+    );
     let mut pixels = {
         let window_size = window.inner_size();
         let surface_texture = SurfaceTexture::new(window_size.width, window_size.height, &window);
-        Pixels::new(64, 32, surface_texture).unwrap()
+        Pixels::new(DISPLAY_COLUMNS, DISPLAY_ROWS, surface_texture).unwrap()
+    };
+    // SUPER-CHIP programs can switch resolution, so we keep track of the buffer size
+    let mut buffer_size = (DISPLAY_COLUMNS, DISPLAY_ROWS);
+
+    // The CPU runs on its own thread, so dragging the window or opening a menu doesn't stall it
+    let frames = SharedFrame::new();
+    let (command_tx, command_rx) = mpsc::channel();
+    let emulation = Emulation {
+        debugger: Debugger::new(cpu),
+        scheduler,
+        gdb,
+        player,
+        recording,
+        program_path,
+        frames: frames.clone(),
+        palette: palettes[selected].1,
+        screenshot_scale: args.screenshot_scale,
+        video,
+        video_scale: args.video_scale,
+        audio: Some(audio),
+        persistence: args.persistence.with_decay(args.decay),
+        proxy: event_loop.create_proxy(),
     };
+    let join_emulation = emulation.spawn(command_rx);
+    // The frame being shown, traded with the emulation thread for newer ones
+    let mut frame = Frame::default();
 
     // We do this to avoid the compiler screaming at us for moving the handle
-    let mut join_clock_option = Some(join_clock);
-    let mut join_sound_option = Some(join_sound);
+    let mut join_emulation_option = Some(join_emulation);
 
     event_loop.run(move |event, _target, control_flow| {
         *control_flow = ControlFlow::Wait;
-        if let Ok(tick) = clock_rx.try_recv() {
-            cpu.tick(tick);
-        }
-        match event {
+        let command = match event {
             Event::MenuEvent {
                 window_id: _,
                 menu_id,
                 origin: _,
                 ..
             } if menu_id.0 == 1 => {
-                cpu.speed_up();
                 info!("Speed up requested");
+                Some(Command::SpeedUp)
             }
             Event::MenuEvent {
                 window_id: _,
@@ -228,40 +546,97 @@ fn main() -> Result<(), String> {
                 origin: _,
                 ..
             } if menu_id.0 == 2 => {
-                cpu.slow_down();
                 info!("Slow down requested");
+                Some(Command::SlowDown)
+            }
+            Event::MenuEvent {
+                window_id: _,
+                menu_id,
+                origin: _,
+                ..
+            } if menu_id.0 == 3 => {
+                info!("State dump requested");
+                Some(Command::DumpState)
+            }
+            Event::MenuEvent {
+                window_id: _,
+                menu_id,
+                origin: _,
+                ..
+            } if menu_id.0 == 4 => {
+                info!("Screenshot requested");
+                Some(Command::Screenshot)
+            }
+            Event::MenuEvent {
+                window_id: _,
+                menu_id,
+                origin: _,
+                ..
+            } if menu_id.0 == 5 => {
+                info!("Video recording toggled");
+                Some(Command::ToggleVideo)
+            }
+            Event::MenuEvent {
+                window_id: _,
+                menu_id,
+                origin: _,
+                ..
+            } if menu_id.0 >= PALETTE_MENU_ID => {
+                let index = (menu_id.0 - PALETTE_MENU_ID) as usize;
+                palettes.get(index).map(|(name, palette)| {
+                    info!("Switching to the {} palette", name);
+                    for (item_index, item) in palette_items.iter_mut().enumerate() {
+                        item.set_selected(item_index == index);
+                    }
+                    Command::SetPalette(*palette)
+                })
             }
             Event::WindowEvent { event, .. } => {
-                handle_window_event(
-                    event,
-                    &mut pixels,
-                    control_flow,
-                    &mut cpu,
-                    timer_tick_stop.clone(),
-                    &keymap,
-                );
-                if *control_flow == ControlFlow::Exit {
-                    debug!("Joining 60Hz clock thread");
-                    join_clock_option.take().map(JoinHandle::join);
-
-                    debug!("Joining sound thread");
-                    cpu.force_audio_stop();
-                    join_sound_option.take().map(JoinHandle::join);
+                handle_window_event(event, &mut pixels, control_flow, &keys)
+            }
+            Event::UserEvent(Notification::FrameReady) => {
+                if frames.take(&mut frame) {
+                    // Only upload the rows that changed, unless the resolution did
+                    let frame_size = (frame.width as u32, frame.height as u32);
+                    if frame_size != buffer_size {
+                        debug!("Resizing buffer to {}x{}", frame_size.0, frame_size.1);
+                        pixels.resize_buffer(frame_size.0, frame_size.1).unwrap();
+                        buffer_size = frame_size;
+                        pixels.get_frame_mut().copy_from_slice(&frame.pixels);
+                    } else {
+                        frame.copy_dirty_rows(pixels.get_frame_mut());
+                    }
+                    window.request_redraw();
                 }
+                None
+            }
+            Event::UserEvent(Notification::Stopped(err)) => {
+                println!("Execution stopped: {}", err);
+                println!("Use File > Dump state to dump the CPU state and memory");
+                window.set_title(&format!("Chippy - {}", err));
+                None
             }
-            Event::MainEventsCleared => {
-                if let CPUIterationDecision::Halt = cpu.fetch_decode_execute() {
-                    *control_flow = ControlFlow::Wait;
-                };
-                window.request_redraw();
+            Event::UserEvent(Notification::Resumed) => {
+                window.set_title("Chippy");
+                None
             }
             Event::RedrawRequested(_) => {
-                let frame = pixels.get_frame_mut();
-                if cpu.screen_mut().draw(frame) {
-                    pixels.render().unwrap();
-                }
+                pixels.render().unwrap();
+                None
             }
-            _ => {}
+            _ => None,
         };
+
+        if let Some(command) = command {
+            // Only fails once the emulation thread is gone, e.g. while exiting
+            command_tx.send(command).unwrap_or_else(|err| {
+                debug!("Could not send command: {:?}", err.0);
+            });
+        }
+        if *control_flow == ControlFlow::Exit {
+            debug!("Joining emulation thread");
+            command_tx.send(Command::Quit).ok();
+            join_emulation_option.take().map(JoinHandle::join);
+        }
     });
 }
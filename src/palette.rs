@@ -0,0 +1,205 @@
+use std::{fs::OpenOptions, io::Read, path::Path};
+
+use chip8::gfx::palette::Palette;
+use log::error;
+use serde::{Deserialize, Serialize};
+
+/// Palette as written in theme files, with colours as "#rrggbb"
+///
+/// The second plane colours are only used by XO-CHIP programs, they default to shades in between
+/// the background and the foreground.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Theme {
+    pub background: String,
+    pub foreground: String,
+    pub second_plane: Option<String>,
+    pub both_planes: Option<String>,
+}
+
+impl Theme {
+    pub fn palette(&self) -> Result<Palette, String> {
+        let mut palette = Palette::new(
+            parse_colour(&self.background)?,
+            parse_colour(&self.foreground)?,
+        );
+        for (planes, colour) in [(2, &self.second_plane), (3, &self.both_planes)] {
+            if let Some(colour) = colour {
+                let [r, g, b] = parse_colour(colour)?;
+                palette.colours[planes] = [r, g, b, 0xff];
+            }
+        }
+        Ok(palette)
+    }
+}
+
+fn parse_colour(colour: &str) -> Result<[u8; 3], String> {
+    let invalid = || format!("Invalid colour {:?}, expected \"#rrggbb\"", colour);
+    let hex = colour.strip_prefix('#').ok_or_else(invalid)?;
+    // from_str_radix would also take a sign
+    if hex.len() != 6 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+    let value = u32::from_str_radix(hex, 16).map_err(|_| invalid())?;
+    let [_, r, g, b] = value.to_be_bytes();
+    Ok([r, g, b])
+}
+
+pub fn read_theme(path: &Path) -> Result<Palette, String> {
+    let mut data = String::new();
+    let read = OpenOptions::new()
+        .read(true)
+        .open(path)
+        .and_then(|mut file| file.read_to_string(&mut data));
+    if let Err(err) = read {
+        error!("Could not read theme {}: {}", path.display(), err);
+        return Err(format!("Could not read theme {}", path.display()));
+    }
+
+    let theme: Theme = serde_json::from_str(&data).map_err(|err| {
+        error!("Could not parse theme {}: {}", path.display(), err);
+        format!("Could not parse theme {}", path.display())
+    })?;
+    theme.palette()
+}
+
+/// A palette given with `--palette`
+pub struct SelectedPalette {
+    /// Name to show the palette under
+    pub name: String,
+    pub palette: Palette,
+
+    /// Whether `name` is one of the built-in palettes rather than a theme file
+    pub built_in: bool,
+}
+
+/// Looks `name` up among the built-in palettes, or reads it as a theme file
+pub fn load_palette(name: &str) -> Result<SelectedPalette, String> {
+    if let Some(palette) = Palette::built_in(name) {
+        return Ok(SelectedPalette {
+            name: name.to_string(),
+            palette,
+            built_in: true,
+        });
+    }
+    let path = Path::new(name);
+    let palette = read_theme(path)?;
+    let name = path
+        .file_stem()
+        .map_or(name.into(), |stem| stem.to_string_lossy().into_owned());
+    Ok(SelectedPalette {
+        name,
+        palette,
+        built_in: false,
+    })
+}
+
+/// Every built-in palette, followed by `selected` if it came from a theme file
+///
+/// A theme named after a built-in palette is listed under a numbered name, e.g. `amber (2)`.
+/// Returns the index of `selected` in the list along with it
+pub fn palettes(selected: SelectedPalette) -> (Vec<(String, Palette)>, usize) {
+    let mut palettes: Vec<(String, Palette)> = Palette::BUILT_IN
+        .iter()
+        .map(|(name, palette)| (name.to_string(), *palette))
+        .collect();
+    if selected.built_in {
+        if let Some(index) = palettes.iter().position(|(name, _)| *name == selected.name) {
+            return (palettes, index);
+        }
+    }
+
+    let is_taken = |name: &str| palettes.iter().any(|(taken, _)| taken == name);
+    let mut name = selected.name.clone();
+    let mut number = 2;
+    while is_taken(&name) {
+        name = format!("{} ({})", selected.name, number);
+        number += 1;
+    }
+    let index = palettes.len();
+    palettes.push((name, selected.palette));
+    (palettes, index)
+}
+
+#[cfg(test)]
+mod tests {
+    use chip8::gfx::palette::Palette;
+
+    use super::{palettes, SelectedPalette, Theme};
+
+    fn parse(json: &str) -> Result<Palette, String> {
+        let theme: Theme = serde_json::from_str(json).map_err(|err| err.to_string())?;
+        theme.palette()
+    }
+
+    fn theme(name: &str, palette: Palette) -> SelectedPalette {
+        SelectedPalette {
+            name: name.to_string(),
+            palette,
+            built_in: false,
+        }
+    }
+
+    #[test]
+    fn second_plane_colours_default_to_shades_in_between() {
+        let palette = parse(r##"{"background": "#1a0f00", "foreground": "#ffb000"}"##);
+        assert_eq!(palette, Ok(Palette::AMBER));
+    }
+
+    #[test]
+    fn every_plane_colour_can_be_given() {
+        let palette = parse(
+            r##"{"background": "#000000", "foreground": "#FFFFFF",
+                "second_plane": "#ff0000", "both_planes": "#00ff00"}"##,
+        );
+        let expected = Palette::with_planes([0; 3], [0xff; 3], [0xff, 0, 0], [0, 0xff, 0]);
+        assert_eq!(palette, Ok(expected));
+    }
+
+    #[test]
+    fn bad_colours_are_rejected() {
+        for colour in ["ffffff", "#fff", "#fffffff", "#gggggg", "#+fffff", ""] {
+            let json = format!(
+                r##"{{"background": "#000000", "foreground": "{}"}}"##,
+                colour
+            );
+            let expected = format!("Invalid colour {:?}, expected \"#rrggbb\"", colour);
+            assert_eq!(parse(&json), Err(expected));
+        }
+        let json = r##"{"background": "#000000", "foreground": "#ffffff", "both_planes": "red"}"##;
+        assert!(parse(json).is_err());
+    }
+
+    #[test]
+    fn background_and_foreground_are_required() {
+        assert!(parse(r##"{"background": "#000000"}"##).is_err());
+        assert!(parse(r##"{"foreground": "#ffffff"}"##).is_err());
+    }
+
+    #[test]
+    fn built_in_palettes_are_selected_in_place() {
+        let selected = SelectedPalette {
+            built_in: true,
+            ..theme("amber", Palette::AMBER)
+        };
+        let (palettes, index) = palettes(selected);
+        assert_eq!(palettes.len(), Palette::BUILT_IN.len());
+        assert_eq!(palettes[index], ("amber".to_string(), Palette::AMBER));
+    }
+
+    #[test]
+    fn themes_are_listed_after_the_built_in_palettes() {
+        let custom = Palette::new([1, 2, 3], [4, 5, 6]);
+        let (palettes, index) = palettes(theme("custom", custom));
+        assert_eq!(index, Palette::BUILT_IN.len());
+        assert_eq!(palettes[index], ("custom".to_string(), custom));
+    }
+
+    #[test]
+    fn themes_named_after_a_built_in_palette_are_kept() {
+        let custom = Palette::new([1, 2, 3], [4, 5, 6]);
+        let (palettes, index) = palettes(theme("amber", custom));
+        assert_eq!(palettes.len(), Palette::BUILT_IN.len() + 1);
+        assert_eq!(palettes[1], ("amber".to_string(), Palette::AMBER));
+        assert_eq!(palettes[index], ("amber (2)".to_string(), custom));
+    }
+}
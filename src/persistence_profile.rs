@@ -0,0 +1,25 @@
+use chip8::gfx::phosphor::Persistence;
+use clap::ValueEnum;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum PersistenceProfile {
+    /// Show exactly what the screen holds
+    Off,
+
+    /// Fade pixels out when they turn off, like a CRT phosphor
+    Fade,
+
+    /// OR the last two frames together
+    Blend,
+}
+
+impl PersistenceProfile {
+    /// `decay` is the brightness fading pixels lose every frame, it only matters for `Fade`
+    pub fn with_decay(self, decay: f32) -> Persistence {
+        match self {
+            PersistenceProfile::Off => Persistence::Off,
+            PersistenceProfile::Fade => Persistence::Fade { decay },
+            PersistenceProfile::Blend => Persistence::FrameBlend,
+        }
+    }
+}
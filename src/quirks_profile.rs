@@ -0,0 +1,42 @@
+use chip8::cpu::quirks::Quirks;
+use clap::ValueEnum;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum QuirksProfile {
+    /// The original COSMAC VIP interpreter
+    CosmacVip,
+
+    /// CHIP-48 for the HP48 calculator
+    Chip48,
+
+    /// SUPER-CHIP 1.1
+    SuperChip,
+
+    /// XO-CHIP, with 64 KiB of memory
+    XoChip,
+
+    /// What most modern interpreters do
+    Modern,
+}
+
+impl From<QuirksProfile> for Quirks {
+    fn from(profile: QuirksProfile) -> Self {
+        match profile {
+            QuirksProfile::CosmacVip => Quirks::cosmac_vip(),
+            QuirksProfile::Chip48 => Quirks::chip48(),
+            QuirksProfile::SuperChip => Quirks::superchip(),
+            QuirksProfile::XoChip => Quirks::xo_chip(),
+            QuirksProfile::Modern => Quirks::modern(),
+        }
+    }
+}
+
+/// What happens to sprites drawn across the screen edges, overriding the profile
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SpriteEdgesProfile {
+    /// Cut the sprite at the edges
+    Clip,
+
+    /// Draw the rest of the sprite from the opposite edge
+    Wrap,
+}
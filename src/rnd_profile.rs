@@ -0,0 +1,20 @@
+use chip8::cpu::rng::RndAlgorithm;
+use clap::ValueEnum;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum RndProfile {
+    /// SplitMix64, evenly distributed
+    SplitMix,
+
+    /// The original COSMAC VIP routine, with its correlated results
+    CosmacVip,
+}
+
+impl From<RndProfile> for RndAlgorithm {
+    fn from(profile: RndProfile) -> Self {
+        match profile {
+            RndProfile::SplitMix => RndAlgorithm::SplitMix,
+            RndProfile::CosmacVip => RndAlgorithm::CosmacVip,
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use std::path::{Path, PathBuf};
+
+use chip8::cpu::{cpu::CPU, snapshot::Snapshot};
+use log::error;
+use tao::keyboard::KeyCode;
+
+/// F1 to F4 save into slots 1 to 4
+const SAVE_KEYS: [KeyCode; 4] = [KeyCode::F1, KeyCode::F2, KeyCode::F3, KeyCode::F4];
+
+/// F5 to F8 load from slots 1 to 4
+const LOAD_KEYS: [KeyCode; 4] = [KeyCode::F5, KeyCode::F6, KeyCode::F7, KeyCode::F8];
+
+/// Slots live next to the program, e.g. `game.ch8` saves slot 1 to `game.1.state`
+fn slot_path(program_path: &Path, slot: usize) -> PathBuf {
+    program_path.with_extension(format!("{}.state", slot))
+}
+
+/// What one of the save state keys does, slots are numbered from 1
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlotAction {
+    Save(usize),
+    Load(usize),
+}
+
+/// Returns what `key` does, if it is one of the save state keys
+pub fn slot_action(key: KeyCode) -> Option<SlotAction> {
+    if let Some(index) = SAVE_KEYS.iter().position(|save_key| *save_key == key) {
+        return Some(SlotAction::Save(index + 1));
+    }
+    if let Some(index) = LOAD_KEYS.iter().position(|load_key| *load_key == key) {
+        return Some(SlotAction::Load(index + 1));
+    }
+    None
+}
+
+/// Saves `cpu` to a slot, or restores it from one
+pub fn apply(action: SlotAction, cpu: &mut CPU, program_path: &Path) {
+    match action {
+        SlotAction::Save(slot) => {
+            let path = slot_path(program_path, slot);
+            match cpu.snapshot().save_to_file(&path) {
+                Ok(()) => println!("Saved slot {}", slot),
+                Err(err) => error!("Could not save slot {}: {}", slot, err),
+            }
+        }
+        SlotAction::Load(slot) => {
+            let path = slot_path(program_path, slot);
+            match Snapshot::load_from_file(&path).and_then(|snapshot| cpu.restore(&snapshot)) {
+                Ok(()) => println!("Loaded slot {}", slot),
+                Err(err) => error!("Could not load slot {}: {}", slot, err),
+            }
+        }
+    }
+}
@@ -0,0 +1,392 @@
+#![forbid(unsafe_code)]
+#![deny(clippy::all)]
+
+use std::{
+    io::{self, Stdout, Write},
+    path::PathBuf,
+    sync::mpsc::{self, Receiver},
+    time::Duration,
+};
+
+use chip8::{
+    cpu::{
+        cpu::{CPUIterationDecision, CPU},
+        error::CpuError,
+        scheduler::Scheduler,
+    },
+    gfx::{palette::Palette, screen::Screen},
+    sound::message::SoundMessage,
+};
+use chippy::{
+    keymap::{self, Keymap},
+    logs, palette,
+    quirks_profile::QuirksProfile,
+};
+use clap::Parser;
+use crossterm::{
+    cursor::{Hide, MoveTo, Show},
+    event::{
+        self, Event, KeyCode as TermKeyCode, KeyEvent, KeyEventKind, KeyModifiers,
+        KeyboardEnhancementFlags, PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+    },
+    execute, queue,
+    style::{Color, Print, ResetColor, SetBackgroundColor, SetForegroundColor},
+    terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use log::{error, info};
+
+/// Most terminals only report presses, so a key counts as held until this many frames
+/// go by without it repeating
+const KEY_HOLD_FRAMES: u8 = 15;
+
+/// The upper pixel is drawn with the foreground colour, the lower one with the background
+const HALF_BLOCK: char = '▀';
+
+/// Switches to the next palette, there is no menu in the terminal
+const PALETTE_KEY: TermKeyCode = TermKeyCode::F(9);
+
+/// Runs a CHIP-8 program in the terminal
+#[derive(Parser, Debug)]
+#[command(name = "Chippy TUI")]
+#[command(author = "Salvio G. <sungvzer@proton.me>")]
+#[command(version = "0.1.0")]
+#[command(about, long_about = None)]
+struct Cli {
+    /// .ch8, .sc8 or .xo8 file to load program from
+    #[arg(short, long, required = true)]
+    file: PathBuf,
+
+    /// Keymap .json file
+    #[arg(short, long)]
+    keymap: Option<PathBuf>,
+
+    /// Frequency in Hz for the CPU
+    #[arg(short = 'F', long, default_value_t = 500)]
+    frequency: u32,
+
+    /// Interpreter behaviour to emulate
+    #[arg(value_enum, short, long, default_value_t = QuirksProfile::Modern)]
+    quirks: QuirksProfile,
+
+    /// Display colours: classic, amber, green-phosphor, lcd, or a theme .json file
+    #[arg(long, value_name = "NAME|FILE", default_value = "classic")]
+    palette: String,
+
+    /// Turn debugging information on, logs only go to the log file
+    #[arg(short, long)]
+    debug: bool,
+}
+
+/// Puts the terminal back the way we found it, even when panicking
+struct TerminalGuard {
+    stdout: Stdout,
+    key_releases: bool,
+}
+
+impl TerminalGuard {
+    fn new() -> io::Result<Self> {
+        let mut stdout = io::stdout();
+        terminal::enable_raw_mode()?;
+        execute!(stdout, EnterAlternateScreen, Hide, Clear(ClearType::All))?;
+
+        // Terminals supporting the kitty keyboard protocol tell us when keys are released
+        let key_releases = terminal::supports_keyboard_enhancement().unwrap_or(false);
+        if key_releases {
+            execute!(
+                stdout,
+                PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES)
+            )?;
+        }
+        Ok(TerminalGuard {
+            stdout,
+            key_releases,
+        })
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        if self.key_releases {
+            let _ = execute!(self.stdout, PopKeyboardEnhancementFlags);
+        }
+        let _ = execute!(self.stdout, ResetColor, Show, LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// For every CHIP-8 key, how many more frames it counts as held without a release event
+type HeldKeys = [u8; 16];
+
+/// What the status line says
+enum State {
+    Running,
+    Halted,
+    Failed(CpuError),
+}
+
+/// Names the physical key behind a terminal key the way keymaps do, e.g. `KeyQ`
+///
+/// Terminals only report the character typed, so this assumes a US layout like keymaps do.
+fn key_name(code: TermKeyCode) -> Option<String> {
+    let name = match code {
+        TermKeyCode::Char(digit @ '0'..='9') => return Some(format!("Digit{}", digit)),
+        TermKeyCode::Char(letter) if letter.is_ascii_alphabetic() => {
+            return Some(format!("Key{}", letter.to_ascii_uppercase()))
+        }
+        TermKeyCode::Char(' ') => "Space",
+        TermKeyCode::Char('-') => "Minus",
+        TermKeyCode::Char('=') => "Equal",
+        TermKeyCode::Char(',') => "Comma",
+        TermKeyCode::Char('.') => "Period",
+        TermKeyCode::Char('/') => "Slash",
+        TermKeyCode::Char(';') => "Semicolon",
+        TermKeyCode::Char('\'') => "Quote",
+        TermKeyCode::Char('[') => "BracketLeft",
+        TermKeyCode::Char(']') => "BracketRight",
+        TermKeyCode::Char('\\') => "Backslash",
+        TermKeyCode::Char('`') => "Backquote",
+        TermKeyCode::Up => "ArrowUp",
+        TermKeyCode::Down => "ArrowDown",
+        TermKeyCode::Left => "ArrowLeft",
+        TermKeyCode::Right => "ArrowRight",
+        TermKeyCode::Enter => "Enter",
+        TermKeyCode::Tab => "Tab",
+        TermKeyCode::Backspace => "Backspace",
+        _ => return None,
+    };
+    Some(name.to_string())
+}
+
+/// Returns `false` when the user asked to quit
+fn handle_key(event: KeyEvent, keymap: &Keymap, held: &mut HeldKeys) -> bool {
+    let quit = event.code == TermKeyCode::Esc
+        || (event.code == TermKeyCode::Char('c') && event.modifiers == KeyModifiers::CONTROL);
+    if quit {
+        return false;
+    }
+
+    let Some(key) = key_name(event.code).and_then(|name| keymap.key(&name)) else {
+        return true;
+    };
+    // Masked like `Keypad` does, a keymap can map keys to values above 0xF
+    held[(key & 0xF) as usize] = match event.kind {
+        KeyEventKind::Press | KeyEventKind::Repeat => KEY_HOLD_FRAMES,
+        KeyEventKind::Release => 0,
+    };
+    true
+}
+
+fn colour(palette: &Palette, planes: u8) -> Color {
+    let [r, g, b, _] = palette.colour(planes);
+    Color::Rgb { r, g, b }
+}
+
+/// Draws two pixel rows per terminal row
+///
+/// Colours are only sent when they change, to keep the output small over SSH
+///
+/// Only the lines holding a row set in `dirty_rows` are redrawn, bit `y` standing for row `y`
+fn render_display(
+    stdout: &mut Stdout,
+    screen: &Screen,
+    palette: &Palette,
+    dirty_rows: u64,
+) -> io::Result<()> {
+    for row in 0..screen.height() / 2 {
+        if (dirty_rows >> (row * 2)) & 0b11 == 0 {
+            continue;
+        }
+        queue!(stdout, MoveTo(0, row as u16))?;
+        let mut colours = None;
+        for x in 0..screen.width() {
+            let cell = (screen.pixel(x, row * 2), screen.pixel(x, row * 2 + 1));
+            if colours != Some(cell) {
+                queue!(
+                    stdout,
+                    SetForegroundColor(colour(palette, cell.0)),
+                    SetBackgroundColor(colour(palette, cell.1))
+                )?;
+                colours = Some(cell);
+            }
+            queue!(stdout, Print(HALF_BLOCK))?;
+        }
+    }
+    queue!(stdout, ResetColor)
+}
+
+/// Draws the registers on the right of the display, and the status line below it
+fn render_status(stdout: &mut Stdout, cpu: &CPU, state: &State, beeping: bool) -> io::Result<()> {
+    let screen = cpu.screen();
+
+    let registers = cpu.registers();
+    let mut lines = vec![
+        format!("PC {:04X}", cpu.program_counter()),
+        format!("I  {:04X}", cpu.memory_location()),
+        format!("SP {:X}", cpu.stack_pointer()),
+        String::new(),
+    ];
+    for index in 0..8 {
+        lines.push(format!(
+            "V{:X} {:02X}  V{:X} {:02X}",
+            index,
+            registers[index],
+            index + 8,
+            registers[index + 8]
+        ));
+    }
+    lines.push(String::new());
+    lines.push(if beeping { "BEEP" } else { "    " }.to_string());
+
+    let column = screen.width() as u16 + 2;
+    for (row, line) in lines.iter().enumerate() {
+        queue!(stdout, MoveTo(column, row as u16), Print(line))?;
+    }
+
+    let status = match state {
+        State::Running => "Esc to quit, F9 for the next palette".to_string(),
+        State::Halted => "Program halted, Esc to quit".to_string(),
+        State::Failed(err) => format!("Execution stopped: {}", err),
+    };
+    queue!(
+        stdout,
+        MoveTo(0, (screen.height() / 2) as u16),
+        Clear(ClearType::CurrentLine),
+        Print(status)
+    )?;
+    stdout.flush()
+}
+
+/// Runs `frequency / 60` instructions and one timer tick per frame, sleeping in between
+///
+/// The display starts in `palettes[selected]`
+fn run(
+    cpu: &mut CPU,
+    keymap: &Keymap,
+    frequency: u32,
+    palettes: &[(String, Palette)],
+    mut selected: usize,
+    sound_rx: Receiver<SoundMessage>,
+) -> io::Result<()> {
+    let mut terminal = TerminalGuard::new()?;
+
+    let mut scheduler = Scheduler::from_frequency(frequency);
+    info!("Running in the terminal at {}Hz", scheduler.frequency());
+
+    let mut held: HeldKeys = [0; 16];
+    let mut state = State::Running;
+    let mut beeping = false;
+    // Resolution of what the terminal currently shows, `None` to force a redraw
+    let mut drawn: Option<(usize, usize)> = None;
+
+    loop {
+        while event::poll(Duration::ZERO)? {
+            match event::read()? {
+                Event::Key(event)
+                    if event.code == PALETTE_KEY && event.kind == KeyEventKind::Press =>
+                {
+                    selected = (selected + 1) % palettes.len();
+                    info!("Switching to the {} palette", palettes[selected].0);
+                    drawn = None;
+                }
+                Event::Key(event) if !handle_key(event, keymap, &mut held) => return Ok(()),
+                Event::Resize(_, _) => {
+                    queue!(terminal.stdout, Clear(ClearType::All))?;
+                    drawn = None;
+                }
+                _ => {}
+            }
+        }
+        for (key, frames_left) in held.iter().enumerate() {
+            if *frames_left > 0 {
+                cpu.press_key(key as u8);
+            } else {
+                cpu.release_key(key as u8);
+            }
+        }
+
+        let frame = scheduler.frame();
+        match state {
+            State::Running => match scheduler.run_frame(cpu) {
+                Ok(CPUIterationDecision::Continue) => {}
+                Ok(CPUIterationDecision::Halt) => {
+                    info!("Program halted at frame {}", frame);
+                    state = State::Halted;
+                }
+                Err(err) => {
+                    error!("Execution stopped at frame {}: {}", frame, err);
+                    state = State::Failed(err);
+                }
+            },
+            // Keep redrawing at 60Hz, so the status line is still shown
+            _ => scheduler.skip_frame(),
+        }
+
+        for message in sound_rx.try_iter() {
+            match message {
+                SoundMessage::Play => beeping = true,
+                SoundMessage::Pause | SoundMessage::Stop => beeping = false,
+                // XO-CHIP patterns change how the beep sounds, not whether it plays
+                SoundMessage::Pattern(_) => {}
+            }
+        }
+
+        let screen = cpu.screen_mut();
+        let size = (screen.width(), screen.height());
+        let mut dirty_rows = screen.take_dirty_rows();
+        if drawn != Some(size) {
+            // Switching resolution leaves the old display behind
+            if drawn.is_some() {
+                queue!(terminal.stdout, Clear(ClearType::All))?;
+            }
+            dirty_rows = u64::MAX;
+            drawn = Some(size);
+        }
+        let palette = &palettes[selected].1;
+        render_display(&mut terminal.stdout, cpu.screen(), palette, dirty_rows)?;
+        render_status(&mut terminal.stdout, cpu, &state, beeping)?;
+
+        if !terminal.key_releases {
+            for frames_left in held.iter_mut() {
+                *frames_left = frames_left.saturating_sub(1);
+            }
+        }
+
+        scheduler.wait_for_next_frame();
+    }
+}
+
+fn main() -> Result<(), String> {
+    let args = Cli::parse();
+
+    if let Err(error) = logs::log_init(args.debug, false) {
+        println!("Could not setup logger: {}", error)
+    }
+
+    let keymap: Keymap = if let Some(keymap) = args.keymap {
+        keymap::read_keymap(keymap).unwrap()
+    } else {
+        keymap::default_keymap()
+    };
+
+    let (palettes, selected) = palette::palettes(palette::load_palette(&args.palette)?);
+
+    // Audio would play on the machine we are running on, which is not where the user is
+    // over SSH, so sound is only shown on screen
+    let (sound_message_tx, sound_message_rx) = mpsc::channel();
+
+    let mut cpu = CPU::new(sound_message_tx, args.quirks.into());
+    cpu.load_program_from_file(args.file)?;
+
+    run(
+        &mut cpu,
+        &keymap,
+        args.frequency,
+        &palettes,
+        selected,
+        sound_message_rx,
+    )
+    .map_err(|err| {
+        error!("Terminal error: {}", err);
+        format!("Terminal error: {}", err)
+    })
+}